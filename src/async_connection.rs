@@ -0,0 +1,442 @@
+//! ServerQuery session on tokio.
+//!
+//! A background task owns the read half of the stream: notifications go to a
+//! queue read by [`AsyncTelnetConn::next_event`], every other reply is handed to
+//! the command waiting for it. Commands are answered in order, so the waiting
+//! commands are kept in a FIFO.
+
+use crate::datastructures::{Notification, QueryStatus};
+use crate::error::{QueryError, Result};
+use crate::protocol::{build_command, build_command_with, parse_kv};
+use log::{debug, warn};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
+
+/// Data lines of a reply and its status line.
+#[derive(Debug)]
+struct Reply {
+    lines: Vec<String>,
+    status: QueryStatus,
+}
+
+#[derive(Default)]
+struct Pending(Mutex<VecDeque<oneshot::Sender<Reply>>>);
+
+impl Pending {
+    fn lock(&self) -> MutexGuard<'_, VecDeque<oneshot::Sender<Reply>>> {
+        // A panic while holding the lock leaves the queue itself intact
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Async counterpart of [`crate::TelnetConn`].
+pub struct AsyncTelnetConn {
+    writer: Box<dyn AsyncWrite + Unpin + Send>,
+    pending: Arc<Pending>,
+    notifications: mpsc::UnboundedReceiver<Notification>,
+    welcome: String,
+    timeout: Duration,
+}
+
+impl AsyncTelnetConn {
+    pub async fn connect(server: &str, port: u16, timeout: Duration) -> Result<Self> {
+        let addr = format!("{}:{}", server, port);
+        let stream = tokio::time::timeout(timeout, TcpStream::connect((server, port)))
+            .await
+            .map_err(|_| QueryError::Timeout)?
+            .map_err(|source| QueryError::Connect { addr, source })?;
+        Self::from_stream(stream, timeout).await
+    }
+
+    /// Start a session on an already connected stream, `timeout` applies to
+    /// the banner and every command.
+    pub async fn from_stream<S>(stream: S, timeout: Duration) -> Result<Self>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (reader, writer) = tokio::io::split(stream);
+        let mut reader = BufReader::new(reader);
+        let banner = async {
+            match read_line(&mut reader).await? {
+                Some(line) if line == "TS3" => {}
+                Some(line) => {
+                    return Err(QueryError::Protocol(format!(
+                        "Unexpected greeting {:?}, is this a ServerQuery port?",
+                        line
+                    )))
+                }
+                None => return Err(QueryError::Protocol("No greeting received".to_string())),
+            }
+            read_line(&mut reader).await
+        };
+        let welcome = match tokio::time::timeout(timeout, banner).await {
+            Ok(welcome) => welcome?,
+            Err(_) => return Err(QueryError::Protocol("No greeting received".to_string())),
+        };
+        let welcome = welcome.unwrap_or_else(|| {
+            warn!("Got no welcome message");
+            String::new()
+        });
+        debug!("{}", welcome);
+
+        let pending = Arc::new(Pending::default());
+        let (sender, notifications) = mpsc::unbounded_channel();
+        tokio::spawn(demultiplex(reader, pending.clone(), sender));
+        Ok(Self {
+            writer: Box::new(writer),
+            pending,
+            notifications,
+            welcome,
+            timeout,
+        })
+    }
+
+    /// Welcome message sent by the server after connecting.
+    pub fn welcome(&self) -> &str {
+        &self.welcome
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Next pushed notification, `None` once the connection is closed.
+    pub async fn next_event(&mut self) -> Option<Notification> {
+        self.notifications.recv().await
+    }
+
+    async fn send(&mut self, payload: &str) -> Result<Reply> {
+        let (sender, receiver) = oneshot::channel();
+        // Queue before writing, the reply may arrive before the write returns
+        self.pending.lock().push_back(sender);
+        if let Err(e) = self.write(payload).await {
+            // Nothing answers an unsent command, the next reply is for the one after
+            self.pending.lock().pop_back();
+            return Err(e);
+        }
+        // A late reply still goes to our dropped receiver, keeping the order for the next command
+        tokio::time::timeout(self.timeout, receiver)
+            .await
+            .map_err(|_| QueryError::Timeout)?
+            .map_err(|_| QueryError::Closed)
+    }
+
+    async fn write(&mut self, payload: &str) -> Result<()> {
+        self.writer.write_all(payload.as_bytes()).await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+
+    /// Send a command, a status other than ok is returned as error.
+    pub async fn command(&mut self, payload: &str) -> Result<(QueryStatus, Vec<String>)> {
+        let reply = self.send(payload).await?;
+        if !reply.status.is_ok() {
+            return Err(reply.status.into());
+        }
+        Ok((reply.status, reply.lines))
+    }
+
+    /// Send a command whose reply is a single `key=value` line.
+    async fn command_kv(&mut self, payload: &str) -> Result<HashMap<String, String>> {
+        let (_, lines) = self.command(payload).await?;
+        Ok(lines.first().map(|line| parse_kv(line)).unwrap_or_default())
+    }
+
+    pub async fn login(&mut self, user: &str, password: &str) -> Result<QueryStatus> {
+        let payload = build_command_with("login", &[user, password], &[], &[]);
+        Ok(self.command(&payload).await?.0)
+    }
+
+    /// Select the virtual server by its id (`use`).
+    pub async fn select_server(&mut self, sid: i32) -> Result<QueryStatus> {
+        let payload = build_command_with("use", &[&sid.to_string()], &[], &[]);
+        self.select(&payload, sid.to_string()).await
+    }
+
+    /// Select the virtual server by its voice port (`use port=`).
+    pub async fn select_server_by_port(&mut self, port: u16) -> Result<QueryStatus> {
+        let payload = build_command("use", &[("port", &port.to_string())]);
+        self.select(&payload, format!("on port {}", port)).await
+    }
+
+    /// A stopped server (1033) fails with [`QueryError::ServerNotRunning`].
+    async fn select(&mut self, payload: &str, server: String) -> Result<QueryStatus> {
+        let reply = self.send(payload).await?;
+        match reply.status.id() {
+            0 => Ok(reply.status),
+            1033 => Err(QueryError::ServerNotRunning(server)),
+            _ => Err(reply.status.into()),
+        }
+    }
+
+    /// Subscribe to `event` notifications, `id` is the channel for channel events.
+    pub async fn notify_register(&mut self, event: &str, id: Option<i32>) -> Result<QueryStatus> {
+        let id = id.map(|id| id.to_string());
+        let mut args = vec![("event", event)];
+        if let Some(id) = &id {
+            args.push(("id", id));
+        }
+        Ok(self
+            .command(&build_command("servernotifyregister", &args))
+            .await?
+            .0)
+    }
+
+    /// Create a channel named `name` with extra properties, return its cid.
+    pub async fn channel_create(&mut self, name: &str, properties: &[(&str, &str)]) -> Result<i32> {
+        let mut args = vec![("channel_name", name)];
+        args.extend_from_slice(properties);
+        let result = self
+            .command_kv(&build_command("channelcreate", &args))
+            .await?;
+        result
+            .get("cid")
+            .ok_or_else(|| {
+                QueryError::Protocol("Can't find cid in channelcreate reply.".to_string())
+            })?
+            .parse()
+            .map_err(|e| QueryError::Protocol(format!("Got error while parse cid: {:?}", e)))
+    }
+
+    /// Move client `clid` into channel `cid`.
+    pub async fn client_move(&mut self, clid: i32, cid: i32) -> Result<QueryStatus> {
+        let payload = build_command(
+            "clientmove",
+            &[("clid", &clid.to_string()), ("cid", &cid.to_string())],
+        );
+        Ok(self.command(&payload).await?.0)
+    }
+
+    /// Delete channel `cid`, `force` also kicks the clients still inside.
+    pub async fn channel_delete(&mut self, cid: i32, force: bool) -> Result<QueryStatus> {
+        let payload = build_command(
+            "channeldelete",
+            &[
+                ("cid", &cid.to_string()),
+                ("force", &(force as u8).to_string()),
+            ],
+        );
+        Ok(self.command(&payload).await?.0)
+    }
+
+    /// Keep an idle session alive.
+    pub async fn keepalive(&mut self) -> Result<QueryStatus> {
+        Ok(self.command(&build_command("version", &[])).await?.0)
+    }
+
+    /// Close the session, the server replies and closes the connection.
+    pub async fn quit(mut self) -> Result<()> {
+        self.writer
+            .write_all(build_command("quit", &[]).as_bytes())
+            .await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+}
+
+/// Next non-empty line without the `\n\r` separators, `None` at the end of the stream.
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<String>> {
+    loop {
+        let mut line = Vec::new();
+        if reader.read_until(b'\n', &mut line).await? == 0 {
+            return Ok(None);
+        }
+        let line = String::from_utf8_lossy(&line);
+        let line = line.trim_matches(|c| c == '\n' || c == '\r');
+        if !line.is_empty() {
+            return Ok(Some(line.to_string()));
+        }
+    }
+}
+
+/// Route the lines read from the server until the connection is closed.
+async fn demultiplex<R: AsyncBufRead + Unpin>(
+    mut reader: R,
+    pending: Arc<Pending>,
+    notifications: mpsc::UnboundedSender<Notification>,
+) {
+    let mut lines = Vec::new();
+    loop {
+        let line = match read_line(&mut reader).await {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(e) => {
+                warn!("Got error while read from server: {:?}", e);
+                break;
+            }
+        };
+        if line.starts_with("notify") {
+            match Notification::try_from(line.as_str()) {
+                // Nobody listening for events is fine
+                Ok(event) => drop(notifications.send(event)),
+                Err(e) => warn!("Got error while parse notification: {:?}", e),
+            }
+            continue;
+        }
+        if !line.starts_with("error ") {
+            lines.push(line);
+            continue;
+        }
+        let status = match QueryStatus::try_from(line.as_str()) {
+            Ok(status) => status,
+            Err(e) => {
+                warn!("Got error while parse status line: {:?}", e);
+                continue;
+            }
+        };
+        let reply = Reply {
+            lines: std::mem::take(&mut lines),
+            status,
+        };
+        match pending.lock().pop_front() {
+            // The command may have timed out and dropped its receiver
+            Some(sender) => drop(sender.send(reply)),
+            None => warn!("Got reply without a pending command: {:?}", reply),
+        }
+    }
+    // Dropping the senders fails the waiting commands with Closed
+    pending.lock().clear();
+}
+
+#[cfg(test)]
+mod test {
+    use super::AsyncTelnetConn;
+    use crate::error::QueryError;
+    use crate::protocol::TERMINATOR;
+    use std::time::Duration;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, DuplexStream};
+
+    const BANNER: &str = "TS3\n\rWelcome to the TeamSpeak 3 ServerQuery interface.\n\r";
+    const TIMEOUT: Duration = Duration::from_secs(2);
+
+    /// Connection to an in-memory server, returned as the other end of the duplex.
+    async fn duplex_conn() -> (AsyncTelnetConn, BufReader<DuplexStream>) {
+        let (client, mut server) = tokio::io::duplex(4096);
+        server.write_all(BANNER.as_bytes()).await.unwrap();
+        let conn = AsyncTelnetConn::from_stream(client, TIMEOUT).await.unwrap();
+        (conn, BufReader::new(server))
+    }
+
+    async fn expect_command(server: &mut BufReader<DuplexStream>, command: &str) {
+        let mut line = String::new();
+        server.read_line(&mut line).await.unwrap();
+        assert_eq!(line, format!("{}{}", command, TERMINATOR));
+    }
+
+    #[tokio::test]
+    async fn test_banner() {
+        let (conn, _server) = duplex_conn().await;
+        assert_eq!(
+            conn.welcome(),
+            "Welcome to the TeamSpeak 3 ServerQuery interface."
+        );
+
+        let (client, mut server) = tokio::io::duplex(4096);
+        server.write_all(b"SSH-2.0-OpenSSH\r\n").await.unwrap();
+        assert!(matches!(
+            AsyncTelnetConn::from_stream(client, TIMEOUT).await,
+            Err(QueryError::Protocol(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_commands() {
+        let (mut conn, mut server) = duplex_conn().await;
+        let server = tokio::spawn(async move {
+            expect_command(&mut server, "login serveradmin pass\\sword").await;
+            server
+                .get_mut()
+                .write_all(b"error id=0 msg=ok\n\r")
+                .await
+                .unwrap();
+            expect_command(&mut server, "use 1").await;
+            server
+                .get_mut()
+                .write_all(b"error id=0 msg=ok\n\r")
+                .await
+                .unwrap();
+            expect_command(
+                &mut server,
+                "channelcreate channel_name=Alice's\\sChannel cpid=1",
+            )
+            .await;
+            // A notification in front of the reply must not be taken for it
+            server
+                .get_mut()
+                .write_all(b"notifycliententerview cfid=0 ctid=1 reasonid=0 clid=5 client_nickname=Alice\n\rcid=42\n\rerror id=0 msg=ok\n\r")
+                .await
+                .unwrap();
+            expect_command(&mut server, "clientmove clid=5 cid=42").await;
+            server
+                .get_mut()
+                .write_all(b"error id=770 msg=already\\smember\\sof\\schannel\n\r")
+                .await
+                .unwrap();
+            server
+        });
+
+        assert!(conn
+            .login("serveradmin", "pass word")
+            .await
+            .unwrap()
+            .is_ok());
+        assert!(conn.select_server(1).await.unwrap().is_ok());
+        let cid = conn
+            .channel_create("Alice's Channel", &[("cpid", "1")])
+            .await
+            .unwrap();
+        assert_eq!(cid, 42);
+        assert!(matches!(
+            conn.client_move(5, cid).await,
+            Err(QueryError::ServerError { id: 770, .. })
+        ));
+        let event = conn.next_event().await.unwrap();
+        assert_eq!(event.name(), "notifycliententerview");
+        assert_eq!(event.fields()["clid"], "5");
+
+        // The server going away fails the commands still waiting and ends the events
+        drop(server.await.unwrap());
+        assert!(matches!(
+            conn.keepalive().await,
+            Err(QueryError::Closed) | Err(QueryError::Io(_))
+        ));
+        assert!(conn.next_event().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_write_error() {
+        let (mut conn, mut server) = duplex_conn().await;
+        let (dead, peer) = tokio::io::duplex(64);
+        drop(peer);
+        let writer = std::mem::replace(&mut conn.writer, Box::new(dead));
+        assert!(matches!(conn.keepalive().await, Err(QueryError::Io(_))));
+        assert!(conn.pending.lock().is_empty());
+
+        // The next reply goes to the next command
+        conn.writer = writer;
+        let server = tokio::spawn(async move {
+            expect_command(&mut server, "version").await;
+            server
+                .get_mut()
+                .write_all(
+                    b"version=3.13.6 build=1623234157 platform=Linux\n\rerror id=0 msg=ok\n\r",
+                )
+                .await
+                .unwrap();
+            server
+        });
+        assert!(conn.keepalive().await.unwrap().is_ok());
+        drop(server.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let (mut conn, _server) = duplex_conn().await;
+        conn.set_timeout(Duration::from_millis(50));
+        assert!(matches!(conn.keepalive().await, Err(QueryError::Timeout)));
+    }
+}
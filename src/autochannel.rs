@@ -0,0 +1,3259 @@
+use crate::channel_tree::descendants;
+use crate::config::Config;
+use crate::connection::{ClientListFlags, TargetMode, VirtualServer};
+use crate::datastructures::{Event, Notification, QueryStatus};
+use crate::error::{QueryError, Result};
+#[cfg(feature = "metrics")]
+use crate::metrics::Metrics;
+use crate::protocol::build_command;
+use crate::state::{ManagedChannel, State};
+use crate::TelnetConn;
+use log::{debug, error, info, warn};
+use serde_derive::Deserialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// A watched lobby channel and the settings of the channels spawned for it.
+/// Unset settings fall back to the ones of the [`AutoChannel`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WatchRule {
+    parent_channel: i32,
+    /// Channel the spawned channels are created in, the watched one if unset
+    spawn_parent: Option<i32>,
+    name_template: Option<String>,
+    /// `channel_codec` of the spawned channels, e.g. 4 for Opus Voice
+    codec: Option<u8>,
+    /// `channel_codec_quality` of the spawned channels, 0-10
+    codec_quality: Option<u8>,
+    /// Channels a single identity may own for this parent at once
+    max_per_user: Option<usize>,
+}
+
+impl WatchRule {
+    pub fn new(parent_channel: i32) -> Self {
+        Self {
+            parent_channel,
+            ..Default::default()
+        }
+    }
+
+    pub fn set_spawn_parent(&mut self, spawn_parent: Option<i32>) {
+        self.spawn_parent = spawn_parent;
+    }
+    pub fn set_name_template(&mut self, template: Option<String>) {
+        self.name_template = template;
+    }
+    pub fn set_codec(&mut self, codec: Option<u8>) {
+        self.codec = codec;
+    }
+    pub fn set_codec_quality(&mut self, quality: Option<u8>) {
+        self.codec_quality = quality;
+    }
+    pub fn set_max_per_user(&mut self, max: Option<usize>) {
+        self.max_per_user = max;
+    }
+
+    pub fn parent_channel(&self) -> i32 {
+        self.parent_channel
+    }
+    pub fn spawn_parent(&self) -> Option<i32> {
+        self.spawn_parent
+    }
+    pub fn name_template(&self) -> Option<&str> {
+        self.name_template.as_deref()
+    }
+    pub fn codec(&self) -> Option<u8> {
+        self.codec
+    }
+    pub fn codec_quality(&self) -> Option<u8> {
+        self.codec_quality
+    }
+    pub fn max_per_user(&self) -> Option<usize> {
+        self.max_per_user
+    }
+}
+
+/// Limit that kept a client from getting a channel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    /// [`AutoChannel::set_max_channels_total`]
+    Total(usize),
+    /// [`AutoChannel::set_max_channels_per_user`] or [`WatchRule::set_max_per_user`]
+    PerUser(usize),
+    /// [`AutoChannel::set_spawn_cooldown`]
+    Cooldown(Duration),
+}
+
+/// How long a spawned channel lives, set by the mutually exclusive flags of `channelcreate`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChannelLifetime {
+    /// Deleted by the server itself once empty, never by us
+    Temporary,
+    /// Kept while empty until we delete it, gone after a server restart
+    SemiPermanent,
+    /// Kept while empty until we delete it, also over server restarts
+    Permanent,
+}
+
+impl ChannelLifetime {
+    /// The `channelcreate` flag giving a channel this lifetime.
+    pub fn flag(self) -> &'static str {
+        match self {
+            ChannelLifetime::Temporary => "channel_flag_temporary",
+            ChannelLifetime::SemiPermanent => "channel_flag_semi_permanent",
+            ChannelLifetime::Permanent => "channel_flag_permanent",
+        }
+    }
+}
+
+impl FromStr for ChannelLifetime {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "temporary" => Ok(ChannelLifetime::Temporary),
+            "semi-permanent" => Ok(ChannelLifetime::SemiPermanent),
+            "permanent" => Ok(ChannelLifetime::Permanent),
+            _ => Err(format!(
+                "Unknown channel lifetime {}, expected temporary, semi-permanent or permanent",
+                s
+            )),
+        }
+    }
+}
+
+/// What to do when the rendered name of a channel to spawn is taken (error 771).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NameCollision {
+    /// Append ` (2)`, ` (3)`, ... up to [`AutoChannel::set_name_attempts`] names
+    #[default]
+    Suffix,
+    /// Spawn nothing, the client stays in the lobby
+    Skip,
+    /// Move the client into the channel of that name
+    Reuse,
+}
+
+impl FromStr for NameCollision {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "suffix" => Ok(NameCollision::Suffix),
+            "skip" => Ok(NameCollision::Skip),
+            "reuse" => Ok(NameCollision::Reuse),
+            _ => Err(format!(
+                "Unknown name collision strategy {}, expected suffix, skip or reuse",
+                s
+            )),
+        }
+    }
+}
+
+/// Hooks called by [`AutoChannel`] for embedders reacting to its activity.
+/// They run on the event loop, so keep them short. Every hook does nothing by default.
+pub trait AutoChannelObserver: Send + Sync {
+    fn on_channel_created(&self, _cid: i32, _creator_uid: &str) {}
+    /// The client a channel was created for was moved into it
+    fn on_client_moved(&self, _clid: i32, _cid: i32) {}
+    fn on_channel_deleted(&self, _cid: i32) {}
+    fn on_limit_reached(&self, _clid: i32, _uid: &str, _limit: Limit) {}
+}
+
+/// Observer ignoring everything, the default of [`AutoChannel`].
+pub struct NoopObserver;
+
+impl AutoChannelObserver for NoopObserver {}
+
+/// What the name of a spawned channel was rendered from, to render it again when
+/// the creator changes the nickname.
+#[derive(Clone, Debug)]
+struct SpawnedName {
+    /// clid of the creator, while it stays online
+    creator: i32,
+    template: String,
+    fields: HashMap<String, String>,
+    datetime: String,
+    /// Name attempt the channel got, see [`numbered`]
+    attempt: u32,
+}
+
+/// A channel to spawn, queued by [`AutoChannel::queue_event`].
+#[derive(Clone, Debug)]
+struct PendingSpawn {
+    clid: i32,
+    parent: i32,
+    /// Fields of `notifycliententerview`, `None` for a client moved into the parent
+    /// whose identity is asked for when the spawn is processed
+    fields: Option<HashMap<String, String>>,
+}
+
+/// Spawn a sub-channel for every client entering one of the watched parent channels,
+/// and delete it again once the last client left.
+pub struct AutoChannel {
+    rules: Vec<WatchRule>,
+    /// Name of spawned channels unless the rule has its own, see [`render_name`]
+    name_template: String,
+    /// Names tried per channel when the name is taken, see [`Self::set_name_attempts`]
+    name_attempts: u32,
+    name_collision: NameCollision,
+    /// Occupancy of the channels created by us
+    channels: HashMap<i32, usize>,
+    /// Managed channel each tracked client currently sits in
+    clients: HashMap<i32, i32>,
+    /// ServerQuery clients (`client_type=1`), they never keep a channel alive
+    query_clients: HashSet<i32>,
+    /// UID of the client each channel was created for
+    owners: HashMap<i32, String>,
+    /// Watched channel each managed channel was spawned for
+    parents: HashMap<i32, i32>,
+    /// Names of the managed channels whose creator is online
+    names: HashMap<i32, SpawnedName>,
+    /// Target of our `clientmove`s whose `notifyclientmoved` is still to come, by clid
+    bot_moves: HashMap<i32, i32>,
+    /// Managed channels below each channel they were created in, oldest first.
+    /// New channels are ordered after the last one.
+    spawned: HashMap<i32, Vec<i32>>,
+    max_channels_per_user: Option<usize>,
+    max_channels_total: Option<usize>,
+    /// Channel group granted to the client a channel was created for
+    creator_channel_group: Option<i32>,
+    /// Channel spawned channels are created in unless the rule has its own
+    spawn_parent: Option<i32>,
+    /// Codec and quality of spawned channels unless the rule has its own
+    codec: Option<u8>,
+    codec_quality: Option<u8>,
+    /// `channel_maxclients` of spawned channels, unlimited if unset
+    max_clients: Option<u32>,
+    /// Template of the password of spawned channels, see [`Self::set_password`]
+    password: Option<String>,
+    /// Templates of `channel_topic` and `channel_description`, rendered like the name
+    topic_template: Option<String>,
+    description_template: Option<String>,
+    /// Lifetime flag of spawned channels, the server default if unset
+    lifetime: Option<ChannelLifetime>,
+    /// Where the managed channels are saved on every change
+    state_file: Option<PathBuf>,
+    /// How long a channel may stay empty before it is deleted
+    empty_grace: Duration,
+    /// When each empty managed channel became empty, see [`Self::sweep`]
+    empty_since: HashMap<i32, Instant>,
+    /// Time a UID has to wait between two spawned channels
+    spawn_cooldown: Duration,
+    /// When a channel was last spawned for each UID
+    last_spawn: HashMap<String, Instant>,
+    /// Move a client owning a channel back into it instead of spawning another one
+    rejoin_existing: bool,
+    /// Channel idle clients are moved to, see [`Self::set_afk_channel`]
+    afk_channel: Option<i32>,
+    afk_idle: Duration,
+    /// When the idle times were last checked
+    last_afk_check: Option<Instant>,
+    /// Identities allowed to send `!commands`, see [`Self::set_admin_uids`]
+    admin_uids: HashSet<String>,
+    /// Channels waiting to be spawned, at most one per client, oldest first
+    pending: VecDeque<PendingSpawn>,
+    max_pending: usize,
+    observer: Arc<dyn AutoChannelObserver>,
+    /// Every channel below this one is watched with the global settings
+    watch_subtree: Option<i32>,
+    /// Channels below `watch_subtree` besides the managed ones, see [`Self::refresh_subtree`]
+    subtree: HashSet<i32>,
+    #[cfg(feature = "metrics")]
+    metrics: Option<Arc<Metrics>>,
+    /// Managed channels last counted into `metrics`, shared with the other servers
+    #[cfg(feature = "metrics")]
+    reported_channels: usize,
+}
+
+impl AutoChannel {
+    pub fn new(parent_channel: i32) -> Self {
+        Self::with_rules(vec![WatchRule::new(parent_channel)])
+    }
+
+    /// Watch every parent channel of `rules`.
+    pub fn with_rules(rules: Vec<WatchRule>) -> Self {
+        Self {
+            rules,
+            name_template: DEFAULT_NAME_TEMPLATE.to_string(),
+            name_attempts: DEFAULT_NAME_ATTEMPTS,
+            name_collision: NameCollision::default(),
+            channels: Default::default(),
+            clients: Default::default(),
+            query_clients: Default::default(),
+            owners: Default::default(),
+            parents: Default::default(),
+            names: Default::default(),
+            bot_moves: Default::default(),
+            spawned: Default::default(),
+            max_channels_per_user: None,
+            max_channels_total: None,
+            creator_channel_group: None,
+            spawn_parent: None,
+            codec: None,
+            codec_quality: None,
+            max_clients: None,
+            password: None,
+            topic_template: None,
+            description_template: None,
+            lifetime: None,
+            state_file: None,
+            empty_grace: Duration::ZERO,
+            empty_since: Default::default(),
+            spawn_cooldown: Duration::ZERO,
+            last_spawn: Default::default(),
+            rejoin_existing: false,
+            afk_channel: None,
+            afk_idle: Duration::ZERO,
+            last_afk_check: None,
+            admin_uids: Default::default(),
+            pending: Default::default(),
+            max_pending: DEFAULT_MAX_PENDING,
+            observer: Arc::new(NoopObserver),
+            watch_subtree: None,
+            subtree: Default::default(),
+            #[cfg(feature = "metrics")]
+            metrics: None,
+            #[cfg(feature = "metrics")]
+            reported_channels: 0,
+        }
+    }
+
+    /// Take over the auto-channel settings of `config`.
+    pub fn configure(&mut self, config: &Config) {
+        self.rules = config.watch_rules();
+        self.set_watch_subtree(config.watch_subtree());
+        self.set_name_template(config.channel_name_template().to_string());
+        self.set_name_attempts(config.channel_name_attempts());
+        self.set_name_collision(config.on_name_collision());
+        self.set_max_channels_per_user(config.max_channels_per_user());
+        self.set_max_channels_total(config.max_channels_total());
+        self.set_creator_channel_group(config.creator_channel_group());
+        self.set_spawn_parent(config.spawn_parent());
+        self.set_codec(config.channel_codec());
+        self.set_codec_quality(config.channel_codec_quality());
+        self.set_max_clients(config.channel_max_clients());
+        self.set_password(config.channel_password().map(ToString::to_string));
+        self.set_topic_template(config.channel_topic_template().map(ToString::to_string));
+        self.set_description_template(
+            config
+                .channel_description_template()
+                .map(ToString::to_string),
+        );
+        self.set_lifetime(config.channel_lifetime());
+        self.set_empty_grace(Duration::from_secs(config.empty_grace_secs()));
+        self.set_spawn_cooldown(Duration::from_secs(config.spawn_cooldown_secs()));
+        self.set_rejoin_existing(config.rejoin_existing());
+        self.set_afk_channel(
+            config.afk_channel(),
+            Duration::from_secs(config.afk_idle_secs()),
+        );
+        self.set_admin_uids(config.admin_uids().to_vec());
+    }
+
+    /// Apply a reloaded `config` while running. Channels spawned so far stay managed,
+    /// the private messages are registered once there are admins.
+    pub fn reload(&mut self, conn: &mut TelnetConn, config: &Config) -> Result<()> {
+        let had_admins = !self.admin_uids.is_empty();
+        self.configure(&config.resolve_names(conn)?);
+        self.refresh_subtree(conn)?;
+        // `event=channel id=0` covers the newly watched channels already. A single
+        // event can't be unregistered, without admins every message is ignored anyway
+        if !had_admins && !self.admin_uids.is_empty() {
+            info!("Admins configured, registering their private messages");
+            conn.notify_register("textprivate", None)?;
+        }
+        info!("Configuration reloaded");
+        Ok(())
+    }
+
+    pub fn set_name_template(&mut self, template: String) {
+        self.name_template = template;
+    }
+
+    /// Names tried when the rendered name is taken (error 771), the second is
+    /// suffixed with ` (2)` and so on. At least one is always tried.
+    pub fn set_name_attempts(&mut self, attempts: u32) {
+        self.name_attempts = attempts.max(1);
+    }
+
+    /// What happens when the rendered name is taken, [`NameCollision::Suffix`] by default.
+    pub fn set_name_collision(&mut self, strategy: NameCollision) {
+        self.name_collision = strategy;
+    }
+
+    /// Channels a single identity may own below each parent, unless the rule has
+    /// its own limit. `None` for no limit.
+    pub fn set_max_channels_per_user(&mut self, max: Option<usize>) {
+        self.max_channels_per_user = max;
+    }
+
+    /// Channels we manage at once, `None` for no limit.
+    pub fn set_max_channels_total(&mut self, max: Option<usize>) {
+        self.max_channels_total = max;
+    }
+
+    /// Channel group (usually Channel Admin) given to the creator of a channel.
+    pub fn set_creator_channel_group(&mut self, cgid: Option<i32>) {
+        self.creator_channel_group = cgid;
+    }
+
+    /// Create the channels below `cid` instead of next to the watched channel,
+    /// e.g. in a dedicated "Temporary Channels" parent.
+    pub fn set_spawn_parent(&mut self, cid: Option<i32>) {
+        self.spawn_parent = cid;
+    }
+
+    /// `channel_codec` of spawned channels, `None` for the server default.
+    pub fn set_codec(&mut self, codec: Option<u8>) {
+        self.codec = codec;
+    }
+
+    /// `channel_codec_quality` of spawned channels, `None` for the server default.
+    pub fn set_codec_quality(&mut self, quality: Option<u8>) {
+        self.codec_quality = quality;
+    }
+
+    /// Clients a spawned channel holds at most, `None` for the server default (unlimited).
+    pub fn set_max_clients(&mut self, max: Option<u32>) {
+        self.max_clients = max;
+    }
+
+    /// Protect spawned channels with a password rendered like the name. `{random}`
+    /// is replaced by a generated password, which is poked to the creator.
+    pub fn set_password(&mut self, template: Option<String>) {
+        self.password = template;
+    }
+
+    /// Topic of spawned channels, rendered like the name. `None` leaves it empty.
+    pub fn set_topic_template(&mut self, template: Option<String>) {
+        self.topic_template = template;
+    }
+
+    /// Description of spawned channels, rendered like the name. It may span several lines.
+    pub fn set_description_template(&mut self, template: Option<String>) {
+        self.description_template = template;
+    }
+
+    /// Lifetime of spawned channels. Temporary ones are left to the server to delete,
+    /// the empty grace period doesn't apply to them.
+    pub fn set_lifetime(&mut self, lifetime: Option<ChannelLifetime>) {
+        self.lifetime = lifetime;
+    }
+
+    /// Save the managed channels to `path` whenever they change, see [`Self::restore`].
+    pub fn set_state_file(&mut self, path: Option<PathBuf>) {
+        self.state_file = path;
+    }
+
+    /// Keep empty channels for `grace` before deleting them, so a client dropping
+    /// out for a moment finds its channel again. Zero deletes them right away.
+    pub fn set_empty_grace(&mut self, grace: Duration) {
+        self.empty_grace = grace;
+    }
+
+    /// Spawn at most one channel per UID within `cooldown`, a client rejoining
+    /// earlier is moved into its newest channel or poked. Zero disables it.
+    pub fn set_spawn_cooldown(&mut self, cooldown: Duration) {
+        self.spawn_cooldown = cooldown;
+    }
+
+    /// Move a client entering a watched channel into the newest channel its identity
+    /// still owns there, a new channel is only spawned if there is none.
+    pub fn set_rejoin_existing(&mut self, rejoin: bool) {
+        self.rejoin_existing = rejoin;
+    }
+
+    /// Move clients idle for `idle` to `cid`, checked every [`AFK_CHECK_INTERVAL`]
+    /// by [`Self::move_idle`]. `None` or a zero `idle` disables it.
+    pub fn set_afk_channel(&mut self, cid: Option<i32>, idle: Duration) {
+        self.afk_channel = cid;
+        self.afk_idle = idle;
+    }
+
+    /// Identities whose private text messages starting with `!` are run as commands,
+    /// e.g. `!stats` or `!spawn`. Nobody may use them if empty.
+    pub fn set_admin_uids(&mut self, uids: Vec<String>) {
+        self.admin_uids = uids.into_iter().collect();
+    }
+
+    /// Channels waiting to be spawned at most, the oldest is dropped when another
+    /// client enters a full queue. At least one is kept.
+    pub fn set_max_pending(&mut self, max: usize) {
+        self.max_pending = max.max(1);
+    }
+
+    /// Channels waiting to be spawned by [`Self::process_next`].
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Report the activity to `observer`.
+    pub fn set_observer(&mut self, observer: Arc<dyn AutoChannelObserver>) {
+        self.observer = observer;
+    }
+
+    /// Watch every channel below `root` too, e.g. one lobby per game in a category.
+    /// The set is computed by [`Self::refresh_subtree`] and follows the channel tree.
+    pub fn set_watch_subtree(&mut self, root: Option<i32>) {
+        self.watch_subtree = root;
+        self.subtree.clear();
+    }
+
+    /// Recompute the channels below the `--watch-subtree` root from `channellist`.
+    pub fn refresh_subtree(&mut self, conn: &mut TelnetConn) -> Result<()> {
+        let root = match self.watch_subtree {
+            Some(root) => root,
+            None => return Ok(()),
+        };
+        let timeout = conn.timeout();
+        let (_, channels) =
+            conn.write_and_parse_list(&build_command("channellist", &[]), timeout)?;
+        let mut subtree = descendants(&channels, root)?;
+        // Channels spawned below a watched one are no lobbies themselves
+        subtree.retain(|cid| !self.channels.contains_key(cid));
+        debug!("Watching {} channel(s) below {}", subtree.len(), root);
+        self.subtree = subtree;
+        Ok(())
+    }
+
+    /// Recompute the ServerQuery clients from `clientlist`, their moves are never
+    /// spawned for.
+    pub fn refresh_query_clients(&mut self, conn: &mut TelnetConn) -> Result<()> {
+        let clients = conn.client_list(ClientListFlags::default())?;
+        self.query_clients = clients
+            .iter()
+            .filter(|client| client.is_query())
+            .map(|client| client.clid() as i32)
+            .collect();
+        Ok(())
+    }
+
+    /// Rule of the watched channel `cid`, `None` if it isn't watched.
+    fn rule_for(&self, cid: i32) -> Option<WatchRule> {
+        self.rules
+            .iter()
+            .find(|rule| rule.parent_channel == cid)
+            .cloned()
+            .or_else(|| self.subtree.contains(&cid).then(|| WatchRule::new(cid)))
+    }
+
+    /// Move `clid` into `cid`, a channel it owns. False if the channel turned out
+    /// to be deleted without us noticing, it is forgotten then.
+    fn move_to_owned(&mut self, conn: &mut TelnetConn, clid: i32, cid: i32) -> Result<bool> {
+        match self.move_client(conn, clid, cid) {
+            Ok(_) => Ok(true),
+            // invalid channelID
+            Err(QueryError::ServerError { id: 768, .. }) => {
+                info!("Channel {} is gone", cid);
+                self.forget_channel(cid);
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Count the activity into `metrics`.
+    #[cfg(feature = "metrics")]
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = Some(metrics);
+        self.reported_channels = 0;
+        self.report_channels();
+    }
+
+    /// Handle one notification pushed by the server, spawning the channel it asks for
+    /// right away.
+    pub fn handle_event(&mut self, conn: &mut TelnetConn, event: &Notification) -> Result<()> {
+        self.queue_event(conn, event)?;
+        while self.process_next(conn)? {}
+        Ok(())
+    }
+
+    /// Like [`Self::handle_event`], but channels to spawn are only queued for
+    /// [`Self::process_next`]. A client entering again before its channel was spawned
+    /// replaces its pending spawn, one leaving drops it.
+    pub fn queue_event(&mut self, conn: &mut TelnetConn, event: &Notification) -> Result<()> {
+        match event.event()? {
+            Event::ClientEnterView { .. } => self.on_client_enter_queued(event.fields()),
+            Event::ClientMoved {
+                clid,
+                ctid,
+                reasonid,
+            } => {
+                if self.query_clients.contains(&clid) {
+                    return Ok(());
+                }
+                debug!(
+                    "Client {} moved to channel {}, reasonid {}",
+                    clid, ctid, reasonid
+                );
+                // Our own move, nothing to spawn for it
+                let echo = self.bot_moves.get(&clid) == Some(&ctid);
+                if echo {
+                    self.bot_moves.remove(&clid);
+                }
+                self.pending.retain(|spawn| spawn.clid != clid);
+                self.on_client_leave(conn, clid)?;
+                if !echo && self.rule_for(ctid).is_some() {
+                    self.enqueue(PendingSpawn {
+                        clid,
+                        parent: ctid,
+                        fields: None,
+                    });
+                    return Ok(());
+                }
+                self.on_client_join(clid, ctid);
+                Ok(())
+            }
+            Event::ClientLeftView { clid, .. } => {
+                self.query_clients.remove(&clid);
+                // The clid may be handed to another client next
+                self.names.retain(|_, name| name.creator != clid);
+                self.bot_moves.remove(&clid);
+                self.pending.retain(|spawn| spawn.clid != clid);
+                self.on_client_leave(conn, clid)
+            }
+            Event::ClientUpdated {
+                clid,
+                client_nickname: Some(nickname),
+            } => self.on_nickname_changed(conn, clid, &nickname),
+            Event::ChannelDeleted { cid } => {
+                self.forget_channel(cid);
+                if self.subtree.remove(&cid) {
+                    self.refresh_subtree(conn)?;
+                }
+                Ok(())
+            }
+            Event::TextMessage {
+                targetmode: 1,
+                msg,
+                invokerid,
+                invokername,
+                invokeruid,
+            } => self.on_text_message(conn, invokerid, &invokername, &invokeruid, &msg),
+            Event::ChannelCreated { cid, .. }
+            | Event::ChannelEdited { cid }
+            | Event::ChannelMoved { cid, .. } => {
+                if self.watch_subtree.is_some() && !self.channels.contains_key(&cid) {
+                    self.refresh_subtree(conn)?;
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Answer a private `!command`, only admins may run them.
+    fn on_text_message(
+        &mut self,
+        conn: &mut TelnetConn,
+        clid: i32,
+        nickname: &str,
+        uid: &str,
+        msg: &str,
+    ) -> Result<()> {
+        // Our own replies are notified too, they never start with `!`
+        let command = match msg.trim().strip_prefix('!') {
+            Some(command) => command,
+            None => return Ok(()),
+        };
+        // No reply, anybody could spend the command budget the spawns need
+        if !self.admin_uids.contains(uid) {
+            debug!(
+                "Ignoring command {} of {}({}), not an admin",
+                msg, nickname, clid
+            );
+            return Ok(());
+        }
+        info!("Running command {} of {}({})", msg, nickname, clid);
+        let reply = self.run_command(clid, command);
+        conn.send_text_message(TargetMode::Client, clid, &reply)?;
+        Ok(())
+    }
+
+    /// Run an admin `command` sent by `clid`, without the `!`, returns the reply.
+    fn run_command(&mut self, clid: i32, command: &str) -> String {
+        let mut words = command.split_whitespace();
+        match words.next().unwrap_or_default() {
+            "help" => "Commands: !spawn [parent channel id], !stats, !help".to_string(),
+            "stats" => format!(
+                "Managing {} channel(s) with {} client(s), {} waiting to be spawned",
+                self.channels.len(),
+                self.channels.values().sum::<usize>(),
+                self.pending.len()
+            ),
+            "spawn" => {
+                let parent = match words.next() {
+                    Some(cid) => match cid.parse() {
+                        Ok(cid) => cid,
+                        Err(_) => return format!("Not a channel id: {}", cid),
+                    },
+                    None => match self.rules.first() {
+                        Some(rule) => rule.parent_channel,
+                        None => return "Pass the watched channel to spawn in".to_string(),
+                    },
+                };
+                if self.rule_for(parent).is_none() {
+                    return format!("Channel {} is not watched", parent);
+                }
+                self.enqueue(PendingSpawn {
+                    clid,
+                    parent,
+                    fields: None,
+                });
+                format!("Spawning a channel below {} for you", parent)
+            }
+            other => format!("Unknown command !{}, try !help", other),
+        }
+    }
+
+    fn on_client_enter_queued(&mut self, fields: &HashMap<String, String>) -> Result<()> {
+        let target: i32 = get_field(fields, "ctid")?;
+        let clid: i32 = get_field(fields, "clid")?;
+        if fields.get("client_type").map(String::as_str) == Some("1") {
+            self.query_clients.insert(clid);
+            return Ok(());
+        }
+        if self.rule_for(target).is_none() {
+            self.on_client_join(clid, target);
+            return Ok(());
+        }
+        self.enqueue(PendingSpawn {
+            clid,
+            parent: target,
+            fields: Some(fields.clone()),
+        });
+        Ok(())
+    }
+
+    fn enqueue(&mut self, spawn: PendingSpawn) {
+        if let Some(queued) = self
+            .pending
+            .iter_mut()
+            .find(|queued| queued.clid == spawn.clid)
+        {
+            debug!(
+                "Client {} entered again, replacing its pending channel",
+                spawn.clid
+            );
+            *queued = spawn;
+            return;
+        }
+        if self.pending.len() >= self.max_pending {
+            if let Some(dropped) = self.pending.pop_front() {
+                warn!(
+                    "{} channels waiting to be spawned, dropping the one of client {}",
+                    self.max_pending, dropped.clid
+                );
+            }
+        }
+        self.pending.push_back(spawn);
+    }
+
+    /// Spawn the oldest pending channel, returns whether there was one. A failed spawn
+    /// is not retried.
+    pub fn process_next(&mut self, conn: &mut TelnetConn) -> Result<bool> {
+        let spawn = match self.pending.pop_front() {
+            Some(spawn) => spawn,
+            None => return Ok(false),
+        };
+        match &spawn.fields {
+            Some(fields) => self.on_client_enter(conn, fields)?,
+            None => self.on_client_moved_to_parent(conn, spawn.clid, spawn.parent)?,
+        }
+        Ok(true)
+    }
+
+    fn on_client_enter(
+        &mut self,
+        conn: &mut TelnetConn,
+        fields: &HashMap<String, String>,
+    ) -> Result<()> {
+        let target: i32 = get_field(fields, "ctid")?;
+        let clid: i32 = get_field(fields, "clid")?;
+        if fields.get("client_type").map(String::as_str) == Some("1") {
+            self.query_clients.insert(clid);
+            return Ok(());
+        }
+        let rule = match self.rule_for(target) {
+            Some(rule) => rule,
+            None => {
+                self.on_client_join(clid, target);
+                return Ok(());
+            }
+        };
+        let nickname = fields
+            .get("client_nickname")
+            .map(String::as_str)
+            .unwrap_or_default();
+        let uid = fields
+            .get("client_unique_identifier")
+            .map(String::as_str)
+            .unwrap_or_default();
+
+        if self.rejoin_existing && !uid.is_empty() {
+            if let Some(cid) = self.newest_owned_below(uid, rule.parent_channel) {
+                if self.move_to_owned(conn, clid, cid)? {
+                    info!(
+                        "Moved client {}({}) back to its channel {}",
+                        nickname, clid, cid
+                    );
+                    self.observer.on_client_moved(clid, cid);
+                    return Ok(());
+                }
+            }
+        }
+        if let Some(max) = self.max_channels_total {
+            if self.channels.len() >= max {
+                warn!(
+                    "Not creating a channel for {}({}), limit of {} channels reached",
+                    nickname, clid, max
+                );
+                self.observer.on_limit_reached(clid, uid, Limit::Total(max));
+                return Ok(());
+            }
+        }
+        if let Some(max) = rule.max_per_user.or(self.max_channels_per_user) {
+            if self.owned_below(uid, rule.parent_channel) >= max {
+                warn!(
+                    "Not creating a channel for {}({}), already owns {} channel(s)",
+                    nickname, clid, max
+                );
+                self.observer
+                    .on_limit_reached(clid, uid, Limit::PerUser(max));
+                return Ok(());
+            }
+        }
+        let now = Instant::now();
+        self.last_spawn
+            .retain(|_, at| now.saturating_duration_since(*at) < self.spawn_cooldown);
+        if let Some(at) = self.last_spawn.get(uid) {
+            let remaining = self.spawn_cooldown - now.saturating_duration_since(*at);
+            // Round up, never ask to wait 0s
+            let wait = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+            let moved = match self.newest_owned_below(uid, rule.parent_channel) {
+                Some(cid) => {
+                    info!(
+                        "Client {}({}) rejoined within the cooldown, moving it to channel {}",
+                        nickname, clid, cid
+                    );
+                    self.move_to_owned(conn, clid, cid)?
+                }
+                None => false,
+            };
+            if !moved {
+                warn!(
+                    "Not creating a channel for {}({}), cooldown for {}s",
+                    nickname, clid, wait
+                );
+                let msg = format!("Please wait {}s before creating another channel", wait);
+                conn.poke_client(clid, &msg)?;
+            }
+            self.observer
+                .on_limit_reached(clid, uid, Limit::Cooldown(self.spawn_cooldown));
+            return Ok(());
+        }
+
+        let datetime = chrono::Local::now().format("%Y-%m-%d %H:%M").to_string();
+        let template = rule.name_template.as_deref().unwrap_or(&self.name_template);
+        let name = render_name(template, fields, &datetime);
+        let spawn_parent = rule.spawn_parent.or(self.spawn_parent);
+        let cpid = spawn_parent.unwrap_or(rule.parent_channel);
+        let cpid_arg = cpid.to_string();
+        let order = self
+            .spawned
+            .get(&cpid)
+            .and_then(|siblings| siblings.last())
+            .map(|cid| cid.to_string());
+        let codec = rule.codec.or(self.codec).map(|codec| codec.to_string());
+        let quality = rule
+            .codec_quality
+            .or(self.codec_quality)
+            .map(|quality| quality.to_string());
+        let mut properties = vec![("cpid", cpid_arg.as_str())];
+        if let Some(order) = &order {
+            properties.push(("channel_order", order));
+        }
+        if let Some(codec) = &codec {
+            properties.push(("channel_codec", codec));
+        }
+        if let Some(quality) = &quality {
+            properties.push(("channel_codec_quality", quality));
+        }
+        let max_clients = self.max_clients.map(|max| max.to_string());
+        if let Some(max_clients) = &max_clients {
+            properties.push(("channel_maxclients", max_clients));
+            properties.push(("channel_flag_maxclients_unlimited", "0"));
+        }
+        let password = match &self.password {
+            Some(template) if template.contains("{random}") => {
+                let template = template.replace("{random}", &random_password()?);
+                Some((render_name(&template, fields, &datetime), true))
+            }
+            Some(template) => Some((render_name(template, fields, &datetime), false)),
+            None => None,
+        };
+        if let Some((password, _)) = &password {
+            properties.push(("channel_flag_password", "1"));
+            properties.push(("channel_password", password));
+        }
+        let topic = self
+            .topic_template
+            .as_ref()
+            .map(|template| render_name(template, fields, &datetime));
+        if let Some(topic) = &topic {
+            properties.push(("channel_topic", topic));
+        }
+        let description = self
+            .description_template
+            .as_ref()
+            .map(|template| render_name(template, fields, &datetime));
+        if let Some(description) = &description {
+            properties.push(("channel_description", description));
+        }
+        if let Some(lifetime) = self.lifetime {
+            properties.push((lifetime.flag(), "1"));
+        }
+        let mut attempt = 1;
+        let (cid, name) = loop {
+            let candidate = numbered(&name, attempt);
+            match conn.channel_create(&candidate, &properties) {
+                Ok(cid) => break (cid, candidate),
+                // channel name is already in use
+                Err(QueryError::ServerError { id: 771, .. })
+                    if self.name_collision == NameCollision::Suffix
+                        && attempt < self.name_attempts =>
+                {
+                    debug!("Channel name {} is taken, trying another one", candidate);
+                    attempt += 1;
+                }
+                Err(QueryError::ServerError { id: 771, .. })
+                    if self.name_collision == NameCollision::Skip =>
+                {
+                    info!(
+                        "Channel name {} is taken, not creating a channel for {}({})",
+                        candidate, nickname, clid
+                    );
+                    return Ok(());
+                }
+                Err(QueryError::ServerError { id: 771, .. })
+                    if self.name_collision == NameCollision::Reuse =>
+                {
+                    return self.join_named(conn, clid, nickname, cpid, &candidate);
+                }
+                // invalid channelID, the configured spawn parent is gone
+                Err(QueryError::ServerError { id: 768, .. }) if spawn_parent.is_some() => {
+                    error!(
+                        "Spawn parent {} doesn't exist, not creating a channel for {}({})",
+                        cpid, nickname, clid
+                    );
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        info!(
+            event = "channel_created", cid = cid, creator_uid = uid;
+            "Created channel {}({}) for client {}({})", name, cid, nickname, clid
+        );
+        // Counted once the server confirms the move with notifyclientmoved
+        self.channels.insert(cid, 0);
+        self.owners.insert(cid, uid.to_string());
+        self.parents.insert(cid, rule.parent_channel);
+        self.names.insert(
+            cid,
+            SpawnedName {
+                creator: clid,
+                template: template.to_string(),
+                fields: fields.clone(),
+                datetime,
+                attempt,
+            },
+        );
+        self.spawned.entry(cpid).or_default().push(cid);
+        if !self.spawn_cooldown.is_zero() && !uid.is_empty() {
+            self.last_spawn.insert(uid.to_string(), now);
+        }
+        self.channels_changed();
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &self.metrics {
+            metrics.inc_channels_created();
+        }
+        self.observer.on_channel_created(cid, uid);
+        self.move_client(conn, clid, cid)?;
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &self.metrics {
+            metrics.inc_clients_moved();
+        }
+        self.observer.on_client_moved(clid, cid);
+        if let Some((password, true)) = &password {
+            conn.poke_client(clid, &format!("Password of your channel: {}", password))?;
+        }
+        if let Some(cgid) = self.creator_channel_group {
+            let cldbid = match fields.get("client_database_id") {
+                Some(cldbid) => cldbid.parse().map_err(|e| {
+                    QueryError::Protocol(format!(
+                        "Got error while parse client_database_id: {:?}",
+                        e
+                    ))
+                })?,
+                None => conn.client_dbid_from_uid(uid)?,
+            };
+            conn.set_client_channel_group(cgid, cid, cldbid)?;
+        }
+        Ok(())
+    }
+
+    /// Move `clid` into `cid`, remembering the move so its `notifyclientmoved` doesn't
+    /// spawn a channel even when `cid` is watched.
+    fn move_client(&mut self, conn: &mut TelnetConn, clid: i32, cid: i32) -> Result<QueryStatus> {
+        self.bot_moves.insert(clid, cid);
+        let result = conn.client_move(clid, cid);
+        if result.is_err() {
+            self.bot_moves.remove(&clid);
+        }
+        result
+    }
+
+    /// Render the names of the channels `clid` created again with its new nickname.
+    fn on_nickname_changed(
+        &mut self,
+        conn: &mut TelnetConn,
+        clid: i32,
+        nickname: &str,
+    ) -> Result<()> {
+        let mut renamed: Vec<i32> = self
+            .names
+            .iter()
+            .filter(|(_, name)| {
+                name.creator == clid
+                    && name.fields.get("client_nickname").map(String::as_str) != Some(nickname)
+            })
+            .map(|(cid, _)| *cid)
+            .collect();
+        renamed.sort_unstable();
+        for cid in renamed {
+            let spawned = match self.names.get_mut(&cid) {
+                Some(spawned) => spawned,
+                None => continue,
+            };
+            spawned
+                .fields
+                .insert("client_nickname".to_string(), nickname.to_string());
+            let name = numbered(
+                &render_name(&spawned.template, &spawned.fields, &spawned.datetime),
+                spawned.attempt,
+            );
+            info!(
+                "Client {} is now called {}, renaming channel {} to {}",
+                clid, nickname, cid, name
+            );
+            match conn.channel_edit(cid, &[("channel_name", &name)]) {
+                Ok(_) => {}
+                // channel name is already in use, keep the old one
+                Err(QueryError::ServerError { id: 771, .. }) => {
+                    warn!(
+                        "Channel name {} is taken, not renaming channel {}",
+                        name, cid
+                    )
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Move the client into the channel named `name` below `cpid`, for
+    /// [`NameCollision::Reuse`].
+    fn join_named(
+        &mut self,
+        conn: &mut TelnetConn,
+        clid: i32,
+        nickname: &str,
+        cpid: i32,
+        name: &str,
+    ) -> Result<()> {
+        let timeout = conn.timeout();
+        let (_, channels) =
+            conn.write_and_parse_list(&build_command("channellist", &[]), timeout)?;
+        let pid = cpid.to_string();
+        let existing = channels.iter().find(|channel| {
+            channel.get("pid") == Some(&pid)
+                && channel.get("channel_name").map(String::as_str) == Some(name)
+        });
+        let cid = match existing
+            .map(|channel| get_field(channel, "cid"))
+            .transpose()?
+        {
+            Some(cid) => cid,
+            None => {
+                warn!(
+                    "Channel name {} is taken but no channel below {} has it",
+                    name, cpid
+                );
+                return Ok(());
+            }
+        };
+        info!(
+            "Channel name {} is taken, moving client {}({}) into channel {}",
+            name, nickname, clid, cid
+        );
+        self.move_client(conn, clid, cid)?;
+        Ok(())
+    }
+
+    /// A client already online switched into a watched channel. Unlike
+    /// `notifycliententerview` the event lacks the identity, so ask for it.
+    fn on_client_moved_to_parent(
+        &mut self,
+        conn: &mut TelnetConn,
+        clid: i32,
+        parent: i32,
+    ) -> Result<()> {
+        let info = conn.client_info(clid)?;
+        let fields: HashMap<String, String> = [
+            ("clid", clid.to_string()),
+            ("ctid", parent.to_string()),
+            ("client_type", info.client_type().to_string()),
+            (
+                "client_unique_identifier",
+                info.client_unique_identifier().to_string(),
+            ),
+            ("client_nickname", info.client_nickname().to_string()),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value))
+        .collect();
+        self.on_client_enter(conn, &fields)
+    }
+
+    fn on_client_join(&mut self, clid: i32, cid: i32) {
+        if let Some(count) = self.channels.get_mut(&cid) {
+            *count += 1;
+            self.clients.insert(clid, cid);
+            self.empty_since.remove(&cid);
+        }
+    }
+
+    fn on_client_leave(&mut self, conn: &mut TelnetConn, clid: i32) -> Result<()> {
+        let cid = match self.clients.remove(&clid) {
+            Some(cid) => cid,
+            None => return Ok(()),
+        };
+        let count = match self.channels.get_mut(&cid) {
+            Some(count) => count,
+            None => return Ok(()),
+        };
+        *count = count.saturating_sub(1);
+        if *count > 0 || self.server_deletes() {
+            return Ok(());
+        }
+        if self.empty_grace.is_zero() {
+            return self.delete_channel(conn, cid);
+        }
+        self.empty_since.insert(cid, Instant::now());
+        Ok(())
+    }
+
+    /// Move the clients idle for longer than allowed to the AFK channel, at most once
+    /// per [`AFK_CHECK_INTERVAL`]. ServerQuery clients are left alone.
+    pub fn move_idle(&mut self, conn: &mut TelnetConn, now: Instant) -> Result<()> {
+        let afk_channel = match self.afk_channel {
+            Some(cid) if !self.afk_idle.is_zero() => cid,
+            _ => return Ok(()),
+        };
+        if let Some(last) = self.last_afk_check {
+            if now.saturating_duration_since(last) < AFK_CHECK_INTERVAL {
+                return Ok(());
+            }
+        }
+        self.last_afk_check = Some(now);
+        let mut flags = ClientListFlags::default();
+        flags.set_times(true);
+        let idle_ms = self.afk_idle.as_millis() as u64;
+        for client in conn.client_list(flags)? {
+            if client.is_query()
+                || client.cid() == i64::from(afk_channel)
+                || client.client_idle_time().unwrap_or_default() < idle_ms
+            {
+                continue;
+            }
+            info!(
+                "Client {}({}) is idle, moving it to channel {}",
+                client.client_nickname(),
+                client.clid(),
+                afk_channel
+            );
+            // One client gone meanwhile shouldn't keep the others where they are
+            if let Err(e) = self.move_client(conn, client.clid() as i32, afk_channel) {
+                warn!(
+                    "Got error while move client {} to the AFK channel: {:?}",
+                    client.clid(),
+                    e
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Clean up before exiting: delete the empty managed channels, unregister the
+    /// notifications and quit, all within `budget`. Whatever is left when it runs out
+    /// is abandoned, the connection is closed in any case.
+    pub fn shutdown(&mut self, conn: &mut TelnetConn, budget: Duration) {
+        let deadline = Instant::now() + budget;
+        let timeout = conn.timeout();
+        let mut empty: Vec<i32> = self
+            .channels
+            .iter()
+            .filter(|(_, count)| **count == 0 && !self.server_deletes())
+            .map(|(cid, _)| *cid)
+            .collect();
+        empty.sort_unstable();
+        let mut timed_out = false;
+        for (index, cid) in empty.iter().enumerate() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() || timed_out {
+                warn!(
+                    "Shutdown budget of {:?} used up, leaving {} empty channel(s)",
+                    budget,
+                    empty.len() - index
+                );
+                break;
+            }
+            conn.set_timeout(timeout.min(remaining));
+            if let Err(e) = self.delete_channel(conn, *cid) {
+                warn!("Got error while delete channel {}: {:?}", cid, e);
+                // The server is slow, don't wait for it again
+                timed_out = matches!(e, QueryError::Timeout | QueryError::IncompleteResponse(_));
+            }
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if !remaining.is_zero() && !timed_out {
+            conn.set_timeout(timeout.min(remaining));
+            if let Err(e) = conn.notify_unregister() {
+                warn!("Got error while unregister notifications: {:?}", e);
+            }
+        }
+        conn.set_timeout(timeout);
+        if let Err(e) = conn.quit() {
+            warn!("Got error while quit: {:?}", e);
+        }
+    }
+
+    /// Delete the channels that stayed empty for the whole grace period.
+    pub fn sweep(&mut self, conn: &mut TelnetConn, now: Instant) -> Result<()> {
+        let mut expired: Vec<i32> = self
+            .empty_since
+            .iter()
+            .filter(|(_, since)| now.saturating_duration_since(**since) >= self.empty_grace)
+            .map(|(cid, _)| *cid)
+            .collect();
+        expired.sort_unstable();
+        for cid in expired {
+            // One failing channel must not keep the others around
+            if let Err(e) = self.delete_channel(conn, cid) {
+                warn!("Got error while delete channel {}: {:?}", cid, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the server deletes the empty channels itself, we forget them on
+    /// `notifychanneldeleted`.
+    fn server_deletes(&self) -> bool {
+        self.lifetime == Some(ChannelLifetime::Temporary)
+    }
+
+    /// Delete the empty channel `cid`, it stays managed if that fails so the
+    /// sweep tries again.
+    fn delete_channel(&mut self, conn: &mut TelnetConn, cid: i32) -> Result<()> {
+        info!(event = "channel_deleted", cid = cid; "Channel {} is empty, deleting", cid);
+        match conn.channel_delete(cid, true) {
+            Ok(_) => {}
+            // invalid channelID, deleted by someone else meanwhile
+            Err(QueryError::ServerError { id: 768, .. }) => {
+                info!("Channel {} is gone already", cid);
+            }
+            Err(e) => {
+                self.empty_since.insert(cid, Instant::now());
+                return Err(e);
+            }
+        }
+        self.forget_channel(cid);
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &self.metrics {
+            metrics.inc_channels_deleted();
+        }
+        self.observer.on_channel_deleted(cid);
+        Ok(())
+    }
+
+    fn forget_channel(&mut self, cid: i32) {
+        if self.channels.remove(&cid).is_none() {
+            return;
+        }
+        self.owners.remove(&cid);
+        self.names.remove(&cid);
+        self.parents.remove(&cid);
+        self.empty_since.remove(&cid);
+        for siblings in self.spawned.values_mut() {
+            siblings.retain(|sibling| *sibling != cid);
+        }
+        self.clients.retain(|_, channel| *channel != cid);
+        self.channels_changed();
+    }
+
+    /// Publish the health of the query connection.
+    #[cfg(feature = "metrics")]
+    fn report_ping(&self, conn: &TelnetConn) {
+        if let Some(metrics) = &self.metrics {
+            if let Some((at, latency)) = conn.last_ping() {
+                metrics.set_ping(latency, at);
+            }
+            if let Some(at) = conn.last_reply() {
+                metrics.set_last_reply(at);
+            }
+        }
+    }
+
+    /// Publish that the connection delivers events, see [`Metrics::ready`].
+    #[cfg(feature = "metrics")]
+    fn report_event(&self) {
+        if let Some(metrics) = &self.metrics {
+            metrics.set_last_event(std::time::SystemTime::now());
+        }
+    }
+
+    /// Publish our share of the managed channels of all servers.
+    #[cfg(feature = "metrics")]
+    fn report_channels(&mut self) {
+        if let Some(metrics) = &self.metrics {
+            metrics.update_active_temp_channels(self.reported_channels, self.channels.len());
+            self.reported_channels = self.channels.len();
+        }
+    }
+
+    /// Publish the set of managed channels after it changed.
+    fn channels_changed(&mut self) {
+        #[cfg(feature = "metrics")]
+        self.report_channels();
+        if let Some(path) = &self.state_file {
+            if let Err(e) = self.state().save(path) {
+                warn!("Got error while save state: {:?}", e);
+            }
+        }
+    }
+
+    /// The managed channels, ordered by cid.
+    pub fn state(&self) -> State {
+        let mut channels: Vec<_> = self
+            .owners
+            .iter()
+            .map(|(cid, owner)| {
+                let parent = self.parents.get(cid).copied().unwrap_or_default();
+                ManagedChannel::new(*cid, owner.clone(), parent)
+            })
+            .collect();
+        channels.sort_by_key(ManagedChannel::cid);
+        State::new(channels)
+    }
+
+    /// Resume managing the channels of a previous run. Channels gone meanwhile are
+    /// dropped, empty ones deleted and the clients inside the others are tracked again.
+    pub fn restore(&mut self, conn: &mut TelnetConn, state: &State) -> Result<()> {
+        let (_, channels) = conn.query_channels()?;
+        let (_, clients) = conn.query_clients()?;
+        self.query_clients.extend(
+            clients
+                .iter()
+                .filter(|client| client.is_query())
+                .map(|client| client.clid() as i32),
+        );
+        for managed in state.channels() {
+            let cid = managed.cid();
+            if !channels.iter().any(|channel| channel.cid() == cid as i64) {
+                info!("Channel {} from the state file is gone", cid);
+                continue;
+            }
+            let inside: Vec<i32> = clients
+                .iter()
+                .filter(|client| client.cid() == cid as i64 && !client.is_query())
+                .map(|client| client.clid() as i32)
+                .collect();
+            if inside.is_empty() {
+                info!(event = "channel_deleted", cid = cid; "Channel {} from the state file is empty, deleting", cid);
+                if let Err(e) = conn.channel_delete(cid, true) {
+                    warn!("Got error while delete channel {}: {:?}", cid, e);
+                }
+                continue;
+            }
+            self.channels.insert(cid, inside.len());
+            self.owners.insert(cid, managed.owner().to_string());
+            self.parents.insert(cid, managed.parent());
+            for clid in inside {
+                self.clients.insert(clid, cid);
+            }
+        }
+        info!("Resumed managing {} channel(s)", self.channels.len());
+        self.channels_changed();
+        Ok(())
+    }
+
+    /// Number of managed channels created for `uid`.
+    pub fn owned_by(&self, uid: &str) -> usize {
+        self.owners.values().filter(|owner| *owner == uid).count()
+    }
+
+    /// Number of channels created for `uid` from the watched channel `parent`.
+    pub fn owned_below(&self, uid: &str, parent: i32) -> usize {
+        self.owners
+            .iter()
+            .filter(|(cid, owner)| *owner == uid && self.parents.get(cid) == Some(&parent))
+            .count()
+    }
+
+    /// Most recent channel `uid` owns below `parent`.
+    fn newest_owned_below(&self, uid: &str, parent: i32) -> Option<i32> {
+        self.owners
+            .iter()
+            .filter(|(cid, owner)| *owner == uid && self.parents.get(cid) == Some(&parent))
+            .map(|(cid, _)| *cid)
+            .max()
+    }
+
+    /// Current occupancy of a channel created by us, `None` for other channels.
+    pub fn occupancy(&self, cid: i32) -> Option<usize> {
+        self.channels.get(&cid).copied()
+    }
+}
+
+/// Name template used unless configured otherwise.
+pub const DEFAULT_NAME_TEMPLATE: &str = "{client_nickname}'s Channel";
+
+/// Time [`AutoChannel::shutdown`] may take unless configured otherwise.
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time between two checks of the idle times, see [`AutoChannel::move_idle`].
+pub const AFK_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Channels waiting to be spawned at most unless configured otherwise.
+pub const DEFAULT_MAX_PENDING: usize = 64;
+
+/// Names tried per spawned channel unless configured otherwise.
+pub const DEFAULT_NAME_ATTEMPTS: u32 = 5;
+
+/// Expand the `{placeholder}`s of `template`. `{client_nickname}`, `{client_uid}` and any
+/// other field of the `notifycliententerview` are taken from `fields`, `{datetime}` is
+/// replaced by `datetime`. Unknown placeholders are kept as is. The result is unescaped,
+/// escaping happens when the command is built.
+pub fn render_name(template: &str, fields: &HashMap<String, String>, datetime: &str) -> String {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+        output.push_str(&rest[..start]);
+        let placeholder = &rest[start + 1..end];
+        let value = match placeholder {
+            "datetime" => Some(datetime),
+            "client_uid" => fields.get("client_unique_identifier").map(String::as_str),
+            _ => fields.get(placeholder).map(String::as_str),
+        };
+        match value {
+            Some(value) => output.push_str(value),
+            None => output.push_str(&rest[start..=end]),
+        }
+        rest = &rest[end + 1..];
+    }
+    output.push_str(rest);
+    output
+}
+
+/// The `attempt`th name tried for a channel, the second is suffixed with ` (2)` and so on.
+fn numbered(name: &str, attempt: u32) -> String {
+    match attempt {
+        1 => name.to_string(),
+        _ => format!("{} ({})", name, attempt),
+    }
+}
+
+/// Eight letters and digits for `{random}` in the channel password, leaving out the
+/// ones easily mixed up when read out.
+fn random_password() -> Result<String> {
+    const CHARSET: &[u8] = b"abcdefghjkmnpqrstuvwxyzABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+    crate::random::string(CHARSET, 8)
+}
+
+/// Log in, select the virtual server, take the `nickname` and register the
+/// notifications the daemon needs. `user` is `None` when the transport already
+/// authenticated (SSH), `commands` is whether admins may send `!commands`.
+pub fn setup_session(
+    conn: &mut TelnetConn,
+    user: Option<(&str, &str)>,
+    server: VirtualServer,
+    nickname: Option<&str>,
+    commands: bool,
+) -> Result<()> {
+    if let Some((user, password)) = user {
+        conn.login(user, password)?;
+    }
+    conn.select_virtual_server(server)?;
+    if let Some(nickname) = nickname {
+        let nickname = conn.set_nickname(nickname)?;
+        info!("Using nickname {}", nickname);
+    }
+    register_notifications(conn, commands)
+}
+
+fn register_notifications(conn: &mut TelnetConn, commands: bool) -> Result<()> {
+    // Channel events of every channel, this covers all watched parents and is needed
+    // to follow clients leaving spawned channels
+    conn.notify_register("channel", Some(0))?;
+    // Private messages, the commands of the admins
+    if commands {
+        conn.notify_register("textprivate", None)?;
+    }
+    Ok(())
+}
+
+/// Handle events until the connection fails or `running` is cleared. `reload` is
+/// polled once per round and returns a new config to apply, e.g. after SIGHUP.
+pub fn serve(
+    conn: &mut TelnetConn,
+    auto_channel: &mut AutoChannel,
+    keepalive: Duration,
+    running: &AtomicBool,
+    reload: &mut dyn FnMut() -> Option<Config>,
+) -> Result<()> {
+    // Clients of a previous connection may be gone meanwhile
+    auto_channel.pending.clear();
+    auto_channel.bot_moves.clear();
+    auto_channel.refresh_query_clients(conn)?;
+    auto_channel.refresh_subtree(conn)?;
+    while running.load(Ordering::SeqCst) {
+        if let Some(config) = reload() {
+            if let Err(e) = auto_channel.reload(conn, &config) {
+                error!("Got error while apply the reloaded config: {:?}", e);
+            }
+        }
+        // Don't wait for events while channels are waiting to be spawned
+        let timeout = if auto_channel.pending() > 0 { 0 } else { 1 };
+        if let Some(event) = conn.poll_event(timeout)? {
+            #[cfg(feature = "metrics")]
+            auto_channel.report_event();
+            check_server_stop(&event)?;
+            if let Err(e) = auto_channel.queue_event(conn, &event) {
+                error!("Got error while handle event: {:?}", e);
+            }
+            // Take the events received meanwhile too, so a burst is coalesced
+            // before the rate limited spawns
+            while let Some(event) = conn.poll_event(0)? {
+                check_server_stop(&event)?;
+                if let Err(e) = auto_channel.queue_event(conn, &event) {
+                    error!("Got error while handle event: {:?}", e);
+                }
+            }
+        }
+        if let Err(e) = auto_channel.process_next(conn) {
+            error!("Got error while spawn channel: {:?}", e);
+        }
+        if let Err(e) = auto_channel.sweep(conn, Instant::now()) {
+            error!("Got error while delete empty channels: {:?}", e);
+        }
+        if let Err(e) = auto_channel.move_idle(conn, Instant::now()) {
+            error!("Got error while move idle clients: {:?}", e);
+        }
+        conn.keepalive(keepalive, Instant::now())?;
+        #[cfg(feature = "metrics")]
+        auto_channel.report_ping(conn);
+    }
+    Ok(())
+}
+
+/// Fail on `notifyserverstop`, so the caller reconnects instead of waiting for the
+/// connection to drop.
+fn check_server_stop(event: &Notification) -> Result<()> {
+    if let Ok(Event::ServerStop { reasonmsg }) = event.event() {
+        let reason = reasonmsg.unwrap_or_else(|| "no reason given".to_string());
+        warn!("Server is shutting down: {}", reason);
+        return Err(QueryError::ServerStopped(reason));
+    }
+    Ok(())
+}
+
+fn get_field(fields: &HashMap<String, String>, key: &str) -> Result<i32> {
+    fields
+        .get(key)
+        .ok_or_else(|| QueryError::Protocol(format!("Missing field {} in notification", key)))?
+        .parse()
+        .map_err(|e| QueryError::Protocol(format!("Got error while parse {}: {:?}", key, e)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        random_password, render_name, serve, AutoChannel, AutoChannelObserver, ChannelLifetime,
+        Limit, NameCollision, WatchRule,
+    };
+    use crate::config::Config;
+    use crate::connection::test::mock_conn;
+    use crate::datastructures::Notification;
+    use crate::error::QueryError;
+    use crate::protocol::parse_kv;
+    use crate::state::{ManagedChannel, State};
+    use crate::transport::mock::MockTransport;
+    use crate::TelnetConn;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    const ENTER_LINE: &str = "notifycliententerview cfid=0 ctid=1 reasonid=0 clid=5 client_unique_identifier=abcdef= client_nickname=Alice client_type=0";
+
+    fn event(line: &str) -> Notification {
+        Notification::try_from(line).unwrap()
+    }
+
+    #[test]
+    fn test_enter_parent_channel() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&["cid=42\n\rerror id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+
+        let mut auto_channel = AutoChannel::new(1);
+        auto_channel
+            .handle_event(&mut conn, &event(ENTER_LINE))
+            .unwrap();
+
+        assert_eq!(
+            mock.written(),
+            vec![
+                "channelcreate channel_name=Alice's\\sChannel cpid=1\n".to_string(),
+                "clientmove clid=5 cid=42\n".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ignore_query_clients() {
+        let (mut auto_channel, mut conn, mock) = spawned_channel();
+        auto_channel
+            .handle_event(
+                &mut conn,
+                &event("notifycliententerview cfid=0 ctid=1 reasonid=0 clid=7 client_unique_identifier=monitor client_nickname=monitor client_type=1"),
+            )
+            .unwrap();
+        auto_channel
+            .handle_event(
+                &mut conn,
+                &event("notifyclientmoved ctid=42 reasonid=0 clid=7"),
+            )
+            .unwrap();
+        assert_eq!(auto_channel.occupancy(42), Some(1));
+        assert_eq!(mock.written().len(), 2);
+
+        // The query client left inside doesn't keep the channel
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        auto_channel
+            .handle_event(
+                &mut conn,
+                &event("notifyclientmoved ctid=3 reasonid=0 clid=5"),
+            )
+            .unwrap();
+        assert_eq!(auto_channel.occupancy(42), None);
+        assert_eq!(
+            mock.written().last().unwrap(),
+            "channeldelete cid=42 force=1\n"
+        );
+    }
+
+    #[test]
+    fn test_move_into_parent_channel() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&["cid=1 client_database_id=3 client_type=0 client_unique_identifier=abcdef= client_nickname=Alice\n\rerror id=0 msg=ok\n\r"]);
+        mock.push_reply(&["cid=42\n\rerror id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+
+        let mut auto_channel = AutoChannel::new(1);
+        auto_channel
+            .handle_event(
+                &mut conn,
+                &event("notifyclientmoved ctid=1 reasonid=0 clid=5"),
+            )
+            .unwrap();
+        assert_eq!(
+            mock.written(),
+            vec![
+                "clientinfo clid=5\n".to_string(),
+                "channelcreate channel_name=Alice's\\sChannel cpid=1\n".to_string(),
+                "clientmove clid=5 cid=42\n".to_string(),
+            ]
+        );
+        assert_eq!(auto_channel.owned_by("abcdef="), 1);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_metrics() {
+        use crate::metrics::{test::scrape, Metrics};
+
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&["cid=42\n\rerror id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        let metrics = Arc::new(Metrics::new());
+        let mut auto_channel = AutoChannel::new(1);
+        auto_channel.set_metrics(metrics.clone());
+        auto_channel
+            .handle_event(&mut conn, &event(ENTER_LINE))
+            .unwrap();
+
+        let response = scrape(metrics);
+        assert!(response.contains("\nchannels_created_total 1\n"));
+        assert!(response.contains("\nclients_moved_total 1\n"));
+        assert!(response.contains("\nchannels_deleted_total 0\n"));
+        assert!(response.contains("\nactive_temp_channels 1\n"));
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_metrics_shared() {
+        use crate::metrics::Metrics;
+
+        // Two virtual servers count into one gauge
+        let metrics = Arc::new(Metrics::new());
+        let (mut first, mut first_conn, first_mock) = spawned_channel();
+        first.set_metrics(metrics.clone());
+        let (mut second, _, _) = spawned_channel();
+        second.set_metrics(metrics.clone());
+        assert!(metrics.render().contains("\nactive_temp_channels 2\n"));
+
+        first_mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        first
+            .handle_event(
+                &mut first_conn,
+                &event("notifyclientmoved ctid=3 reasonid=0 clid=5"),
+            )
+            .unwrap();
+        assert_eq!(first.occupancy(42), None);
+        assert!(metrics.render().contains("\nactive_temp_channels 1\n"));
+    }
+
+    #[test]
+    fn test_render_name() {
+        let fields =
+            parse_kv("clid=5 client_unique_identifier=abcdef= client_nickname=Alice\\sB\\p\\/ob");
+        assert_eq!(
+            render_name("{client_nickname}'s Channel", &fields, ""),
+            "Alice B|/ob's Channel"
+        );
+        assert_eq!(
+            render_name("{client_uid} {datetime}", &fields, "2022-06-01 12:00"),
+            "abcdef= 2022-06-01 12:00"
+        );
+        assert_eq!(render_name("{unknown} {", &fields, ""), "{unknown} {");
+        assert_eq!(render_name("{clid}", &fields, ""), "5");
+    }
+
+    #[test]
+    fn test_name_template() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&["cid=42\n\rerror id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+
+        let mut auto_channel = AutoChannel::new(1);
+        auto_channel.set_name_template("[{client_nickname}]".to_string());
+        auto_channel
+            .handle_event(
+                &mut conn,
+                &event("notifycliententerview cfid=0 ctid=1 reasonid=0 clid=5 client_nickname=Alice\\sB\\pob client_type=0"),
+            )
+            .unwrap();
+        assert_eq!(
+            mock.written()[0],
+            "channelcreate channel_name=[Alice\\sB\\pob] cpid=1\n"
+        );
+    }
+
+    #[test]
+    fn test_creator_channel_group() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&["cid=42\n\rerror id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        mock.push_reply(&["cluid=abcdef= cldbid=17\n\rerror id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+
+        let mut auto_channel = AutoChannel::new(1);
+        auto_channel.set_creator_channel_group(Some(5));
+        auto_channel
+            .handle_event(&mut conn, &event(ENTER_LINE))
+            .unwrap();
+        assert_eq!(
+            mock.written(),
+            vec![
+                "channelcreate channel_name=Alice's\\sChannel cpid=1\n".to_string(),
+                "clientmove clid=5 cid=42\n".to_string(),
+                "clientgetdbidfromuid cluid=abcdef=\n".to_string(),
+                "setclientchannelgroup cgid=5 cid=42 cldbid=17\n".to_string(),
+            ]
+        );
+
+        // Known database id, no lookup needed
+        mock.push_reply(&["cid=43\n\rerror id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        auto_channel
+            .handle_event(
+                &mut conn,
+                &event("notifycliententerview cfid=0 ctid=1 reasonid=0 clid=6 client_unique_identifier=xyz= client_nickname=Bob client_database_id=23 client_type=0"),
+            )
+            .unwrap();
+        assert_eq!(
+            mock.written().last().unwrap(),
+            "setclientchannelgroup cgid=5 cid=43 cldbid=23\n"
+        );
+    }
+
+    fn enter(clid: i32, uid: &str) -> Notification {
+        event(&format!(
+            "notifycliententerview cfid=0 ctid=1 reasonid=0 clid={} client_unique_identifier={} client_nickname=User{} client_type=0",
+            clid, uid, clid
+        ))
+    }
+
+    #[test]
+    fn test_rejoin_existing() {
+        let (mut conn, mock) = mock_conn();
+        let mut auto_channel = AutoChannel::new(1);
+        auto_channel.set_rejoin_existing(true);
+        auto_channel.set_empty_grace(Duration::from_secs(30));
+        mock.push_reply(&["cid=42\n\rerror id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        auto_channel
+            .handle_event(&mut conn, &enter(5, "abc="))
+            .unwrap();
+        auto_channel
+            .handle_event(
+                &mut conn,
+                &event("notifyclientmoved ctid=42 reasonid=1 clid=5"),
+            )
+            .unwrap();
+
+        // Back to the lobby, then moved into the kept channel again
+        mock.push_reply(&["cid=1 client_database_id=3 client_type=0 client_unique_identifier=abc= client_nickname=User5\n\rerror id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        auto_channel
+            .handle_event(
+                &mut conn,
+                &event("notifyclientmoved ctid=1 reasonid=0 clid=5"),
+            )
+            .unwrap();
+        assert_eq!(auto_channel.owned_by("abc="), 1);
+        assert_eq!(mock.written()[3], "clientmove clid=5 cid=42\n");
+
+        // The channel was deleted without a notification
+        mock.push_reply(&["error id=768 msg=invalid\\schannelID\n\r"]);
+        mock.push_reply(&["cid=43\n\rerror id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        auto_channel
+            .handle_event(&mut conn, &enter(7, "abc="))
+            .unwrap();
+        assert_eq!(auto_channel.occupancy(42), None);
+        assert_eq!(auto_channel.occupancy(43), Some(0));
+        assert_eq!(auto_channel.owned_by("abc="), 1);
+        let written = mock.written();
+        assert_eq!(written[4], "clientmove clid=7 cid=42\n");
+        assert!(written[5].starts_with("channelcreate "));
+        assert_eq!(written[6], "clientmove clid=7 cid=43\n");
+    }
+
+    #[test]
+    fn test_admin_commands() {
+        let (mut conn, mock) = mock_conn();
+        let mut auto_channel = AutoChannel::new(1);
+        auto_channel.set_admin_uids(vec!["admin=".to_string()]);
+        let message = |uid: &str, msg: &str| {
+            event(&format!(
+                "notifytextmessage targetmode=1 msg={} target=1 invokerid=5 invokername=Alice invokeruid={}",
+                crate::protocol::escape(msg),
+                uid
+            ))
+        };
+
+        // Not a command, e.g. our own reply
+        auto_channel
+            .handle_event(&mut conn, &message("admin=", "Hello"))
+            .unwrap();
+        assert!(mock.written().is_empty());
+
+        // Others are ignored without a reply
+        auto_channel
+            .handle_event(&mut conn, &message("other=", "!stats"))
+            .unwrap();
+        assert!(mock.written().is_empty());
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        auto_channel
+            .handle_event(&mut conn, &message("admin=", "!stats"))
+            .unwrap();
+
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        mock.push_reply(&["cid=3 client_database_id=3 client_type=0 client_unique_identifier=admin= client_nickname=Alice\n\rerror id=0 msg=ok\n\r"]);
+        mock.push_reply(&["cid=42\n\rerror id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        auto_channel
+            .handle_event(&mut conn, &message("admin=", "!spawn"))
+            .unwrap();
+        assert_eq!(auto_channel.owned_by("admin="), 1);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        auto_channel
+            .handle_event(&mut conn, &message("admin=", "!spawn 2"))
+            .unwrap();
+
+        assert_eq!(
+            mock.written(),
+            vec![
+                "sendtextmessage targetmode=1 target=5 msg=Managing\\s0\\schannel(s)\\swith\\s0\\sclient(s),\\s0\\swaiting\\sto\\sbe\\sspawned\n".to_string(),
+                "sendtextmessage targetmode=1 target=5 msg=Spawning\\sa\\schannel\\sbelow\\s1\\sfor\\syou\n".to_string(),
+                "clientinfo clid=5\n".to_string(),
+                "channelcreate channel_name=Alice's\\sChannel cpid=1\n".to_string(),
+                "clientmove clid=5 cid=42\n".to_string(),
+                "sendtextmessage targetmode=1 target=5 msg=Channel\\s2\\sis\\snot\\swatched\n".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_server_stop() {
+        let (mut conn, mock) = mock_conn();
+        let mut auto_channel = AutoChannel::new(1);
+        mock.push_reply(&["clid=1 cid=1 client_database_id=1 client_nickname=serveradmin client_type=1\n\rerror id=0 msg=ok\n\r"]);
+        mock.push_read("notifyserverstop reasonmsg=Maintenance\n\r");
+        let running = std::sync::atomic::AtomicBool::new(true);
+        let result = serve(
+            &mut conn,
+            &mut auto_channel,
+            Duration::from_secs(300),
+            &running,
+            &mut || None,
+        );
+        assert!(
+            matches!(result, Err(QueryError::ServerStopped(ref reason)) if reason == "Maintenance")
+        );
+        assert_eq!(mock.written(), vec!["clientlist\n".to_string()]);
+    }
+
+    #[test]
+    fn test_query_clients_before_startup() {
+        let (mut conn, mock) = mock_conn();
+        let mut auto_channel = AutoChannel::new(1);
+        // Connected before us, so its notifycliententerview was never seen
+        mock.push_reply(&["clid=1 cid=1 client_database_id=1 client_nickname=serveradmin client_type=1|clid=9 cid=3 client_database_id=2 client_nickname=monitor client_type=1\n\rerror id=0 msg=ok\n\r"]);
+        mock.push_read("notifyclientmoved ctid=1 reasonid=0 clid=9\n\r");
+        mock.push_read("notifyserverstop reasonmsg=Maintenance\n\r");
+        let running = std::sync::atomic::AtomicBool::new(true);
+        let result = serve(
+            &mut conn,
+            &mut auto_channel,
+            Duration::from_secs(300),
+            &running,
+            &mut || None,
+        );
+        assert!(matches!(result, Err(QueryError::ServerStopped(_))));
+        assert_eq!(auto_channel.pending(), 0);
+        assert_eq!(mock.written(), vec!["clientlist\n".to_string()]);
+    }
+
+    #[test]
+    fn test_move_idle() {
+        let (mut conn, mock) = mock_conn();
+        let mut auto_channel = AutoChannel::new(1);
+        auto_channel.set_afk_channel(Some(9), Duration::from_secs(600));
+        mock.push_reply(&["clid=1 cid=1 client_database_id=1 client_nickname=serveradmin client_type=1 client_idle_time=900000|clid=5 cid=2 client_database_id=3 client_nickname=Idle client_type=0 client_idle_time=600000|clid=6 cid=2 client_database_id=4 client_nickname=Active client_type=0 client_idle_time=599999|clid=7 cid=9 client_database_id=5 client_nickname=Away client_type=0 client_idle_time=900000\n\rerror id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        let now = Instant::now();
+        auto_channel.move_idle(&mut conn, now).unwrap();
+        // Not checked again right away
+        auto_channel
+            .move_idle(&mut conn, now + Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(
+            mock.written(),
+            vec![
+                "clientlist -times\n".to_string(),
+                "clientmove clid=5 cid=9\n".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ignore_own_moves() {
+        let (mut conn, mock) = mock_conn();
+        let mut auto_channel = AutoChannel::new(1);
+        // Idle clients end up in the watched channel
+        auto_channel.set_afk_channel(Some(1), Duration::from_secs(600));
+        mock.push_reply(&["clid=5 cid=2 client_database_id=3 client_nickname=Idle client_type=0 client_idle_time=600000\n\rerror id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        auto_channel.move_idle(&mut conn, Instant::now()).unwrap();
+        let moved = event("notifyclientmoved ctid=1 reasonid=1 clid=5");
+        auto_channel.handle_event(&mut conn, &moved).unwrap();
+        assert_eq!(auto_channel.pending(), 0);
+        assert_eq!(mock.written().len(), 2);
+
+        // The echo is consumed, moving in by itself spawns again
+        mock.push_reply(&["cid=1 client_database_id=3 client_type=0 client_unique_identifier=abc= client_nickname=Idle\n\rerror id=0 msg=ok\n\r"]);
+        mock.push_reply(&["cid=42\n\rerror id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        auto_channel
+            .handle_event(
+                &mut conn,
+                &event("notifyclientmoved ctid=1 reasonid=0 clid=5"),
+            )
+            .unwrap();
+        let written = mock.written();
+        assert_eq!(
+            written[written.len() - 2..],
+            [
+                "channelcreate channel_name=Idle's\\sChannel cpid=1\n".to_string(),
+                "clientmove clid=5 cid=42\n".to_string(),
+            ]
+        );
+        // Counted in the spawned channel on the echo of that move, no second spawn
+        auto_channel
+            .handle_event(
+                &mut conn,
+                &event("notifyclientmoved ctid=42 reasonid=1 clid=5"),
+            )
+            .unwrap();
+        assert_eq!(auto_channel.occupancy(42), Some(1));
+        assert_eq!(mock.written().len(), written.len());
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let (mut conn, mock) = mock_conn();
+        let mut auto_channel = AutoChannel::new(1);
+        for (clid, cid) in [(5, 42), (6, 43), (7, 44)] {
+            mock.push_reply(&[&format!("cid={}\n\rerror id=0 msg=ok\n\r", cid)]);
+            mock.push_reply(&["error id=0 msg=ok\n\r"]);
+            auto_channel
+                .handle_event(&mut conn, &enter(clid, &format!("{}=", clid)))
+                .unwrap();
+        }
+        auto_channel
+            .handle_event(
+                &mut conn,
+                &event("notifyclientmoved ctid=44 reasonid=1 clid=7"),
+            )
+            .unwrap();
+
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        auto_channel.shutdown(&mut conn, Duration::from_secs(5));
+        assert_eq!(
+            mock.written()[6..],
+            [
+                "channeldelete cid=42 force=1\n".to_string(),
+                "channeldelete cid=43 force=1\n".to_string(),
+                "servernotifyunregister\n".to_string(),
+                "quit\n".to_string(),
+            ]
+        );
+        assert_eq!(auto_channel.occupancy(44), Some(1));
+    }
+
+    #[test]
+    fn test_shutdown_budget() {
+        let (mut conn, mock) = mock_conn();
+        let mut auto_channel = AutoChannel::new(1);
+        for (clid, cid) in [(5, 42), (6, 43)] {
+            mock.push_reply(&[&format!("cid={}\n\rerror id=0 msg=ok\n\r", cid)]);
+            mock.push_reply(&["error id=0 msg=ok\n\r"]);
+            auto_channel
+                .handle_event(&mut conn, &enter(clid, &format!("{}=", clid)))
+                .unwrap();
+        }
+
+        // The server hangs on the first delete
+        mock.set_delay(Duration::from_secs(10));
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        let started = Instant::now();
+        auto_channel.shutdown(&mut conn, Duration::from_millis(200));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(
+            mock.written()[4..],
+            [
+                "channeldelete cid=42 force=1\n".to_string(),
+                "quit\n".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_burst() {
+        let (mut conn, mock) = mock_conn();
+        let mut auto_channel = AutoChannel::new(1);
+        auto_channel.set_max_pending(20);
+        // 25 clients entering twice each
+        for clid in 100..125 {
+            for round in 0..2 {
+                auto_channel
+                    .queue_event(&mut conn, &enter(clid, &format!("uid{}-{}=", clid, round)))
+                    .unwrap();
+                assert!(auto_channel.pending() <= 20);
+            }
+        }
+        assert_eq!(auto_channel.pending(), 20);
+        // One leaves before its channel was spawned
+        auto_channel
+            .queue_event(
+                &mut conn,
+                &event("notifyclientleftview cfid=1 ctid=0 reasonid=8 clid=110"),
+            )
+            .unwrap();
+        assert_eq!(auto_channel.pending(), 19);
+        assert!(mock.written().is_empty());
+
+        for cid in 0..19 {
+            mock.push_reply(&[&format!("cid={}\n\rerror id=0 msg=ok\n\r", 200 + cid)]);
+            mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        }
+        while auto_channel.process_next(&mut conn).unwrap() {}
+        let written = mock.written();
+        assert_eq!(written.len(), 38);
+        let moved: Vec<i32> = written
+            .iter()
+            .filter_map(|line| line.strip_prefix("clientmove clid="))
+            .map(|line| line.split(' ').next().unwrap().parse().unwrap())
+            .collect();
+        // The oldest five were dropped, the rest spawned once in order
+        let expected: Vec<i32> = (105..125).filter(|clid| *clid != 110).collect();
+        assert_eq!(moved, expected);
+        // The second enter replaced the first
+        assert_eq!(auto_channel.owned_by("uid105-1="), 1);
+        assert_eq!(auto_channel.owned_by("uid105-0="), 0);
+    }
+
+    #[test]
+    fn test_spawn_cooldown() {
+        let (mut conn, mock) = mock_conn();
+        let mut auto_channel = AutoChannel::new(1);
+        auto_channel.set_spawn_cooldown(Duration::from_secs(60));
+        mock.push_reply(&["cid=42\n\rerror id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        auto_channel
+            .handle_event(&mut conn, &enter(5, "abc="))
+            .unwrap();
+
+        // A second connection of the same identity joins the existing channel
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        auto_channel
+            .handle_event(&mut conn, &enter(6, "abc="))
+            .unwrap();
+        assert_eq!(auto_channel.owned_by("abc="), 1);
+
+        // Rejoining after the channel is gone gets a poke
+        auto_channel
+            .handle_event(
+                &mut conn,
+                &event("notifyclientmoved ctid=42 reasonid=1 clid=5"),
+            )
+            .unwrap();
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        auto_channel
+            .handle_event(
+                &mut conn,
+                &event("notifyclientleftview cfid=42 ctid=0 reasonid=8 clid=5"),
+            )
+            .unwrap();
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        auto_channel
+            .handle_event(&mut conn, &enter(7, "abc="))
+            .unwrap();
+        assert_eq!(auto_channel.owned_by("abc="), 0);
+
+        // Other identities are not affected
+        mock.push_reply(&["cid=43\n\rerror id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        auto_channel
+            .handle_event(&mut conn, &enter(8, "def="))
+            .unwrap();
+        let written = mock.written();
+        assert_eq!(written.len(), 7);
+        assert!(written[0].starts_with("channelcreate "));
+        assert_eq!(written[1], "clientmove clid=5 cid=42\n");
+        assert_eq!(written[2], "clientmove clid=6 cid=42\n");
+        assert_eq!(written[3], "channeldelete cid=42 force=1\n");
+        assert_eq!(
+            written[4],
+            "clientpoke clid=7 msg=Please\\swait\\s60s\\sbefore\\screating\\sanother\\schannel\n"
+        );
+        assert!(written[5].starts_with("channelcreate "));
+    }
+
+    #[test]
+    fn test_spawn_cooldown_channel_gone() {
+        let (mut conn, mock) = mock_conn();
+        let mut auto_channel = AutoChannel::new(1);
+        auto_channel.set_spawn_cooldown(Duration::from_secs(60));
+        mock.push_reply(&["cid=42\n\rerror id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        auto_channel
+            .handle_event(&mut conn, &enter(5, "abc="))
+            .unwrap();
+
+        // Deleted without a notification, the cooldown still applies
+        mock.push_reply(&["error id=768 msg=invalid\\schannelID\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        auto_channel
+            .handle_event(&mut conn, &enter(6, "abc="))
+            .unwrap();
+        assert_eq!(auto_channel.occupancy(42), None);
+        assert_eq!(auto_channel.owned_by("abc="), 0);
+        let written = mock.written();
+        assert_eq!(written.len(), 4);
+        assert_eq!(written[2], "clientmove clid=6 cid=42\n");
+        assert_eq!(
+            written[3],
+            "clientpoke clid=6 msg=Please\\swait\\s60s\\sbefore\\screating\\sanother\\schannel\n"
+        );
+    }
+
+    #[test]
+    fn test_max_channels_per_user() {
+        let (mut conn, mock) = mock_conn();
+        let mut auto_channel = AutoChannel::new(1);
+        auto_channel.set_max_channels_per_user(Some(2));
+        for (clid, cid) in [(5, 42), (6, 43)] {
+            mock.push_reply(&[&format!("cid={}\n\rerror id=0 msg=ok\n\r", cid)]);
+            mock.push_reply(&["error id=0 msg=ok\n\r"]);
+            auto_channel
+                .handle_event(&mut conn, &enter(clid, "abc="))
+                .unwrap();
+        }
+        assert_eq!(auto_channel.owned_by("abc="), 2);
+        assert_eq!(mock.written().len(), 4);
+
+        auto_channel
+            .handle_event(&mut conn, &enter(7, "abc="))
+            .unwrap();
+        assert_eq!(mock.written().len(), 4);
+
+        // Another identity is not affected
+        mock.push_reply(&["cid=44\n\rerror id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        auto_channel
+            .handle_event(&mut conn, &enter(8, "def="))
+            .unwrap();
+        assert_eq!(mock.written().len(), 6);
+
+        // Freed once a channel is deleted
+        auto_channel
+            .handle_event(
+                &mut conn,
+                &event("notifyclientmoved ctid=42 reasonid=1 clid=5"),
+            )
+            .unwrap();
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        auto_channel
+            .handle_event(
+                &mut conn,
+                &event("notifyclientleftview cfid=42 ctid=0 reasonid=8 clid=5"),
+            )
+            .unwrap();
+        assert_eq!(auto_channel.owned_by("abc="), 1);
+        mock.push_reply(&["cid=45\n\rerror id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        auto_channel
+            .handle_event(&mut conn, &enter(9, "abc="))
+            .unwrap();
+        assert_eq!(auto_channel.owned_by("abc="), 2);
+    }
+
+    #[test]
+    fn test_max_channels_total() {
+        let (mut conn, mock) = mock_conn();
+        let mut auto_channel = AutoChannel::new(1);
+        auto_channel.set_max_channels_total(Some(1));
+        mock.push_reply(&["cid=42\n\rerror id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        auto_channel
+            .handle_event(&mut conn, &enter(5, "abc="))
+            .unwrap();
+        auto_channel
+            .handle_event(&mut conn, &enter(6, "def="))
+            .unwrap();
+        assert_eq!(mock.written().len(), 2);
+
+        auto_channel
+            .handle_event(&mut conn, &event("notifychanneldeleted cid=42 invokerid=0"))
+            .unwrap();
+        mock.push_reply(&["cid=43\n\rerror id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        auto_channel
+            .handle_event(&mut conn, &enter(6, "def="))
+            .unwrap();
+        assert_eq!(mock.written().len(), 4);
+    }
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl AutoChannelObserver for Recorder {
+        fn on_channel_created(&self, cid: i32, creator_uid: &str) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("created {} {}", cid, creator_uid));
+        }
+        fn on_client_moved(&self, clid: i32, cid: i32) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("moved {} {}", clid, cid));
+        }
+        fn on_channel_deleted(&self, cid: i32) {
+            self.0.lock().unwrap().push(format!("deleted {}", cid));
+        }
+        fn on_limit_reached(&self, clid: i32, uid: &str, limit: Limit) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("limit {} {} {:?}", clid, uid, limit));
+        }
+    }
+
+    #[test]
+    fn test_observer() {
+        let (mut conn, mock) = mock_conn();
+        let recorder = Arc::new(Recorder::default());
+        let mut auto_channel = AutoChannel::new(1);
+        auto_channel.set_max_channels_total(Some(1));
+        auto_channel.set_observer(recorder.clone());
+        mock.push_reply(&["cid=42\n\rerror id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        for event in [
+            enter(5, "abc="),
+            enter(6, "def="),
+            event("notifyclientmoved ctid=42 reasonid=1 clid=5"),
+            event("notifyclientmoved ctid=3 reasonid=0 clid=5"),
+        ] {
+            auto_channel.handle_event(&mut conn, &event).unwrap();
+        }
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec![
+                "created 42 abc=".to_string(),
+                "moved 5 42".to_string(),
+                "limit 6 def= Total(1)".to_string(),
+                "deleted 42".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_max_clients() {
+        let (mut conn, mock) = mock_conn();
+        let mut auto_channel = AutoChannel::new(1);
+        for cid in [42, 43] {
+            mock.push_reply(&[&format!("cid={}\n\rerror id=0 msg=ok\n\r", cid)]);
+            mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        }
+        auto_channel
+            .handle_event(&mut conn, &enter(5, "abc="))
+            .unwrap();
+        auto_channel.set_max_clients(Some(4));
+        auto_channel
+            .handle_event(&mut conn, &enter(6, "def="))
+            .unwrap();
+        let written = mock.written();
+        assert_eq!(
+            written[0],
+            "channelcreate channel_name=User5's\\sChannel cpid=1\n"
+        );
+        assert_eq!(
+            written[2],
+            "channelcreate channel_name=User6's\\sChannel cpid=1 channel_order=42 channel_maxclients=4 channel_flag_maxclients_unlimited=0\n"
+        );
+    }
+
+    #[test]
+    fn test_password() {
+        let (mut conn, mock) = mock_conn();
+        let mut auto_channel = AutoChannel::new(1);
+        auto_channel.set_password(Some("s3cret {client_nickname}".to_string()));
+        mock.push_reply(&["cid=42\n\rerror id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        auto_channel
+            .handle_event(&mut conn, &enter(5, "abc="))
+            .unwrap();
+        assert_eq!(
+            mock.written(),
+            vec![
+                "channelcreate channel_name=User5's\\sChannel cpid=1 channel_flag_password=1 channel_password=s3cret\\sUser5\n"
+                    .to_string(),
+                "clientmove clid=5 cid=42\n".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_topic_and_description() {
+        let (mut conn, mock) = mock_conn();
+        let mut auto_channel = AutoChannel::new(1);
+        auto_channel.set_topic_template(Some("Owner: {client_nickname}".to_string()));
+        auto_channel.set_description_template(Some(
+            "[b]{client_nickname}|{client_uid}[/b]\nCreated by the bot\n".to_string(),
+        ));
+        mock.push_reply(&["cid=42\n\rerror id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        auto_channel
+            .handle_event(&mut conn, &enter(5, "a/b="))
+            .unwrap();
+        assert_eq!(
+            mock.written()[0],
+            "channelcreate channel_name=User5's\\sChannel cpid=1 channel_topic=Owner:\\sUser5 channel_description=[b]User5\\pa\\/b=[\\/b]\\nCreated\\sby\\sthe\\sbot\\n\n"
+        );
+    }
+
+    #[test]
+    fn test_lifetime() {
+        for (lifetime, flag) in [
+            ("temporary", " channel_flag_temporary=1"),
+            ("semi-permanent", " channel_flag_semi_permanent=1"),
+            ("permanent", " channel_flag_permanent=1"),
+        ] {
+            let (mut conn, mock) = mock_conn();
+            let mut auto_channel = AutoChannel::new(1);
+            auto_channel.set_lifetime(Some(lifetime.parse().unwrap()));
+            mock.push_reply(&["cid=42\n\rerror id=0 msg=ok\n\r"]);
+            mock.push_reply(&["error id=0 msg=ok\n\r"]);
+            auto_channel
+                .handle_event(&mut conn, &enter(5, "abc="))
+                .unwrap();
+            assert_eq!(
+                mock.written()[0],
+                format!(
+                    "channelcreate channel_name=User5's\\sChannel cpid=1{}\n",
+                    flag
+                )
+            );
+        }
+        assert!("forever".parse::<ChannelLifetime>().is_err());
+    }
+
+    #[test]
+    fn test_temporary_left_to_server() {
+        let (mut auto_channel, mut conn, mock) = spawned_channel();
+        auto_channel.set_lifetime(Some(ChannelLifetime::Temporary));
+        auto_channel
+            .handle_event(
+                &mut conn,
+                &event("notifyclientmoved ctid=3 reasonid=0 clid=5"),
+            )
+            .unwrap();
+        assert_eq!(auto_channel.occupancy(42), Some(0));
+        assert_eq!(mock.written().len(), 2);
+        auto_channel.sweep(&mut conn, Instant::now()).unwrap();
+        assert_eq!(mock.written().len(), 2);
+
+        auto_channel
+            .handle_event(&mut conn, &event("notifychanneldeleted cid=42 invokerid=0"))
+            .unwrap();
+        assert_eq!(auto_channel.occupancy(42), None);
+    }
+
+    #[test]
+    fn test_random_password() {
+        let (mut conn, mock) = mock_conn();
+        let mut auto_channel = AutoChannel::new(1);
+        auto_channel.set_password(Some("{random}".to_string()));
+        mock.push_reply(&["cid=42\n\rerror id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        auto_channel
+            .handle_event(&mut conn, &enter(5, "abc="))
+            .unwrap();
+        let written = mock.written();
+        assert_eq!(written.len(), 3);
+        let password = written[0]
+            .trim_end()
+            .split(' ')
+            .find_map(|arg| arg.strip_prefix("channel_password="))
+            .unwrap();
+        assert_eq!(password.len(), 8);
+        assert!(password.chars().all(|c| c.is_ascii_alphanumeric()));
+        assert!(written[0].contains(" channel_flag_password=1 "));
+        assert_eq!(
+            written[2],
+            format!(
+                "clientpoke clid=5 msg=Password\\sof\\syour\\schannel:\\s{}\n",
+                password
+            )
+        );
+        assert_ne!(random_password().unwrap(), random_password().unwrap());
+    }
+
+    #[test]
+    fn test_watch_rules() {
+        let (mut conn, mock) = mock_conn();
+        let mut gaming = WatchRule::new(1);
+        gaming.set_name_template(Some("Game {client_nickname}".to_string()));
+        gaming.set_max_per_user(Some(1));
+        let mut music = WatchRule::new(2);
+        music.set_codec(Some(5));
+        music.set_codec_quality(Some(10));
+        let mut auto_channel = AutoChannel::with_rules(vec![gaming, music]);
+        auto_channel.set_codec(Some(4));
+        auto_channel.set_codec_quality(Some(6));
+        for cid in [42, 43] {
+            mock.push_reply(&[&format!("cid={}\n\rerror id=0 msg=ok\n\r", cid)]);
+            mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        }
+        auto_channel
+            .handle_event(&mut conn, &enter(5, "abc="))
+            .unwrap();
+        auto_channel
+            .handle_event(
+                &mut conn,
+                &event("notifycliententerview cfid=0 ctid=2 reasonid=0 clid=6 client_unique_identifier=abc= client_nickname=User6 client_type=0"),
+            )
+            .unwrap();
+        // The limit of the first rule doesn't count the channel below the second parent
+        assert_eq!(auto_channel.owned_below("abc=", 1), 1);
+        assert_eq!(auto_channel.owned_below("abc=", 2), 1);
+        auto_channel
+            .handle_event(&mut conn, &enter(7, "abc="))
+            .unwrap();
+
+        assert_eq!(
+            mock.written(),
+            vec![
+                "channelcreate channel_name=Game\\sUser5 cpid=1 channel_codec=4 channel_codec_quality=6\n"
+                    .to_string(),
+                "clientmove clid=5 cid=42\n".to_string(),
+                "channelcreate channel_name=User6's\\sChannel cpid=2 channel_codec=5 channel_codec_quality=10\n"
+                    .to_string(),
+                "clientmove clid=6 cid=43\n".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_watch_subtree() {
+        let (mut conn, mock) = mock_conn();
+        let mut auto_channel = AutoChannel::new(1);
+        auto_channel.set_watch_subtree(Some(10));
+        mock.push_reply(&[concat!(
+            "cid=1 pid=0 channel_order=0 channel_name=Lobby|",
+            "cid=10 pid=0 channel_order=1 channel_name=Games|",
+            "cid=11 pid=10 channel_order=0 channel_name=Shooter|",
+            "cid=12 pid=11 channel_order=0 channel_name=Squad\n\r",
+            "error id=0 msg=ok\n\r"
+        )]);
+        auto_channel.refresh_subtree(&mut conn).unwrap();
+        for cid in [42, 43] {
+            mock.push_reply(&[&format!("cid={}\n\rerror id=0 msg=ok\n\r", cid)]);
+            mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        }
+        for (clid, ctid) in [(5, 11), (6, 12), (7, 10)] {
+            auto_channel
+                .handle_event(
+                    &mut conn,
+                    &event(&format!("notifycliententerview cfid=0 ctid={} reasonid=0 clid={} client_unique_identifier=u{}= client_nickname=User{} client_type=0", ctid, clid, clid, clid)),
+                )
+                .unwrap();
+        }
+        // Our own channel doesn't change the tree
+        auto_channel
+            .handle_event(
+                &mut conn,
+                &event("notifychannelcreated cid=42 cpid=11 channel_name=User5's\\sChannel"),
+            )
+            .unwrap();
+        // A new lobby is watched once the server reports it
+        mock.push_reply(&[concat!(
+            "cid=10 pid=0 channel_order=1 channel_name=Games|",
+            "cid=11 pid=10 channel_order=0 channel_name=Shooter|",
+            "cid=13 pid=10 channel_order=11 channel_name=Racing|",
+            "cid=42 pid=11 channel_order=0 channel_name=User5's\\sChannel\n\r",
+            "error id=0 msg=ok\n\r"
+        )]);
+        auto_channel
+            .handle_event(
+                &mut conn,
+                &event("notifychannelcreated cid=13 cpid=10 channel_name=Racing"),
+            )
+            .unwrap();
+        mock.push_reply(&["cid=44\n\rerror id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        auto_channel
+            .handle_event(&mut conn, &event("notifycliententerview cfid=0 ctid=13 reasonid=0 clid=8 client_unique_identifier=u8= client_nickname=User8 client_type=0"))
+            .unwrap();
+        // Neither the removed lobby nor the spawned channel spawn
+        for ctid in [12, 42] {
+            auto_channel
+                .handle_event(
+                    &mut conn,
+                    &event(&format!(
+                        "notifyclientmoved ctid={} reasonid=0 clid=9",
+                        ctid
+                    )),
+                )
+                .unwrap();
+        }
+
+        assert_eq!(
+            mock.written(),
+            vec![
+                "channellist\n".to_string(),
+                "channelcreate channel_name=User5's\\sChannel cpid=11\n".to_string(),
+                "clientmove clid=5 cid=42\n".to_string(),
+                "channelcreate channel_name=User6's\\sChannel cpid=12\n".to_string(),
+                "clientmove clid=6 cid=43\n".to_string(),
+                "channellist\n".to_string(),
+                "channelcreate channel_name=User8's\\sChannel cpid=13\n".to_string(),
+                "clientmove clid=8 cid=44\n".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reload() {
+        let (mut conn, mock) = mock_conn();
+        let mut config = Config::default();
+        config.set_parent_channel(Some(1));
+        let mut auto_channel = AutoChannel::new(1);
+        auto_channel.configure(&config);
+        for cid in [42, 43] {
+            mock.push_reply(&[&format!("cid={}\n\rerror id=0 msg=ok\n\r", cid)]);
+            mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        }
+        auto_channel
+            .handle_event(&mut conn, &enter(5, "a="))
+            .unwrap();
+
+        config.set_channel_name_template(Some("Room of {client_nickname}".to_string()));
+        auto_channel.reload(&mut conn, &config).unwrap();
+        auto_channel
+            .handle_event(&mut conn, &enter(6, "b="))
+            .unwrap();
+
+        // Every channel's events are registered already
+        config.set_parent_channel(Some(2));
+        auto_channel.reload(&mut conn, &config).unwrap();
+        auto_channel
+            .handle_event(&mut conn, &enter(7, "c="))
+            .unwrap();
+        assert_eq!(auto_channel.occupancy(42), Some(0));
+
+        assert_eq!(
+            mock.written(),
+            vec![
+                "channelcreate channel_name=User5's\\sChannel cpid=1\n".to_string(),
+                "clientmove clid=5 cid=42\n".to_string(),
+                "channelcreate channel_name=Room\\sof\\sUser6 cpid=1 channel_order=42\n"
+                    .to_string(),
+                "clientmove clid=6 cid=43\n".to_string(),
+            ]
+        );
+
+        // The admins' commands arrive as private messages
+        config.set_admin_uids(Some(vec!["admin=".to_string()]));
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        auto_channel.reload(&mut conn, &config).unwrap();
+        // Registered once, without unregistering the channel events
+        config.set_parent_channel(Some(3));
+        auto_channel.reload(&mut conn, &config).unwrap();
+        assert_eq!(
+            mock.written()[4..],
+            ["servernotifyregister event=textprivate\n".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_servers() {
+        let mut config = Config::default();
+        let mut first = Config::default();
+        first.set_sid(Some(1));
+        first.set_parent_channel(Some(1));
+        let mut second = Config::default();
+        second.set_sid(Some(2));
+        second.set_parent_channel(Some(1));
+        second.set_channel_name_template(Some("Room {client_nickname}".to_string()));
+        config.set_servers(Some(vec![first, second]));
+        let mut servers: Vec<_> = config
+            .targets()
+            .iter()
+            .map(|target| {
+                let mut auto_channel = AutoChannel::with_rules(Vec::new());
+                auto_channel.configure(target);
+                let (conn, mock) = mock_conn();
+                (auto_channel, conn, mock)
+            })
+            .collect();
+        for (auto_channel, conn, mock) in &mut servers {
+            mock.push_reply(&["cid=42\n\rerror id=0 msg=ok\n\r"]);
+            mock.push_reply(&["error id=0 msg=ok\n\r"]);
+            auto_channel.handle_event(conn, &enter(5, "a=")).unwrap();
+            auto_channel
+                .handle_event(conn, &event("notifyclientmoved ctid=42 reasonid=0 clid=5"))
+                .unwrap();
+        }
+        // Leaving on the first server leaves the channel of the second alone
+        let (auto_channel, conn, mock) = &mut servers[0];
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        auto_channel
+            .handle_event(
+                conn,
+                &event("notifyclientleftview cfid=42 ctid=0 reasonid=8 clid=5"),
+            )
+            .unwrap();
+        assert_eq!(servers[0].0.occupancy(42), None);
+        assert_eq!(servers[1].0.occupancy(42), Some(1));
+
+        assert_eq!(
+            servers[0].2.written(),
+            vec![
+                "channelcreate channel_name=User5's\\sChannel cpid=1\n".to_string(),
+                "clientmove clid=5 cid=42\n".to_string(),
+                "channeldelete cid=42 force=1\n".to_string(),
+            ]
+        );
+        assert_eq!(
+            servers[1].2.written(),
+            vec![
+                "channelcreate channel_name=Room\\sUser5 cpid=1\n".to_string(),
+                "clientmove clid=5 cid=42\n".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rename_on_nickname_change() {
+        let (mut conn, mock) = mock_conn();
+        let mut auto_channel = AutoChannel::new(1);
+        auto_channel.set_name_attempts(2);
+        mock.push_reply(&["error id=771 msg=channel\\sname\\sis\\salready\\sin\\suse\n\r"]);
+        mock.push_reply(&["cid=42\n\rerror id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        auto_channel
+            .handle_event(&mut conn, &enter(5, "abc="))
+            .unwrap();
+
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        for line in [
+            "notifyclientupdated clid=5 client_nickname=Alice",
+            // Nothing to rename
+            "notifyclientupdated clid=5 client_nickname=Alice",
+            "notifyclientupdated clid=5 client_away=1",
+            "notifyclientupdated clid=6 client_nickname=Bob",
+        ] {
+            auto_channel.handle_event(&mut conn, &event(line)).unwrap();
+        }
+        // Gone, its clid may belong to someone else next
+        auto_channel
+            .handle_event(
+                &mut conn,
+                &event("notifyclientleftview cfid=42 ctid=0 reasonid=8 clid=5"),
+            )
+            .unwrap();
+        auto_channel
+            .handle_event(
+                &mut conn,
+                &event("notifyclientupdated clid=5 client_nickname=Carol"),
+            )
+            .unwrap();
+        assert_eq!(
+            mock.written()[3..],
+            ["channeledit cid=42 channel_name=Alice's\\sChannel\\s(2)\n".to_string()]
+        );
+        assert_eq!(auto_channel.occupancy(42), Some(0));
+    }
+
+    #[test]
+    fn test_name_collision() {
+        const TAKEN: &str = "error id=771 msg=channel\\sname\\sis\\salready\\sin\\suse\n\r";
+        assert_eq!("reuse".parse(), Ok(NameCollision::Reuse));
+        assert!("rename".parse::<NameCollision>().is_err());
+
+        let (mut conn, mock) = mock_conn();
+        let mut auto_channel = AutoChannel::new(1);
+        auto_channel.set_name_collision(NameCollision::Skip);
+        mock.push_reply(&[TAKEN]);
+        auto_channel
+            .handle_event(&mut conn, &enter(5, "a="))
+            .unwrap();
+        assert_eq!(
+            mock.written(),
+            vec!["channelcreate channel_name=User5's\\sChannel cpid=1\n".to_string()]
+        );
+        assert_eq!(auto_channel.state(), State::default());
+
+        let (mut conn, mock) = mock_conn();
+        let mut auto_channel = AutoChannel::new(1);
+        auto_channel.set_name_collision(NameCollision::Reuse);
+        mock.push_reply(&[TAKEN]);
+        mock.push_reply(&["cid=1 pid=0 channel_name=Lobby|cid=7 pid=2 channel_name=User5's\\sChannel|cid=8 pid=1 channel_name=User5's\\sChannel\n\rerror id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        auto_channel
+            .handle_event(&mut conn, &enter(5, "a="))
+            .unwrap();
+        assert_eq!(
+            mock.written()[1..],
+            [
+                "channellist\n".to_string(),
+                "clientmove clid=5 cid=8\n".to_string()
+            ]
+        );
+        // Not ours, it isn't deleted when empty
+        assert_eq!(auto_channel.occupancy(8), None);
+    }
+
+    #[test]
+    fn test_name_taken() {
+        let (mut conn, mock) = mock_conn();
+        let mut auto_channel = AutoChannel::new(1);
+        auto_channel.set_name_attempts(2);
+        mock.push_reply(&["error id=771 msg=channel\\sname\\sis\\salready\\sin\\suse\n\r"]);
+        mock.push_reply(&["cid=42\n\rerror id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        auto_channel
+            .handle_event(&mut conn, &enter(5, "a="))
+            .unwrap();
+        assert_eq!(auto_channel.occupancy(42), Some(0));
+
+        // Out of attempts
+        for _ in 0..2 {
+            mock.push_reply(&["error id=771 msg=channel\\sname\\sis\\salready\\sin\\suse\n\r"]);
+        }
+        assert!(matches!(
+            auto_channel.handle_event(&mut conn, &enter(5, "b=")),
+            Err(QueryError::ServerError { id: 771, .. })
+        ));
+
+        assert_eq!(
+            mock.written(),
+            vec![
+                "channelcreate channel_name=User5's\\sChannel cpid=1\n".to_string(),
+                "channelcreate channel_name=User5's\\sChannel\\s(2) cpid=1\n".to_string(),
+                "clientmove clid=5 cid=42\n".to_string(),
+                "channelcreate channel_name=User5's\\sChannel cpid=1 channel_order=42\n"
+                    .to_string(),
+                "channelcreate channel_name=User5's\\sChannel\\s(2) cpid=1 channel_order=42\n"
+                    .to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_channel_order() {
+        let (mut conn, mock) = mock_conn();
+        for cid in [42, 43, 44] {
+            mock.push_reply(&[&format!("cid={}\n\rerror id=0 msg=ok\n\r", cid)]);
+            mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        }
+        let mut auto_channel = AutoChannel::new(1);
+        auto_channel
+            .handle_event(&mut conn, &enter(5, "abc="))
+            .unwrap();
+        auto_channel
+            .handle_event(&mut conn, &enter(6, "def="))
+            .unwrap();
+        // The newest channel is gone, the next one goes after the one before
+        auto_channel
+            .handle_event(&mut conn, &event("notifychanneldeleted cid=43 invokerid=0"))
+            .unwrap();
+        auto_channel
+            .handle_event(&mut conn, &enter(7, "ghi="))
+            .unwrap();
+
+        let creates: Vec<_> = mock
+            .written()
+            .into_iter()
+            .filter(|command| command.starts_with("channelcreate"))
+            .collect();
+        assert_eq!(
+            creates,
+            vec![
+                "channelcreate channel_name=User5's\\sChannel cpid=1\n".to_string(),
+                "channelcreate channel_name=User6's\\sChannel cpid=1 channel_order=42\n"
+                    .to_string(),
+                "channelcreate channel_name=User7's\\sChannel cpid=1 channel_order=42\n"
+                    .to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_spawn_parent() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&["cid=42\n\rerror id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=768 msg=invalid\\schannelID\n\r"]);
+        let mut auto_channel = AutoChannel::new(1);
+        auto_channel.set_spawn_parent(Some(9));
+        auto_channel
+            .handle_event(&mut conn, &enter(5, "abc="))
+            .unwrap();
+        assert_eq!(auto_channel.owned_below("abc=", 1), 1);
+
+        // A missing spawn parent skips the channel without failing
+        auto_channel
+            .handle_event(&mut conn, &enter(6, "def="))
+            .unwrap();
+        assert_eq!(auto_channel.owned_below("def=", 1), 0);
+        assert_eq!(
+            mock.written(),
+            vec![
+                "channelcreate channel_name=User5's\\sChannel cpid=9\n".to_string(),
+                "clientmove clid=5 cid=42\n".to_string(),
+                "channelcreate channel_name=User6's\\sChannel cpid=9 channel_order=42\n"
+                    .to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_restore() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&[
+            "cid=1 pid=0 channel_order=0 channel_name=Lobby total_clients=0 channel_needed_subscribe_power=0|",
+            "cid=42 pid=1 channel_order=0 channel_name=Alice's\\sChannel total_clients=1 channel_needed_subscribe_power=0|",
+            "cid=43 pid=1 channel_order=42 channel_name=Bob's\\sChannel total_clients=0 channel_needed_subscribe_power=0\n\r",
+            "error id=0 msg=ok\n\r",
+        ]);
+        mock.push_reply(&[
+            "clid=1 cid=1 client_database_id=1 client_nickname=serveradmin client_type=1 client_unique_identifier=serveradmin|",
+            "clid=5 cid=42 client_database_id=3 client_nickname=Alice client_type=0 client_unique_identifier=abc=|",
+            // A query client alone doesn't keep Bob's channel
+            "clid=6 cid=43 client_database_id=4 client_nickname=monitor client_type=1 client_unique_identifier=monitor\n\r",
+            "error id=0 msg=ok\n\r",
+        ]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        let state = State::new(vec![
+            ManagedChannel::new(42, "abc=".to_string(), 1),
+            ManagedChannel::new(43, "def=".to_string(), 1),
+            ManagedChannel::new(44, "ghi=".to_string(), 1),
+        ]);
+
+        let mut auto_channel = AutoChannel::new(1);
+        auto_channel.restore(&mut conn, &state).unwrap();
+        assert_eq!(
+            mock.written(),
+            vec![
+                "channellist\n".to_string(),
+                "clientlist -uid\n".to_string(),
+                "channeldelete cid=43 force=1\n".to_string(),
+            ]
+        );
+        assert_eq!(auto_channel.occupancy(42), Some(1));
+        assert_eq!(auto_channel.occupancy(43), None);
+        assert_eq!(auto_channel.occupancy(44), None);
+        assert_eq!(
+            auto_channel.state(),
+            State::new(vec![ManagedChannel::new(42, "abc=".to_string(), 1)])
+        );
+
+        // The restored client leaving still cleans up
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        auto_channel
+            .handle_event(
+                &mut conn,
+                &event("notifyclientleftview cfid=42 ctid=0 reasonid=8 clid=5"),
+            )
+            .unwrap();
+        assert_eq!(
+            mock.written().last().unwrap(),
+            "channeldelete cid=42 force=1\n"
+        );
+        assert_eq!(auto_channel.state(), State::default());
+    }
+
+    fn spawned_channel() -> (AutoChannel, TelnetConn, MockTransport) {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&["cid=42\n\rerror id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        let mut auto_channel = AutoChannel::new(1);
+        auto_channel
+            .handle_event(&mut conn, &event(ENTER_LINE))
+            .unwrap();
+        assert_eq!(auto_channel.occupancy(42), Some(0));
+        auto_channel
+            .handle_event(
+                &mut conn,
+                &event("notifyclientmoved ctid=42 reasonid=1 clid=5"),
+            )
+            .unwrap();
+        assert_eq!(auto_channel.occupancy(42), Some(1));
+        (auto_channel, conn, mock)
+    }
+
+    #[test]
+    fn test_delete_after_move_out() {
+        let (mut auto_channel, mut conn, mock) = spawned_channel();
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        auto_channel
+            .handle_event(
+                &mut conn,
+                &event("notifyclientmoved ctid=3 reasonid=0 clid=5"),
+            )
+            .unwrap();
+        assert_eq!(auto_channel.occupancy(42), None);
+        assert_eq!(
+            mock.written().last().unwrap(),
+            "channeldelete cid=42 force=1\n"
+        );
+    }
+
+    #[test]
+    fn test_delete_after_disconnect() {
+        let (mut auto_channel, mut conn, mock) = spawned_channel();
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        auto_channel
+            .handle_event(
+                &mut conn,
+                &event("notifyclientleftview cfid=42 ctid=0 reasonid=8 reasonmsg=leaving clid=5"),
+            )
+            .unwrap();
+        assert_eq!(auto_channel.occupancy(42), None);
+        assert_eq!(
+            mock.written().last().unwrap(),
+            "channeldelete cid=42 force=1\n"
+        );
+    }
+
+    /// [`spawned_channel`] with a second channel 43 spawned for client 6.
+    fn two_spawned_channels() -> (AutoChannel, TelnetConn, MockTransport) {
+        let (mut auto_channel, mut conn, mock) = spawned_channel();
+        mock.push_reply(&["cid=43\n\rerror id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        auto_channel
+            .handle_event(&mut conn, &enter(6, "b="))
+            .unwrap();
+        auto_channel
+            .handle_event(
+                &mut conn,
+                &event("notifyclientmoved ctid=43 reasonid=1 clid=6"),
+            )
+            .unwrap();
+        assert_eq!(auto_channel.occupancy(43), Some(1));
+        (auto_channel, conn, mock)
+    }
+
+    #[test]
+    fn test_move_into_managed() {
+        let (mut auto_channel, mut conn, mock) = two_spawned_channels();
+        auto_channel
+            .handle_event(
+                &mut conn,
+                &event("notifyclientmoved ctid=42 reasonid=0 clid=7"),
+            )
+            .unwrap();
+        assert_eq!(auto_channel.occupancy(42), Some(2));
+        assert_eq!(auto_channel.occupancy(43), Some(1));
+
+        // From one managed channel into the other
+        auto_channel
+            .handle_event(
+                &mut conn,
+                &event("notifyclientmoved ctid=43 reasonid=0 clid=7"),
+            )
+            .unwrap();
+        assert_eq!(auto_channel.occupancy(42), Some(1));
+        assert_eq!(auto_channel.occupancy(43), Some(2));
+        assert_eq!(mock.written().len(), 4);
+    }
+
+    #[test]
+    fn test_move_out_of_managed() {
+        let (mut auto_channel, mut conn, mock) = two_spawned_channels();
+        // The last one leaves 43 for 42
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        auto_channel
+            .handle_event(
+                &mut conn,
+                &event("notifyclientmoved ctid=42 reasonid=0 clid=6"),
+            )
+            .unwrap();
+        assert_eq!(auto_channel.occupancy(42), Some(2));
+        assert_eq!(auto_channel.occupancy(43), None);
+        assert_eq!(
+            mock.written().last().unwrap(),
+            "channeldelete cid=43 force=1\n"
+        );
+
+        // Left for an unmanaged channel
+        auto_channel
+            .handle_event(
+                &mut conn,
+                &event("notifyclientmoved ctid=3 reasonid=0 clid=5"),
+            )
+            .unwrap();
+        assert_eq!(auto_channel.occupancy(42), Some(1));
+        assert_eq!(mock.written().len(), 5);
+    }
+
+    #[test]
+    fn test_refill_keeps_channel() {
+        let (mut auto_channel, mut conn, mock) = spawned_channel();
+        auto_channel
+            .handle_event(
+                &mut conn,
+                &event("notifyclientmoved ctid=42 reasonid=0 clid=6"),
+            )
+            .unwrap();
+        auto_channel
+            .handle_event(
+                &mut conn,
+                &event("notifyclientmoved ctid=3 reasonid=0 clid=5"),
+            )
+            .unwrap();
+        assert_eq!(auto_channel.occupancy(42), Some(1));
+        assert_eq!(mock.written().len(), 2);
+
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        auto_channel
+            .handle_event(
+                &mut conn,
+                &event("notifyclientmoved ctid=3 reasonid=0 clid=6"),
+            )
+            .unwrap();
+        assert_eq!(
+            mock.written().last().unwrap(),
+            "channeldelete cid=42 force=1\n"
+        );
+    }
+
+    #[test]
+    fn test_failed_delete_kept() {
+        let (mut auto_channel, mut conn, mock) = two_spawned_channels();
+        mock.push_reply(&["error id=2568 msg=insufficient\\sclient\\spermissions\n\r"]);
+        assert!(auto_channel
+            .handle_event(
+                &mut conn,
+                &event("notifyclientmoved ctid=3 reasonid=0 clid=5"),
+            )
+            .is_err());
+        assert_eq!(auto_channel.occupancy(42), Some(0));
+        assert_eq!(auto_channel.state().channels()[0].cid(), 42);
+
+        // Retried by the sweep, a failure there doesn't stop the other channels
+        auto_channel.set_empty_grace(Duration::from_secs(30));
+        auto_channel
+            .handle_event(
+                &mut conn,
+                &event("notifyclientmoved ctid=3 reasonid=0 clid=6"),
+            )
+            .unwrap();
+        mock.push_reply(&["error id=2568 msg=insufficient\\sclient\\spermissions\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        auto_channel
+            .sweep(&mut conn, Instant::now() + Duration::from_secs(30))
+            .unwrap();
+        assert_eq!(auto_channel.occupancy(42), Some(0));
+        assert_eq!(auto_channel.occupancy(43), None);
+
+        // Deleted by someone else meanwhile
+        mock.push_reply(&["error id=768 msg=invalid\\schannelID\n\r"]);
+        auto_channel
+            .sweep(&mut conn, Instant::now() + Duration::from_secs(30))
+            .unwrap();
+        assert_eq!(auto_channel.occupancy(42), None);
+        let written = mock.written();
+        assert_eq!(
+            written[4..],
+            [
+                "channeldelete cid=42 force=1\n".to_string(),
+                "channeldelete cid=42 force=1\n".to_string(),
+                "channeldelete cid=43 force=1\n".to_string(),
+                "channeldelete cid=42 force=1\n".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_empty_grace_refill() {
+        let (mut auto_channel, mut conn, mock) = spawned_channel();
+        auto_channel.set_empty_grace(Duration::from_secs(30));
+        auto_channel
+            .handle_event(
+                &mut conn,
+                &event("notifyclientmoved ctid=3 reasonid=0 clid=5"),
+            )
+            .unwrap();
+        assert_eq!(auto_channel.occupancy(42), Some(0));
+        auto_channel
+            .handle_event(
+                &mut conn,
+                &event("notifyclientmoved ctid=42 reasonid=0 clid=5"),
+            )
+            .unwrap();
+        auto_channel
+            .sweep(&mut conn, Instant::now() + Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(auto_channel.occupancy(42), Some(1));
+        assert_eq!(mock.written().len(), 2);
+    }
+
+    #[test]
+    fn test_empty_grace_expired() {
+        let (mut auto_channel, mut conn, mock) = spawned_channel();
+        auto_channel.set_empty_grace(Duration::from_secs(30));
+        auto_channel
+            .handle_event(
+                &mut conn,
+                &event("notifyclientleftview cfid=42 ctid=0 reasonid=8 reasonmsg=leaving clid=5"),
+            )
+            .unwrap();
+        auto_channel.sweep(&mut conn, Instant::now()).unwrap();
+        assert_eq!(auto_channel.occupancy(42), Some(0));
+        assert_eq!(mock.written().len(), 2);
+
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        auto_channel
+            .sweep(&mut conn, Instant::now() + Duration::from_secs(30))
+            .unwrap();
+        assert_eq!(auto_channel.occupancy(42), None);
+        assert_eq!(
+            mock.written().last().unwrap(),
+            "channeldelete cid=42 force=1\n"
+        );
+    }
+
+    #[test]
+    fn test_ignore_unmanaged_channel() {
+        let (mut conn, mock) = mock_conn();
+        let mut auto_channel = AutoChannel::new(1);
+        auto_channel
+            .handle_event(
+                &mut conn,
+                &event("notifyclientmoved ctid=7 reasonid=0 clid=8"),
+            )
+            .unwrap();
+        auto_channel
+            .handle_event(
+                &mut conn,
+                &event("notifyclientmoved ctid=3 reasonid=0 clid=8"),
+            )
+            .unwrap();
+        assert_eq!(auto_channel.occupancy(7), None);
+        assert!(mock.written().is_empty());
+    }
+
+    #[test]
+    fn test_enter_other_channel() {
+        let (mut conn, mock) = mock_conn();
+        let mut auto_channel = AutoChannel::new(2);
+        auto_channel
+            .handle_event(&mut conn, &event(ENTER_LINE))
+            .unwrap();
+        assert!(mock.written().is_empty());
+    }
+}
@@ -0,0 +1,261 @@
+//! Channel tree of a virtual server, printed by the `list-channels` subcommand and
+//! walked for `--watch-subtree`.
+
+use crate::datastructures::parse_field;
+use crate::error::{QueryError, Result};
+use std::collections::{HashMap, HashSet};
+
+struct Node<'a> {
+    cid: i32,
+    pid: i32,
+    /// cid of the sibling above, 0 for the first one
+    order: i32,
+    record: &'a HashMap<String, String>,
+}
+
+/// Render `channellist -topic -flags` records as an indented tree, with the number of
+/// voice clients of `clientlist` in each channel.
+///
+/// Siblings are listed in the order the server shows them: `channel_order` is the cid
+/// of the channel above, so the chain is followed from 0. Channels outside the chain
+/// come last, by cid.
+pub fn render_tree(
+    channels: &[HashMap<String, String>],
+    clients: &[HashMap<String, String>],
+) -> Result<String> {
+    let mut occupancy: HashMap<i32, usize> = HashMap::new();
+    for client in clients {
+        // ServerQuery clients like ourselves don't count
+        if client.get("client_type").map(String::as_str) == Some("1") {
+            continue;
+        }
+        *occupancy.entry(parse_field(client, "cid")?).or_default() += 1;
+    }
+    let mut children: HashMap<i32, Vec<Node>> = HashMap::new();
+    for record in channels {
+        let node = Node {
+            cid: parse_field(record, "cid")?,
+            pid: parse_field(record, "pid")?,
+            order: parse_field(record, "channel_order")?,
+            record,
+        };
+        children.entry(node.pid).or_default().push(node);
+    }
+    let mut out = String::new();
+    render_children(&mut out, &mut children, &occupancy, 0, 0);
+    Ok(out)
+}
+
+/// Channels below `root` in `channellist` records, at any depth and without `root` itself.
+pub fn descendants(channels: &[HashMap<String, String>], root: i32) -> Result<HashSet<i32>> {
+    let mut children: HashMap<i32, Vec<i32>> = HashMap::new();
+    for record in channels {
+        children
+            .entry(parse_field(record, "pid")?)
+            .or_default()
+            .push(parse_field(record, "cid")?);
+    }
+    let mut found = HashSet::new();
+    let mut pending = vec![root];
+    while let Some(pid) = pending.pop() {
+        for cid in children.remove(&pid).unwrap_or_default() {
+            if found.insert(cid) {
+                pending.push(cid);
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// Find the channel named by `path` in `channellist` records. A single name matches at
+/// any depth, `Lobby/Games` matches a `Games` right below a `Lobby`. A leading `/`
+/// anchors the path at the top level. Fails unless exactly one channel matches.
+pub fn find_channel(channels: &[HashMap<String, String>], path: &str) -> Result<i32> {
+    let (anchored, relative) = match path.strip_prefix('/') {
+        Some(relative) => (true, relative),
+        None => (false, path),
+    };
+    let segments: Vec<&str> = relative.split('/').collect();
+    let mut parents: HashMap<i32, i32> = HashMap::new();
+    let mut names: HashMap<i32, &str> = HashMap::new();
+    for record in channels {
+        let cid = parse_field(record, "cid")?;
+        parents.insert(cid, parse_field(record, "pid")?);
+        names.insert(
+            cid,
+            record
+                .get("channel_name")
+                .map(String::as_str)
+                .unwrap_or_default(),
+        );
+    }
+    // Walk up from every channel with the last name, comparing the ancestors
+    let matches_path = |cid: i32| {
+        let mut current = cid;
+        for (depth, segment) in segments.iter().rev().enumerate() {
+            if depth > 0 {
+                current = parents.get(&current).copied().unwrap_or_default();
+            }
+            if names.get(&current) != Some(segment) {
+                return false;
+            }
+        }
+        !anchored || parents.get(&current) == Some(&0)
+    };
+    let mut found: Vec<i32> = names
+        .keys()
+        .copied()
+        .filter(|cid| matches_path(*cid))
+        .collect();
+    found.sort_unstable();
+    match found.as_slice() {
+        [cid] => Ok(*cid),
+        [] => Err(QueryError::Config(format!("Channel {} not found", path))),
+        _ => Err(QueryError::Config(format!(
+            "Channel {} is ambiguous, it matches the channels {}, give its path like Parent/Child",
+            path,
+            found
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))),
+    }
+}
+
+fn render_children(
+    out: &mut String,
+    children: &mut HashMap<i32, Vec<Node>>,
+    occupancy: &HashMap<i32, usize>,
+    pid: i32,
+    depth: usize,
+) {
+    let mut siblings = children.remove(&pid).unwrap_or_default();
+    siblings.sort_by_key(|node| node.cid);
+    let mut above = 0;
+    while !siblings.is_empty() {
+        let next = siblings
+            .iter()
+            .position(|node| node.order == above)
+            .unwrap_or(0);
+        let node = siblings.remove(next);
+        above = node.cid;
+        render_node(out, &node, occupancy, depth);
+        render_children(out, children, occupancy, node.cid, depth + 1);
+    }
+}
+
+fn render_node(out: &mut String, node: &Node, occupancy: &HashMap<i32, usize>, depth: usize) {
+    let field = |key: &str| node.record.get(key).map(String::as_str).unwrap_or_default();
+    out.push_str(&"  ".repeat(depth));
+    out.push_str(&format!(
+        "{} (cid {}, {} clients)",
+        field("channel_name"),
+        node.cid,
+        occupancy.get(&node.cid).copied().unwrap_or_default()
+    ));
+    for (flag, label) in [
+        ("channel_flag_default", "default"),
+        ("channel_flag_password", "password"),
+    ] {
+        if field(flag) == "1" {
+            out.push_str(&format!(" [{}]", label));
+        }
+    }
+    let permanent =
+        field("channel_flag_permanent") == "1" || field("channel_flag_semi_permanent") == "1";
+    if node.record.contains_key("channel_flag_permanent") && !permanent {
+        out.push_str(" [temporary]");
+    }
+    if !field("channel_topic").is_empty() {
+        out.push_str(&format!(" - {}", field("channel_topic")));
+    }
+    out.push('\n');
+}
+
+#[cfg(test)]
+mod test {
+    use super::{descendants, find_channel, render_tree};
+    use crate::protocol::parse_list;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_descendants() {
+        let channels = parse_list(concat!(
+            "cid=1 pid=0 channel_order=0 channel_name=Games|",
+            "cid=2 pid=1 channel_order=0 channel_name=Shooter|",
+            "cid=3 pid=2 channel_order=0 channel_name=Squad\\sA|",
+            "cid=4 pid=1 channel_order=2 channel_name=Strategy|",
+            "cid=5 pid=0 channel_order=1 channel_name=Music|",
+            "cid=6 pid=5 channel_order=0 channel_name=Lounge"
+        ));
+        assert_eq!(descendants(&channels, 1).unwrap(), HashSet::from([2, 3, 4]));
+        assert_eq!(descendants(&channels, 5).unwrap(), HashSet::from([6]));
+        assert!(descendants(&channels, 6).unwrap().is_empty());
+        assert_eq!(descendants(&channels, 0).unwrap().len(), 6);
+    }
+
+    #[test]
+    fn test_find_channel() {
+        let channels = parse_list(concat!(
+            "cid=1 pid=0 channel_order=0 channel_name=Lobby|",
+            "cid=2 pid=1 channel_order=0 channel_name=Games|",
+            "cid=3 pid=2 channel_order=0 channel_name=Squad|",
+            "cid=4 pid=0 channel_order=1 channel_name=Music|",
+            "cid=5 pid=4 channel_order=0 channel_name=Games|",
+            "cid=6 pid=0 channel_order=4 channel_name=AFK"
+        ));
+        assert_eq!(find_channel(&channels, "AFK").unwrap(), 6);
+        assert_eq!(find_channel(&channels, "Squad").unwrap(), 3);
+        assert_eq!(find_channel(&channels, "Lobby/Games").unwrap(), 2);
+        assert_eq!(find_channel(&channels, "Music/Games").unwrap(), 5);
+        assert_eq!(find_channel(&channels, "Games/Squad").unwrap(), 3);
+        assert_eq!(find_channel(&channels, "/Lobby/Games/Squad").unwrap(), 3);
+        assert!(find_channel(&channels, "/Games/Squad").is_err());
+        assert!(find_channel(&channels, "Lobby/Squad").is_err());
+
+        let err = find_channel(&channels, "Games").unwrap_err().to_string();
+        assert!(err.contains("ambiguous"), "{}", err);
+        assert!(err.contains("2, 5"), "{}", err);
+        let err = find_channel(&channels, "Lounge").unwrap_err().to_string();
+        assert!(err.contains("Channel Lounge not found"), "{}", err);
+    }
+
+    #[test]
+    fn test_render_tree() {
+        // Deliberately not listed in display order
+        let channels = parse_list(concat!(
+            "cid=3 pid=1 channel_order=2 channel_name=Talk\\s2 channel_topic= channel_flag_default=0 channel_flag_password=1 channel_flag_permanent=1 channel_flag_semi_permanent=0|",
+            "cid=1 pid=0 channel_order=0 channel_name=Lobby channel_topic=Join\\sme channel_flag_default=1 channel_flag_password=0 channel_flag_permanent=1 channel_flag_semi_permanent=0|",
+            "cid=4 pid=0 channel_order=1 channel_name=AFK channel_topic= channel_flag_default=0 channel_flag_password=0 channel_flag_permanent=0 channel_flag_semi_permanent=1|",
+            "cid=2 pid=1 channel_order=0 channel_name=Talk\\s1 channel_topic= channel_flag_default=0 channel_flag_password=0 channel_flag_permanent=1 channel_flag_semi_permanent=0|",
+            "cid=5 pid=2 channel_order=0 channel_name=Bob's\\sChannel channel_topic= channel_flag_default=0 channel_flag_password=0 channel_flag_permanent=0 channel_flag_semi_permanent=0"
+        ));
+        let clients = parse_list(concat!(
+            "clid=1 cid=1 client_database_id=1 client_nickname=serveradmin client_type=1|",
+            "clid=2 cid=1 client_database_id=2 client_nickname=Alice client_type=0|",
+            "clid=3 cid=5 client_database_id=3 client_nickname=Bob client_type=0|",
+            "clid=4 cid=5 client_database_id=4 client_nickname=Carol client_type=0"
+        ));
+        assert_eq!(
+            render_tree(&channels, &clients).unwrap(),
+            concat!(
+                "Lobby (cid 1, 1 clients) [default] - Join me\n",
+                "  Talk 1 (cid 2, 0 clients)\n",
+                "    Bob's Channel (cid 5, 2 clients) [temporary]\n",
+                "  Talk 2 (cid 3, 0 clients) [password]\n",
+                "AFK (cid 4, 0 clients)\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_render_tree_broken_order() {
+        let channels = parse_list("cid=7 pid=0 channel_order=99 channel_name=B|cid=6 pid=0 channel_order=98 channel_name=A");
+        assert_eq!(
+            render_tree(&channels, &[]).unwrap(),
+            "A (cid 6, 0 clients)\nB (cid 7, 0 clients)\n"
+        );
+        assert!(render_tree(&parse_list("pid=0 channel_name=A"), &[]).is_err());
+    }
+}
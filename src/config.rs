@@ -0,0 +1,1309 @@
+use crate::autochannel::{
+    ChannelLifetime, NameCollision, WatchRule, DEFAULT_NAME_ATTEMPTS, DEFAULT_NAME_TEMPLATE,
+    DEFAULT_SHUTDOWN_TIMEOUT,
+};
+use crate::channel_tree::find_channel;
+use crate::connection::{
+    ChannelListFlags, TelnetConn, VirtualServer, DEFAULT_MAX_RESPONSE_BYTES, MAX_CODEC,
+    MAX_CODEC_QUALITY,
+};
+use crate::error::{QueryError, Result};
+use crate::protocol::build_command;
+use crate::transport::{IpPreference, Socks5Proxy, TcpOptions};
+use log::info;
+use serde_derive::Deserialize;
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+/// Transport of the ServerQuery connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    /// Plain telnet, port 10011
+    Raw,
+    /// SSH, port 10022
+    Ssh,
+    /// The raw query wrapped in TLS by a terminator in front of it
+    Tls,
+}
+
+/// Settings loaded from the `--config` TOML file, every field may be overridden on the command line.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    server: Option<String>,
+    port: Option<u16>,
+    /// Local address the connection is made from
+    bind: Option<IpAddr>,
+    /// Try these addresses of the server first
+    prefer_ipv4: Option<bool>,
+    prefer_ipv6: Option<bool>,
+    /// SOCKS5 proxy the connection is made through, `socks5://[user:password@]host:port`
+    proxy: Option<String>,
+    user: Option<String>,
+    password: Option<String>,
+    /// Virtual server to select, 1 if neither this nor `server_port` is set
+    sid: Option<i32>,
+    /// Voice port selecting the virtual server, alternative to `sid`
+    server_port: Option<u16>,
+    /// Start the virtual server if it is stopped
+    autostart_server: Option<bool>,
+    parent_channel: Option<i32>,
+    /// `parent_channel` by name or path like `Lobby/Games`, see [`Config::resolve_names`]
+    parent_channel_name: Option<String>,
+    /// Channel the spawned channels are created in, `parent_channel` if unset
+    spawn_parent: Option<i32>,
+    /// Additional parent channels with their own settings, `[[watch]]` tables
+    watch: Option<Vec<WatchRule>>,
+    /// Every channel below this one is watched too, with the global settings
+    watch_subtree: Option<i32>,
+    max_channels_per_user: Option<usize>,
+    max_channels_total: Option<usize>,
+    /// Channel group given to the client a channel was created for
+    creator_channel_group: Option<i32>,
+    /// `creator_channel_group` by name, see [`Config::resolve_names`]
+    creator_channel_group_name: Option<String>,
+    /// `channel_codec` of spawned channels, 4 is Opus Voice and 5 Opus Music
+    channel_codec: Option<u8>,
+    /// `channel_codec_quality` of spawned channels, 0-10
+    channel_codec_quality: Option<u8>,
+    /// `channel_maxclients` of spawned channels, unlimited if unset
+    channel_max_clients: Option<u32>,
+    /// Password of spawned channels, rendered like the name, `{random}` generates one
+    channel_password: Option<String>,
+    /// Topic and description of spawned channels, rendered like the name
+    channel_topic_template: Option<String>,
+    channel_description_template: Option<String>,
+    /// `temporary`, `semi-permanent` or `permanent`, the server default if unset
+    channel_lifetime: Option<ChannelLifetime>,
+    /// Name of spawned channels, see [`crate::autochannel::render_name`]
+    channel_name_template: Option<String>,
+    /// Names tried when a spawned channel name is taken
+    channel_name_attempts: Option<u32>,
+    /// `suffix`, `skip` or `reuse` when a spawned channel name is taken
+    on_name_collision: Option<NameCollision>,
+    /// Seconds a spawned channel may stay empty before it is deleted
+    empty_grace_secs: Option<u64>,
+    /// Seconds a UID has to wait before another channel is spawned for it
+    spawn_cooldown_secs: Option<u64>,
+    /// Move clients back into the channel they own instead of spawning another one
+    rejoin_existing: Option<bool>,
+    /// Clients idle for `afk_idle_secs` are moved to this channel
+    afk_channel: Option<i32>,
+    afk_idle_secs: Option<u64>,
+    /// Identities allowed to control the daemon with private `!commands`
+    admin_uids: Option<Vec<String>>,
+    keepalive_secs: Option<u64>,
+    /// Time the cleanup on exit may take before it is abandoned
+    shutdown_timeout_secs: Option<u64>,
+    max_backoff_secs: Option<u64>,
+    /// Failed reconnect attempts before exiting, 0 retries forever
+    max_reconnect_attempts: Option<u32>,
+    /// Time to wait for the banner and command replies
+    timeout_secs: Option<u64>,
+    /// Commands sent per `interval_secs` at most, 0 for no limit
+    commands_per_interval: Option<u32>,
+    interval_secs: Option<u64>,
+    /// Pause before retrying a command rejected as flooding
+    flood_cooldown_secs: Option<u64>,
+    /// Size a single response may reach
+    max_response_bytes: Option<usize>,
+    ssh: Option<bool>,
+    /// Raw query tunnelled through TLS, e.g. by stunnel
+    tls: Option<bool>,
+    /// PEM file with the CA the TLS certificate is verified against besides the system roots
+    tls_ca_file: Option<PathBuf>,
+    /// Accept any TLS certificate, only for self-signed setups on a trusted network
+    tls_insecure: Option<bool>,
+    /// Nickname of the query client in the client list
+    nickname: Option<String>,
+    /// Log state changing commands instead of sending them
+    dry_run: Option<bool>,
+    /// JSON file keeping the managed channels across restarts
+    state_file: Option<PathBuf>,
+    /// Address of the Prometheus metrics endpoint, e.g. `127.0.0.1:9100`
+    metrics_addr: Option<String>,
+    /// Seconds without events or pings before `/readyz` fails
+    ready_within_secs: Option<u64>,
+    /// Virtual servers served at once, each with its own connection. The
+    /// `[[servers]]` tables override the settings above, see [`Self::targets`]
+    servers: Option<Vec<Config>>,
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path).map_err(|e| {
+            QueryError::Config(format!("Got error while read {}: {:?}", path.display(), e))
+        })?;
+        Self::from_toml(&content).map_err(|e| {
+            QueryError::Config(format!("Got error while parse {}: {:?}", path.display(), e))
+        })
+    }
+
+    pub fn from_toml(content: &str) -> Result<Self> {
+        toml::from_str(content)
+            .map_err(|e| QueryError::Config(format!("Got error while parse config: {:?}", e)))
+    }
+
+    /// Add the `*.toml` files of `dir` in lexical order, see [`Self::add_drop_in`].
+    pub fn with_drop_ins<P: AsRef<Path>>(self, dir: P) -> Result<Self> {
+        let dir = dir.as_ref();
+        let read_error = |e: std::io::Error| {
+            QueryError::Config(format!("Got error while read {}: {:?}", dir.display(), e))
+        };
+        let mut paths = Vec::new();
+        for entry in std::fs::read_dir(dir).map_err(read_error)? {
+            let path = entry.map_err(read_error)?.path();
+            if path
+                .extension()
+                .map_or(false, |extension| extension == "toml")
+                && path.is_file()
+            {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        paths.into_iter().try_fold(self, |config, path| {
+            let drop_in = Self::load(&path)?;
+            config.add_drop_in(drop_in).map_err(|e| match e {
+                QueryError::Config(msg) => {
+                    QueryError::Config(format!("{}: {}", path.display(), msg))
+                }
+                e => e,
+            })
+        })
+    }
+
+    /// Add the `[[watch]]` rules of `drop_in` to ours, its other settings take
+    /// precedence like in [`Self::merge`]. The connection settings may only be
+    /// repeated with the same value, and every parent channel watched once.
+    pub fn add_drop_in(mut self, mut drop_in: Config) -> Result<Self> {
+        fn differs<T: PartialEq>(ours: &Option<T>, theirs: &Option<T>) -> bool {
+            matches!((ours, theirs), (Some(ours), Some(theirs)) if ours != theirs)
+        }
+        let conflicts: Vec<&str> = [
+            ("server", differs(&self.server, &drop_in.server)),
+            ("port", differs(&self.port, &drop_in.port)),
+            ("bind", differs(&self.bind, &drop_in.bind)),
+            ("proxy", differs(&self.proxy, &drop_in.proxy)),
+            ("user", differs(&self.user, &drop_in.user)),
+            ("password", differs(&self.password, &drop_in.password)),
+            ("sid", differs(&self.sid, &drop_in.sid)),
+            (
+                "server_port",
+                differs(&self.server_port, &drop_in.server_port),
+            ),
+            ("ssh", differs(&self.ssh, &drop_in.ssh)),
+            ("tls", differs(&self.tls, &drop_in.tls)),
+            ("servers", differs(&self.servers, &drop_in.servers)),
+        ]
+        .into_iter()
+        .filter(|(_, differs)| *differs)
+        .map(|(name, _)| name)
+        .collect();
+        if !conflicts.is_empty() {
+            return Err(QueryError::Config(format!(
+                "{} differ from the config before, drop-ins can't change the connection",
+                conflicts.join(", ")
+            )));
+        }
+        let mut watch = self.watch.take().unwrap_or_default();
+        let mut parents: HashSet<i32> = watch
+            .iter()
+            .map(WatchRule::parent_channel)
+            .chain(self.parent_channel)
+            .collect();
+        for rule in drop_in.watch.take().unwrap_or_default() {
+            if !parents.insert(rule.parent_channel()) {
+                return Err(QueryError::Config(format!(
+                    "Channel {} is watched twice",
+                    rule.parent_channel()
+                )));
+            }
+            watch.push(rule);
+        }
+        let mut merged = self.merge(drop_in);
+        merged.watch = (!watch.is_empty()).then_some(watch);
+        Ok(merged)
+    }
+
+    /// Fields set in `other` take precedence over ours.
+    pub fn merge(self, other: Config) -> Config {
+        // sid and server_port select the same thing, override them together
+        let (sid, server_port) = if other.sid.is_some() || other.server_port.is_some() {
+            (other.sid, other.server_port)
+        } else {
+            (self.sid, self.server_port)
+        };
+        // Same for the parent channel by id and by name
+        let (parent_channel, parent_channel_name) =
+            if other.parent_channel.is_some() || other.parent_channel_name.is_some() {
+                (other.parent_channel, other.parent_channel_name)
+            } else {
+                (self.parent_channel, self.parent_channel_name)
+            };
+        Config {
+            server: other.server.or(self.server),
+            port: other.port.or(self.port),
+            bind: other.bind.or(self.bind),
+            prefer_ipv4: other.prefer_ipv4.or(self.prefer_ipv4),
+            prefer_ipv6: other.prefer_ipv6.or(self.prefer_ipv6),
+            proxy: other.proxy.or(self.proxy),
+            user: other.user.or(self.user),
+            password: other.password.or(self.password),
+            sid,
+            server_port,
+            autostart_server: other.autostart_server.or(self.autostart_server),
+            parent_channel,
+            parent_channel_name,
+            spawn_parent: other.spawn_parent.or(self.spawn_parent),
+            watch: other.watch.or(self.watch),
+            watch_subtree: other.watch_subtree.or(self.watch_subtree),
+            max_channels_per_user: other.max_channels_per_user.or(self.max_channels_per_user),
+            max_channels_total: other.max_channels_total.or(self.max_channels_total),
+            creator_channel_group: other.creator_channel_group.or(self.creator_channel_group),
+            creator_channel_group_name: other
+                .creator_channel_group_name
+                .or(self.creator_channel_group_name),
+            channel_codec: other.channel_codec.or(self.channel_codec),
+            channel_codec_quality: other.channel_codec_quality.or(self.channel_codec_quality),
+            channel_max_clients: other.channel_max_clients.or(self.channel_max_clients),
+            channel_password: other.channel_password.or(self.channel_password),
+            channel_topic_template: other.channel_topic_template.or(self.channel_topic_template),
+            channel_description_template: other
+                .channel_description_template
+                .or(self.channel_description_template),
+            channel_lifetime: other.channel_lifetime.or(self.channel_lifetime),
+            channel_name_template: other.channel_name_template.or(self.channel_name_template),
+            channel_name_attempts: other.channel_name_attempts.or(self.channel_name_attempts),
+            on_name_collision: other.on_name_collision.or(self.on_name_collision),
+            empty_grace_secs: other.empty_grace_secs.or(self.empty_grace_secs),
+            spawn_cooldown_secs: other.spawn_cooldown_secs.or(self.spawn_cooldown_secs),
+            rejoin_existing: other.rejoin_existing.or(self.rejoin_existing),
+            afk_channel: other.afk_channel.or(self.afk_channel),
+            afk_idle_secs: other.afk_idle_secs.or(self.afk_idle_secs),
+            admin_uids: other.admin_uids.or(self.admin_uids),
+            keepalive_secs: other.keepalive_secs.or(self.keepalive_secs),
+            shutdown_timeout_secs: other.shutdown_timeout_secs.or(self.shutdown_timeout_secs),
+            max_backoff_secs: other.max_backoff_secs.or(self.max_backoff_secs),
+            max_reconnect_attempts: other.max_reconnect_attempts.or(self.max_reconnect_attempts),
+            timeout_secs: other.timeout_secs.or(self.timeout_secs),
+            commands_per_interval: other.commands_per_interval.or(self.commands_per_interval),
+            interval_secs: other.interval_secs.or(self.interval_secs),
+            flood_cooldown_secs: other.flood_cooldown_secs.or(self.flood_cooldown_secs),
+            max_response_bytes: other.max_response_bytes.or(self.max_response_bytes),
+            ssh: other.ssh.or(self.ssh),
+            tls: other.tls.or(self.tls),
+            tls_ca_file: other.tls_ca_file.or(self.tls_ca_file),
+            tls_insecure: other.tls_insecure.or(self.tls_insecure),
+            nickname: other.nickname.or(self.nickname),
+            dry_run: other.dry_run.or(self.dry_run),
+            state_file: other.state_file.or(self.state_file),
+            metrics_addr: other.metrics_addr.or(self.metrics_addr),
+            ready_within_secs: other.ready_within_secs.or(self.ready_within_secs),
+            servers: other.servers.or(self.servers),
+        }
+    }
+
+    /// Check the fields without a sensible default are present, for every server.
+    pub fn validate(&self) -> Result<()> {
+        let servers = match &self.servers {
+            Some(servers) if !servers.is_empty() => servers,
+            _ => return self.validate_target(),
+        };
+        if servers
+            .iter()
+            .any(|server| server.servers.is_some() || server.metrics_addr.is_some())
+        {
+            return Err(QueryError::Config(
+                "servers can't set servers or metrics_addr, they are global".to_string(),
+            ));
+        }
+        let mut state_files = HashSet::new();
+        for (index, target) in self.targets().iter().enumerate() {
+            target.validate_target().map_err(|e| match e {
+                QueryError::Config(msg) => {
+                    QueryError::Config(format!("servers[{}]: {}", index, msg))
+                }
+                e => e,
+            })?;
+            if let Some(path) = target.state_file() {
+                if !state_files.insert(path.to_path_buf()) {
+                    return Err(QueryError::Config(format!(
+                        "servers[{}]: state_file {} is used by another server",
+                        index,
+                        path.display()
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// One config per virtual server to serve: every `[[servers]]` table on top of the
+    /// other settings, or just those without any table.
+    pub fn targets(&self) -> Vec<Config> {
+        let base = Config {
+            servers: None,
+            ..self.clone()
+        };
+        match &self.servers {
+            Some(servers) if !servers.is_empty() => servers
+                .iter()
+                .map(|server| base.clone().merge(server.clone()))
+                .collect(),
+            _ => vec![base],
+        }
+    }
+
+    fn validate_target(&self) -> Result<()> {
+        // Without a parent the default channel is watched, see [`Self::resolve_names`]
+        self.require(&[])?;
+        if self.parent_channel.is_some() && self.parent_channel_name.is_some() {
+            return Err(QueryError::Config(
+                "parent_channel and parent_channel_name are mutually exclusive, set only one of them"
+                    .to_string(),
+            ));
+        }
+        if self.afk_channel.is_some() != self.afk_idle_secs.is_some() {
+            return Err(QueryError::Config(
+                "afk_channel and afk_idle_secs only work together, set both of them".to_string(),
+            ));
+        }
+        if self.creator_channel_group.is_some() && self.creator_channel_group_name.is_some() {
+            return Err(QueryError::Config(
+                "creator_channel_group and creator_channel_group_name are mutually exclusive, set only one of them"
+                    .to_string(),
+            ));
+        }
+        let codecs = std::iter::once((self.channel_codec, self.channel_codec_quality)).chain(
+            self.watch_rules()
+                .into_iter()
+                .map(|rule| (rule.codec(), rule.codec_quality())),
+        );
+        for (codec, quality) in codecs {
+            if let Some(codec) = codec.filter(|codec| *codec > MAX_CODEC) {
+                return Err(QueryError::Config(format!(
+                    "channel_codec must be between 0 and {}, got {}",
+                    MAX_CODEC, codec
+                )));
+            }
+            if let Some(quality) = quality.filter(|quality| *quality > MAX_CODEC_QUALITY) {
+                return Err(QueryError::Config(format!(
+                    "channel_codec_quality must be between 0 and {}, got {}",
+                    MAX_CODEC_QUALITY, quality
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Look up the channels and groups configured by name on the selected virtual
+    /// server, the returned config has their ids set instead. Without any parent
+    /// the channel flagged as default is watched.
+    pub fn resolve_names(&self, conn: &mut TelnetConn) -> Result<Config> {
+        let mut config = self.clone();
+        if config.watches_default_channel() {
+            let mut flags = ChannelListFlags::default();
+            flags.set_flags(true);
+            let channel = conn
+                .channel_list(flags)?
+                .into_iter()
+                .find(|channel| channel.channel_flag_default() == Some(true))
+                .ok_or_else(|| {
+                    QueryError::Config(
+                        "No parent_channel set and the server has no default channel".to_string(),
+                    )
+                })?;
+            info!(
+                "No parent_channel set, watching the default channel {} ({})",
+                channel.channel_name(),
+                channel.cid()
+            );
+            config.parent_channel = Some(channel.cid() as i32);
+        }
+        if let Some(path) = config.parent_channel_name.take() {
+            let timeout = conn.timeout();
+            let (_, channels) =
+                conn.write_and_parse_list(&build_command("channellist", &[]), timeout)?;
+            config.parent_channel = Some(find_channel(&channels, &path)?);
+        }
+        if let Some(name) = config.creator_channel_group_name.take() {
+            let groups = conn.channel_group_list()?;
+            let cgid = groups.get(&name).copied().ok_or_else(|| {
+                let mut known: Vec<_> = groups.keys().map(String::as_str).collect();
+                known.sort_unstable();
+                QueryError::Config(format!(
+                    "Channel group {} not found, the server has: {}",
+                    name,
+                    known.join(", ")
+                ))
+            })?;
+            config.creator_channel_group = Some(cgid);
+        }
+        Ok(config)
+    }
+
+    /// Check the fields needed to log in and select the virtual server are present,
+    /// enough for the subcommands not running the auto-channel.
+    pub fn validate_login(&self) -> Result<()> {
+        self.require(&[])
+    }
+
+    /// Settings changed in `other` that only apply after a restart, the auto-channel
+    /// settings are taken over live by [`crate::autochannel::AutoChannel::reload`].
+    pub fn restart_required(&self, other: &Config) -> Vec<&'static str> {
+        [
+            ("server", self.server != other.server),
+            ("port", self.port != other.port),
+            ("bind", self.bind != other.bind),
+            ("prefer_ipv4", self.prefer_ipv4 != other.prefer_ipv4),
+            ("prefer_ipv6", self.prefer_ipv6 != other.prefer_ipv6),
+            ("proxy", self.proxy != other.proxy),
+            ("user", self.user != other.user),
+            ("password", self.password != other.password),
+            ("sid", self.sid != other.sid),
+            ("server_port", self.server_port != other.server_port),
+            (
+                "autostart_server",
+                self.autostart_server != other.autostart_server,
+            ),
+            (
+                "keepalive_secs",
+                self.keepalive_secs != other.keepalive_secs,
+            ),
+            (
+                "max_backoff_secs",
+                self.max_backoff_secs != other.max_backoff_secs,
+            ),
+            (
+                "max_reconnect_attempts",
+                self.max_reconnect_attempts != other.max_reconnect_attempts,
+            ),
+            ("timeout_secs", self.timeout_secs != other.timeout_secs),
+            (
+                "commands_per_interval",
+                self.commands_per_interval != other.commands_per_interval,
+            ),
+            ("interval_secs", self.interval_secs != other.interval_secs),
+            (
+                "flood_cooldown_secs",
+                self.flood_cooldown_secs != other.flood_cooldown_secs,
+            ),
+            (
+                "max_response_bytes",
+                self.max_response_bytes != other.max_response_bytes,
+            ),
+            ("ssh", self.ssh != other.ssh),
+            ("tls", self.tls != other.tls),
+            ("tls_ca_file", self.tls_ca_file != other.tls_ca_file),
+            ("tls_insecure", self.tls_insecure != other.tls_insecure),
+            ("nickname", self.nickname != other.nickname),
+            ("dry_run", self.dry_run != other.dry_run),
+            ("state_file", self.state_file != other.state_file),
+            ("metrics_addr", self.metrics_addr != other.metrics_addr),
+            (
+                "ready_within_secs",
+                self.ready_within_secs != other.ready_within_secs,
+            ),
+        ]
+        .into_iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(name, _)| name)
+        .collect()
+    }
+
+    fn require(&self, settings: &[(&str, bool)]) -> Result<()> {
+        let missing: Vec<_> = [
+            ("user", self.user.is_none()),
+            ("password", self.password.is_none()),
+        ]
+        .iter()
+        .chain(settings)
+        .filter(|(_, missing)| *missing)
+        .map(|(name, _)| *name)
+        .collect();
+        if !missing.is_empty() {
+            return Err(QueryError::Config(format!(
+                "Missing required setting(s): {}, pass them as argument or in the config file",
+                missing.join(", ")
+            )));
+        }
+        if self.sid.is_some() && self.server_port.is_some() {
+            return Err(QueryError::Config(
+                "sid and server_port are mutually exclusive, set only one of them".to_string(),
+            ));
+        }
+        if self.prefer_ipv4.unwrap_or_default() && self.prefer_ipv6.unwrap_or_default() {
+            return Err(QueryError::Config(
+                "prefer_ipv4 and prefer_ipv6 are mutually exclusive, set only one of them"
+                    .to_string(),
+            ));
+        }
+        if let Some(proxy) = &self.proxy {
+            proxy.parse::<Socks5Proxy>().map_err(|e| match e {
+                QueryError::InvalidArgument(msg) => QueryError::Config(msg),
+                e => e,
+            })?;
+        }
+        if self.ssh() && self.tls() {
+            return Err(QueryError::Config(
+                "ssh and tls are mutually exclusive, set only one of them".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn set_server(&mut self, server: Option<String>) {
+        self.server = server;
+    }
+    pub fn set_port(&mut self, port: Option<u16>) {
+        self.port = port;
+    }
+    pub fn set_bind(&mut self, bind: Option<IpAddr>) {
+        self.bind = bind;
+    }
+    pub fn set_prefer_ipv4(&mut self, prefer: Option<bool>) {
+        self.prefer_ipv4 = prefer;
+    }
+    pub fn set_prefer_ipv6(&mut self, prefer: Option<bool>) {
+        self.prefer_ipv6 = prefer;
+    }
+    pub fn set_proxy(&mut self, proxy: Option<String>) {
+        self.proxy = proxy;
+    }
+    pub fn set_user(&mut self, user: Option<String>) {
+        self.user = user;
+    }
+    pub fn set_password(&mut self, password: Option<String>) {
+        self.password = password;
+    }
+    pub fn set_sid(&mut self, sid: Option<i32>) {
+        self.sid = sid;
+    }
+    pub fn set_server_port(&mut self, server_port: Option<u16>) {
+        self.server_port = server_port;
+    }
+    pub fn set_autostart_server(&mut self, autostart_server: Option<bool>) {
+        self.autostart_server = autostart_server;
+    }
+    pub fn set_parent_channel(&mut self, parent_channel: Option<i32>) {
+        self.parent_channel = parent_channel;
+    }
+    pub fn set_parent_channel_name(&mut self, path: Option<String>) {
+        self.parent_channel_name = path;
+    }
+    pub fn set_spawn_parent(&mut self, spawn_parent: Option<i32>) {
+        self.spawn_parent = spawn_parent;
+    }
+    pub fn set_watch_subtree(&mut self, root: Option<i32>) {
+        self.watch_subtree = root;
+    }
+    pub fn set_max_channels_per_user(&mut self, max_channels_per_user: Option<usize>) {
+        self.max_channels_per_user = max_channels_per_user;
+    }
+    pub fn set_max_channels_total(&mut self, max_channels_total: Option<usize>) {
+        self.max_channels_total = max_channels_total;
+    }
+    pub fn set_creator_channel_group(&mut self, creator_channel_group: Option<i32>) {
+        self.creator_channel_group = creator_channel_group;
+    }
+    pub fn set_creator_channel_group_name(&mut self, name: Option<String>) {
+        self.creator_channel_group_name = name;
+    }
+    pub fn set_channel_codec(&mut self, channel_codec: Option<u8>) {
+        self.channel_codec = channel_codec;
+    }
+    pub fn set_channel_codec_quality(&mut self, channel_codec_quality: Option<u8>) {
+        self.channel_codec_quality = channel_codec_quality;
+    }
+    pub fn set_channel_max_clients(&mut self, channel_max_clients: Option<u32>) {
+        self.channel_max_clients = channel_max_clients;
+    }
+    pub fn set_channel_password(&mut self, channel_password: Option<String>) {
+        self.channel_password = channel_password;
+    }
+    pub fn set_channel_topic_template(&mut self, template: Option<String>) {
+        self.channel_topic_template = template;
+    }
+    pub fn set_channel_description_template(&mut self, template: Option<String>) {
+        self.channel_description_template = template;
+    }
+    pub fn set_channel_lifetime(&mut self, lifetime: Option<ChannelLifetime>) {
+        self.channel_lifetime = lifetime;
+    }
+    pub fn set_channel_name_template(&mut self, channel_name_template: Option<String>) {
+        self.channel_name_template = channel_name_template;
+    }
+    pub fn set_channel_name_attempts(&mut self, attempts: Option<u32>) {
+        self.channel_name_attempts = attempts;
+    }
+    pub fn set_on_name_collision(&mut self, strategy: Option<NameCollision>) {
+        self.on_name_collision = strategy;
+    }
+    pub fn set_empty_grace_secs(&mut self, secs: Option<u64>) {
+        self.empty_grace_secs = secs;
+    }
+    pub fn set_spawn_cooldown_secs(&mut self, secs: Option<u64>) {
+        self.spawn_cooldown_secs = secs;
+    }
+    pub fn set_rejoin_existing(&mut self, rejoin_existing: Option<bool>) {
+        self.rejoin_existing = rejoin_existing;
+    }
+    pub fn set_afk_channel(&mut self, afk_channel: Option<i32>) {
+        self.afk_channel = afk_channel;
+    }
+    pub fn set_afk_idle_secs(&mut self, afk_idle_secs: Option<u64>) {
+        self.afk_idle_secs = afk_idle_secs;
+    }
+    pub fn set_admin_uids(&mut self, admin_uids: Option<Vec<String>>) {
+        self.admin_uids = admin_uids;
+    }
+    pub fn set_keepalive_secs(&mut self, keepalive_secs: Option<u64>) {
+        self.keepalive_secs = keepalive_secs;
+    }
+    pub fn set_shutdown_timeout_secs(&mut self, shutdown_timeout_secs: Option<u64>) {
+        self.shutdown_timeout_secs = shutdown_timeout_secs;
+    }
+    pub fn set_max_backoff_secs(&mut self, max_backoff_secs: Option<u64>) {
+        self.max_backoff_secs = max_backoff_secs;
+    }
+    pub fn set_max_reconnect_attempts(&mut self, max_reconnect_attempts: Option<u32>) {
+        self.max_reconnect_attempts = max_reconnect_attempts;
+    }
+    pub fn set_timeout_secs(&mut self, timeout_secs: Option<u64>) {
+        self.timeout_secs = timeout_secs;
+    }
+    pub fn set_commands_per_interval(&mut self, commands: Option<u32>) {
+        self.commands_per_interval = commands;
+    }
+    pub fn set_interval_secs(&mut self, secs: Option<u64>) {
+        self.interval_secs = secs;
+    }
+    pub fn set_flood_cooldown_secs(&mut self, secs: Option<u64>) {
+        self.flood_cooldown_secs = secs;
+    }
+    pub fn set_max_response_bytes(&mut self, max: Option<usize>) {
+        self.max_response_bytes = max;
+    }
+    pub fn set_ssh(&mut self, ssh: Option<bool>) {
+        self.ssh = ssh;
+    }
+
+    pub fn set_tls(&mut self, tls: Option<bool>) {
+        self.tls = tls;
+    }
+    pub fn set_tls_ca_file(&mut self, ca_file: Option<PathBuf>) {
+        self.tls_ca_file = ca_file;
+    }
+    pub fn set_tls_insecure(&mut self, insecure: Option<bool>) {
+        self.tls_insecure = insecure;
+    }
+
+    pub fn set_nickname(&mut self, nickname: Option<String>) {
+        self.nickname = nickname;
+    }
+    pub fn set_dry_run(&mut self, dry_run: Option<bool>) {
+        self.dry_run = dry_run;
+    }
+    pub fn set_state_file(&mut self, state_file: Option<PathBuf>) {
+        self.state_file = state_file;
+    }
+    pub fn set_metrics_addr(&mut self, metrics_addr: Option<String>) {
+        self.metrics_addr = metrics_addr;
+    }
+    pub fn set_ready_within_secs(&mut self, ready_within_secs: Option<u64>) {
+        self.ready_within_secs = ready_within_secs;
+    }
+    pub fn set_servers(&mut self, servers: Option<Vec<Config>>) {
+        self.servers = servers;
+    }
+
+    pub fn server(&self) -> &str {
+        self.server.as_deref().unwrap_or("localhost")
+    }
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or(if self.ssh() { 10022 } else { 10011 })
+    }
+    pub fn bind(&self) -> Option<IpAddr> {
+        self.bind
+    }
+    /// Local and remote address selection of the connection. An invalid proxy is
+    /// rejected by [`Self::validate`].
+    pub fn tcp_options(&self) -> TcpOptions {
+        let mut options = TcpOptions::default();
+        options.set_bind(self.bind);
+        options.set_prefer(if self.prefer_ipv6.unwrap_or_default() {
+            IpPreference::Ipv6
+        } else if self.prefer_ipv4.unwrap_or_default() {
+            IpPreference::Ipv4
+        } else {
+            IpPreference::Any
+        });
+        options.set_proxy(self.proxy.as_deref().and_then(|proxy| proxy.parse().ok()));
+        options
+    }
+    pub fn user(&self) -> &str {
+        self.user.as_deref().unwrap_or_default()
+    }
+    pub fn password(&self) -> &str {
+        self.password.as_deref().unwrap_or_default()
+    }
+    pub fn sid(&self) -> i32 {
+        self.sid.unwrap_or(1)
+    }
+    /// Virtual server to select, the one with sid 1 unless configured otherwise.
+    pub fn virtual_server(&self) -> VirtualServer {
+        match self.server_port {
+            Some(port) => VirtualServer::Port(port),
+            None => VirtualServer::Id(self.sid()),
+        }
+    }
+    pub fn autostart_server(&self) -> bool {
+        self.autostart_server.unwrap_or_default()
+    }
+    pub fn parent_channel(&self) -> i32 {
+        self.parent_channel.unwrap_or_default()
+    }
+    /// Whether [`Self::resolve_names`] has channels to look up, their ids may change
+    /// when the channels are recreated.
+    pub fn has_channel_names(&self) -> bool {
+        self.parent_channel_name.is_some() || self.watches_default_channel()
+    }
+    /// Whether no parent is configured and the default channel is watched instead.
+    fn watches_default_channel(&self) -> bool {
+        self.watch_rules().is_empty()
+            && self.watch_subtree.is_none()
+            && self.parent_channel_name.is_none()
+    }
+    pub fn spawn_parent(&self) -> Option<i32> {
+        self.spawn_parent
+    }
+    /// Watched parents, `parent_channel` with the global settings first.
+    pub fn watch_rules(&self) -> Vec<WatchRule> {
+        self.parent_channel
+            .map(WatchRule::new)
+            .into_iter()
+            .chain(self.watch.iter().flatten().cloned())
+            .collect()
+    }
+    pub fn watch_subtree(&self) -> Option<i32> {
+        self.watch_subtree
+    }
+    pub fn max_channels_per_user(&self) -> Option<usize> {
+        self.max_channels_per_user
+    }
+    pub fn max_channels_total(&self) -> Option<usize> {
+        self.max_channels_total
+    }
+    pub fn creator_channel_group(&self) -> Option<i32> {
+        self.creator_channel_group
+    }
+    pub fn channel_codec(&self) -> Option<u8> {
+        self.channel_codec
+    }
+    pub fn channel_codec_quality(&self) -> Option<u8> {
+        self.channel_codec_quality
+    }
+    pub fn channel_max_clients(&self) -> Option<u32> {
+        self.channel_max_clients
+    }
+    pub fn channel_password(&self) -> Option<&str> {
+        self.channel_password.as_deref()
+    }
+    pub fn channel_topic_template(&self) -> Option<&str> {
+        self.channel_topic_template.as_deref()
+    }
+    pub fn channel_description_template(&self) -> Option<&str> {
+        self.channel_description_template.as_deref()
+    }
+    pub fn channel_lifetime(&self) -> Option<ChannelLifetime> {
+        self.channel_lifetime
+    }
+    pub fn channel_name_template(&self) -> &str {
+        self.channel_name_template
+            .as_deref()
+            .unwrap_or(DEFAULT_NAME_TEMPLATE)
+    }
+    pub fn channel_name_attempts(&self) -> u32 {
+        self.channel_name_attempts.unwrap_or(DEFAULT_NAME_ATTEMPTS)
+    }
+    pub fn on_name_collision(&self) -> NameCollision {
+        self.on_name_collision.unwrap_or_default()
+    }
+    pub fn empty_grace_secs(&self) -> u64 {
+        self.empty_grace_secs.unwrap_or_default()
+    }
+    pub fn spawn_cooldown_secs(&self) -> u64 {
+        self.spawn_cooldown_secs.unwrap_or_default()
+    }
+    pub fn rejoin_existing(&self) -> bool {
+        self.rejoin_existing.unwrap_or_default()
+    }
+    pub fn afk_channel(&self) -> Option<i32> {
+        self.afk_channel
+    }
+    pub fn afk_idle_secs(&self) -> u64 {
+        self.afk_idle_secs.unwrap_or_default()
+    }
+    pub fn admin_uids(&self) -> &[String] {
+        self.admin_uids.as_deref().unwrap_or_default()
+    }
+    pub fn keepalive_secs(&self) -> u64 {
+        self.keepalive_secs.unwrap_or(60)
+    }
+    pub fn shutdown_timeout_secs(&self) -> u64 {
+        self.shutdown_timeout_secs
+            .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT.as_secs())
+    }
+    pub fn max_backoff_secs(&self) -> u64 {
+        self.max_backoff_secs.unwrap_or(60)
+    }
+    pub fn max_reconnect_attempts(&self) -> u32 {
+        self.max_reconnect_attempts.unwrap_or_default()
+    }
+    pub fn timeout_secs(&self) -> u64 {
+        self.timeout_secs.unwrap_or(2)
+    }
+    /// Below the default anti-flood limit of the server, 10 commands per 3 seconds.
+    pub fn commands_per_interval(&self) -> u32 {
+        self.commands_per_interval.unwrap_or(8)
+    }
+    /// Whether the rate limit was configured, otherwise the one of the server is used.
+    pub fn rate_limit_configured(&self) -> bool {
+        self.commands_per_interval.is_some() || self.interval_secs.is_some()
+    }
+    pub fn interval_secs(&self) -> u64 {
+        self.interval_secs.unwrap_or(3)
+    }
+    pub fn flood_cooldown_secs(&self) -> u64 {
+        self.flood_cooldown_secs.unwrap_or(3)
+    }
+    pub fn max_response_bytes(&self) -> usize {
+        self.max_response_bytes
+            .unwrap_or(DEFAULT_MAX_RESPONSE_BYTES)
+    }
+    pub fn ssh(&self) -> bool {
+        self.ssh.unwrap_or_default()
+    }
+    pub fn tls(&self) -> bool {
+        self.tls.unwrap_or_default()
+    }
+    pub fn tls_ca_file(&self) -> Option<&Path> {
+        self.tls_ca_file.as_deref()
+    }
+    pub fn tls_insecure(&self) -> bool {
+        self.tls_insecure.unwrap_or_default()
+    }
+    /// How to reach the ServerQuery.
+    pub fn transport(&self) -> Transport {
+        if self.ssh() {
+            Transport::Ssh
+        } else if self.tls() {
+            Transport::Tls
+        } else {
+            Transport::Raw
+        }
+    }
+    pub fn nickname(&self) -> Option<&str> {
+        self.nickname.as_deref()
+    }
+    pub fn dry_run(&self) -> bool {
+        self.dry_run.unwrap_or_default()
+    }
+    pub fn state_file(&self) -> Option<&Path> {
+        self.state_file.as_deref()
+    }
+    pub fn metrics_addr(&self) -> Option<&str> {
+        self.metrics_addr.as_deref()
+    }
+    pub fn ready_within_secs(&self) -> u64 {
+        self.ready_within_secs.unwrap_or(180)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Config, Transport};
+    use crate::autochannel::WatchRule;
+    use crate::connection::test::{mock_conn, CHANNEL_GROUP_LIST};
+    use crate::connection::VirtualServer;
+    use crate::error::QueryError;
+    use crate::transport::IpPreference;
+
+    const TEST_CONFIG: &str = r#"
+server = "ts.example.com"
+port = 10011
+user = "serveradmin"
+password = "secret"
+sid = 2
+parent_channel = 5
+keepalive_secs = 30
+"#;
+
+    #[test]
+    fn test_parse() {
+        let config = Config::from_toml(TEST_CONFIG).unwrap();
+        assert_eq!(config.server(), "ts.example.com");
+        assert_eq!(config.port(), 10011);
+        assert_eq!(config.user(), "serveradmin");
+        assert_eq!(config.password(), "secret");
+        assert_eq!(config.sid(), 2);
+        assert_eq!(config.parent_channel(), 5);
+        assert_eq!(config.keepalive_secs(), 30);
+        assert_eq!(config.max_backoff_secs(), 60);
+        assert_eq!(config.timeout_secs(), 2);
+        assert!(!config.ssh());
+        assert!(config.validate().is_ok());
+
+        assert!(Config::from_toml("unknown_key = 1").is_err());
+    }
+
+    #[test]
+    fn test_cli_override() {
+        let file = Config {
+            server: Some("ts.example.com".to_string()),
+            user: Some("serveradmin".to_string()),
+            password: Some("secret".to_string()),
+            sid: Some(2),
+            ..Default::default()
+        };
+        let mut cli = Config::default();
+        cli.set_password(Some("from-cli".to_string()));
+        cli.set_sid(Some(3));
+        cli.set_parent_channel(Some(7));
+
+        let config = file.merge(cli);
+        assert_eq!(config.server(), "ts.example.com");
+        assert_eq!(config.user(), "serveradmin");
+        assert_eq!(config.password(), "from-cli");
+        assert_eq!(config.sid(), 3);
+        assert_eq!(config.parent_channel(), 7);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_server_port() {
+        let file = Config {
+            user: Some("serveradmin".to_string()),
+            password: Some("secret".to_string()),
+            sid: Some(2),
+            parent_channel: Some(5),
+            ..Default::default()
+        };
+        assert_eq!(file.virtual_server(), VirtualServer::Id(2));
+        let mut cli = Config::default();
+        cli.set_server_port(Some(9987));
+
+        let mut config = file.merge(cli);
+        assert_eq!(config.virtual_server(), VirtualServer::Port(9987));
+        assert!(config.validate().is_ok());
+
+        config.set_sid(Some(3));
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_watch_rules() {
+        let config = Config::from_toml(
+            r#"
+user = "serveradmin"
+password = "secret"
+
+[[watch]]
+parent_channel = 5
+name_template = "Game {client_nickname}"
+max_per_user = 1
+
+[[watch]]
+parent_channel = 8
+codec_quality = 10
+"#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        let rules = config.watch_rules();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].parent_channel(), 5);
+        assert_eq!(rules[0].name_template(), Some("Game {client_nickname}"));
+        assert_eq!(rules[0].max_per_user(), Some(1));
+        assert_eq!(rules[1].parent_channel(), 8);
+        assert_eq!(rules[1].codec_quality(), Some(10));
+        assert_eq!(rules[1].name_template(), None);
+    }
+
+    #[test]
+    fn test_drop_ins() {
+        let dir = std::env::temp_dir().join(format!(
+            "teamspeak-autochannel-drop-ins-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let files = [
+            ("20-music.toml", "[[watch]]\nparent_channel = 8\ncodec = 5\n"),
+            (
+                "10-games.toml",
+                "user = \"serveradmin\"\nempty_grace_secs = 30\n\n[[watch]]\nparent_channel = 5\n\n[[watch]]\nparent_channel = 6\n",
+            ),
+            ("README", "not a drop-in"),
+        ];
+        for (name, content) in files {
+            std::fs::write(dir.join(name), content).unwrap();
+        }
+        let base = Config::from_toml(
+            "user = \"serveradmin\"\npassword = \"secret\"\nparent_channel = 1\nempty_grace_secs = 10\n",
+        )
+        .unwrap();
+        let config = base.clone().with_drop_ins(&dir);
+
+        std::fs::write(dir.join("30-other.toml"), "user = \"admin\"\n").unwrap();
+        let conflict = base.with_drop_ins(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let config = config.unwrap();
+        let parents: Vec<i32> = config
+            .watch_rules()
+            .iter()
+            .map(WatchRule::parent_channel)
+            .collect();
+        assert_eq!(parents, vec![1, 5, 6, 8]);
+        assert_eq!(config.watch_rules()[3].codec(), Some(5));
+        assert_eq!(config.empty_grace_secs(), 30);
+        assert!(conflict.unwrap_err().to_string().contains("30-other.toml"));
+    }
+
+    #[test]
+    fn test_add_drop_in() {
+        let base = Config {
+            server: Some("ts.example.org".to_string()),
+            parent_channel: Some(1),
+            ..Default::default()
+        };
+        let mut drop_in = Config {
+            server: Some("ts.example.org".to_string()),
+            watch: Some(vec![WatchRule::new(5)]),
+            ..Default::default()
+        };
+        let merged = base.clone().add_drop_in(drop_in.clone()).unwrap();
+        assert_eq!(merged.watch_rules().len(), 2);
+        // Watched already
+        assert!(merged.add_drop_in(drop_in.clone()).is_err());
+
+        drop_in.server = Some("other.example.org".to_string());
+        match base.add_drop_in(drop_in) {
+            Err(QueryError::Config(msg)) => assert!(msg.starts_with("server differ")),
+            e => panic!("Unexpected result: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_validate_codec() {
+        let mut config = Config {
+            user: Some("serveradmin".to_string()),
+            password: Some("secret".to_string()),
+            parent_channel: Some(5),
+            ..Default::default()
+        };
+        config.set_channel_codec(Some(4));
+        config.set_channel_codec_quality(Some(10));
+        assert!(config.validate().is_ok());
+
+        config.set_channel_codec_quality(Some(11));
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("channel_codec_quality must be between 0 and 10"));
+
+        config.set_channel_codec_quality(None);
+        config.set_channel_codec(Some(6));
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_transport() {
+        let mut config = Config::default();
+        config.set_user(Some("serveradmin".to_string()));
+        config.set_password(Some("secret".to_string()));
+        assert_eq!(config.transport(), Transport::Raw);
+        config.set_tls(Some(true));
+        assert_eq!(config.transport(), Transport::Tls);
+        assert_eq!(config.port(), 10011);
+        assert!(config.validate_login().is_ok());
+        config.set_ssh(Some(true));
+        assert!(config.validate_login().is_err());
+    }
+
+    #[test]
+    fn test_validate_missing() {
+        let mut config = Config::default();
+        config.set_user(Some("serveradmin".to_string()));
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("password"));
+        assert!(!err.contains("parent_channel"));
+        assert!(!err.contains("user,"));
+
+        config.set_password(Some("secret".to_string()));
+        assert!(config.validate().is_ok());
+        assert!(config.validate_login().is_ok());
+        config.set_watch_subtree(Some(10));
+        assert!(config.validate().is_ok());
+        config.set_prefer_ipv4(Some(true));
+        assert_eq!(config.tcp_options().prefer(), IpPreference::Ipv4);
+        config.set_prefer_ipv6(Some(true));
+        assert!(config.validate().is_err());
+        config.set_prefer_ipv6(None);
+
+        config.set_proxy(Some("bastion:1080".to_string()));
+        assert!(matches!(config.validate(), Err(QueryError::Config(_))));
+        config.set_proxy(Some("socks5://bastion:1080".to_string()));
+        assert!(config.validate().is_ok());
+        assert_eq!(config.tcp_options().proxy().unwrap().host(), "bastion");
+    }
+
+    #[test]
+    fn test_servers() {
+        let mut config = Config {
+            user: Some("serveradmin".to_string()),
+            password: Some("secret".to_string()),
+            channel_name_template: Some("{client_nickname}".to_string()),
+            ..Default::default()
+        };
+        let first = Config {
+            sid: Some(1),
+            parent_channel: Some(5),
+            state_file: Some("one.json".into()),
+            ..Default::default()
+        };
+        let second = Config {
+            server_port: Some(9988),
+            parent_channel: Some(8),
+            channel_name_template: Some("Room {client_nickname}".to_string()),
+            ..Default::default()
+        };
+        config.set_servers(Some(vec![first, second.clone()]));
+        let targets = config.targets();
+        assert_eq!(targets.len(), 2);
+        assert_eq!(targets[0].virtual_server(), VirtualServer::Id(1));
+        assert_eq!(targets[0].parent_channel(), 5);
+        assert_eq!(targets[0].channel_name_template(), "{client_nickname}");
+        assert_eq!(targets[1].virtual_server(), VirtualServer::Port(9988));
+        assert_eq!(targets[1].parent_channel(), 8);
+        assert_eq!(targets[1].channel_name_template(), "Room {client_nickname}");
+        assert_eq!(targets[1].user(), "serveradmin");
+        assert!(config.validate().is_ok());
+
+        // Both would write the same state file
+        config.set_state_file(Some("one.json".into()));
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("servers[1]"), "{}", err);
+        config.set_state_file(None);
+
+        let nested = Config {
+            servers: Some(vec![second.clone()]),
+            ..second
+        };
+        config.set_servers(Some(vec![nested]));
+        assert!(config.validate().is_err());
+
+        config.set_servers(None);
+        config.set_parent_channel(Some(5));
+        assert_eq!(config.targets().len(), 1);
+    }
+
+    #[test]
+    fn test_resolve_groups() {
+        let (mut conn, mock) = mock_conn();
+        let mut config = Config::default();
+        config.set_parent_channel(Some(1));
+        assert_eq!(config.resolve_names(&mut conn).unwrap(), config);
+        assert!(mock.written().is_empty());
+
+        config.set_creator_channel_group_name(Some("Channel Admin".to_string()));
+        mock.push_reply(&[CHANNEL_GROUP_LIST]);
+        let resolved = config.resolve_names(&mut conn).unwrap();
+        assert_eq!(resolved.creator_channel_group(), Some(5));
+        assert_eq!(mock.written(), vec!["channelgrouplist\n".to_string()]);
+
+        config.set_creator_channel_group_name(Some("Moderator".to_string()));
+        mock.push_reply(&[CHANNEL_GROUP_LIST]);
+        let err = config.resolve_names(&mut conn).unwrap_err();
+        assert!(matches!(
+            err,
+            QueryError::Config(ref msg)
+                if msg == "Channel group Moderator not found, the server has: Channel Admin, Guest, Operator"
+        ));
+
+        config.set_user(Some("serveradmin".to_string()));
+        config.set_password(Some("password".to_string()));
+        config.set_parent_channel(Some(1));
+        config.validate().unwrap();
+        config.set_creator_channel_group(Some(5));
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains(
+            "creator_channel_group and creator_channel_group_name are mutually exclusive"
+        ));
+    }
+
+    #[test]
+    fn test_resolve_default_channel() {
+        let (mut conn, mock) = mock_conn();
+        let mut config = Config::default();
+        assert!(config.has_channel_names());
+        mock.push_reply(&["cid=4 pid=0 channel_order=0 channel_name=AFK channel_flag_default=0 channel_flag_password=0 channel_flag_permanent=1 channel_flag_semi_permanent=0|cid=7 pid=0 channel_order=4 channel_name=Lobby channel_flag_default=1 channel_flag_password=0 channel_flag_permanent=1 channel_flag_semi_permanent=0|cid=9 pid=7 channel_order=0 channel_name=Games channel_flag_default=0 channel_flag_password=0 channel_flag_permanent=1 channel_flag_semi_permanent=0\n\rerror id=0 msg=ok\n\r"]);
+        let resolved = config.resolve_names(&mut conn).unwrap();
+        assert_eq!(resolved.watch_rules()[0].parent_channel(), 7);
+        assert!(!resolved.has_channel_names());
+        assert_eq!(mock.written(), vec!["channellist -flags\n".to_string()]);
+
+        mock.push_reply(&["cid=4 pid=0 channel_order=0 channel_name=AFK channel_flag_default=0\n\rerror id=0 msg=ok\n\r"]);
+        assert!(matches!(
+            config.resolve_names(&mut conn),
+            Err(QueryError::Config(ref msg)) if msg.contains("no default channel")
+        ));
+
+        config.set_watch_subtree(Some(4));
+        assert!(!config.has_channel_names());
+    }
+
+    #[test]
+    fn test_resolve_parent_channel() {
+        let (mut conn, mock) = mock_conn();
+        let mut config = Config::default();
+        config.set_user(Some("serveradmin".to_string()));
+        config.set_password(Some("password".to_string()));
+        config.set_parent_channel_name(Some("Lobby/Games".to_string()));
+        config.validate().unwrap();
+        assert!(config.has_channel_names());
+        mock.push_reply(&["cid=1 pid=0 channel_order=0 channel_name=Lobby|cid=2 pid=1 channel_order=0 channel_name=Games|cid=3 pid=0 channel_order=1 channel_name=Games\n\rerror id=0 msg=ok\n\r"]);
+        let resolved = config.resolve_names(&mut conn).unwrap();
+        assert_eq!(resolved.parent_channel(), 2);
+        assert!(!resolved.has_channel_names());
+        assert_eq!(mock.written(), vec!["channellist\n".to_string()]);
+
+        config.set_parent_channel_name(Some("Games".to_string()));
+        mock.push_reply(&["cid=1 pid=0 channel_order=0 channel_name=Lobby|cid=2 pid=1 channel_order=0 channel_name=Games|cid=3 pid=0 channel_order=1 channel_name=Games\n\rerror id=0 msg=ok\n\r"]);
+        assert!(matches!(
+            config.resolve_names(&mut conn),
+            Err(QueryError::Config(ref msg)) if msg.contains("ambiguous")
+        ));
+
+        config.set_parent_channel(Some(1));
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_restart_required() {
+        let mut config = Config::default();
+        config.set_parent_channel(Some(5));
+        config.set_server(Some("ts.example.com".to_string()));
+        let mut reloaded = config.clone();
+        reloaded.set_channel_name_template(Some("Room {client_nickname}".to_string()));
+        reloaded.set_parent_channel(Some(6));
+        assert!(config.restart_required(&reloaded).is_empty());
+        reloaded.set_server(None);
+        reloaded.set_keepalive_secs(Some(30));
+        assert_eq!(
+            config.restart_required(&reloaded),
+            vec!["server", "keepalive_secs"]
+        );
+    }
+}
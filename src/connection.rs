@@ -0,0 +1,2681 @@
+use crate::datastructures::channel::Channel;
+use crate::datastructures::client::Client;
+use crate::datastructures::{
+    parse_field, ClientInfo, FromQueryString, HostInfo, Notification, QueryStatus, ServerVersion,
+    VirtualServerInfo, WhoAmI,
+};
+use crate::error::{QueryError, Result};
+use crate::protocol::{build_command, build_command_with, parse_kv, parse_list, TERMINATOR};
+use crate::rate_limit::RateLimiter;
+use crate::transport::{QueryTransport, TcpOptions};
+use log::{debug, error, info, warn};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Id of the next connection, see [`TelnetConn::id`].
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+const NOTIFY_EVENTS: [&str; 5] = [
+    "server",
+    "channel",
+    "textserver",
+    "textchannel",
+    "textprivate",
+];
+
+/// Read buffer of the raw transport unless configured otherwise.
+pub const DEFAULT_BUFFER_SIZE: usize = 512;
+
+/// Time to wait for a reply unless configured otherwise.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Pause before retrying a command rejected as flooding unless configured otherwise.
+pub const DEFAULT_FLOOD_COOLDOWN: Duration = Duration::from_secs(3);
+
+/// Nicknames tried by [`TelnetConn::set_nickname`] before giving up.
+const NICKNAME_ATTEMPTS: u32 = 10;
+
+/// `login` on a session authenticated already, e.g. by the SSH handshake.
+const ALREADY_LOGGED_IN: i32 = 3331;
+
+/// Limit of a single response unless configured otherwise, 1 MiB.
+pub const DEFAULT_MAX_RESPONSE_BYTES: usize = 1 << 20;
+
+/// Highest `channel_codec`: 0-2 Speex, 3 CELT, 4 Opus Voice, 5 Opus Music.
+pub const MAX_CODEC: u8 = 5;
+/// Highest `channel_codec_quality`, the lowest is 0.
+pub const MAX_CODEC_QUALITY: u8 = 10;
+
+/// Non-empty lines of a reply with the terminators trimmed, whether they are
+/// `\n\r`, `\r\n` or a lone `\r`.
+fn lines(content: &str) -> impl Iterator<Item = &str> {
+    content
+        .split(['\n', '\r'])
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+}
+
+/// How to pick the virtual server after connecting.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VirtualServer {
+    /// Internal server id (`use sid=1`)
+    Id(i32),
+    /// Voice port of the server (`use port=9987`)
+    Port(u16),
+}
+
+impl std::fmt::Display for VirtualServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VirtualServer::Id(sid) => write!(f, "{}", sid),
+            VirtualServer::Port(port) => write!(f, "on port {}", port),
+        }
+    }
+}
+
+/// Recipient kind of `sendtextmessage`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TargetMode {
+    /// Private message to a client id
+    Client = 1,
+    /// Message to the channel the query client currently sits in
+    Channel = 2,
+    /// Message to the whole virtual server
+    Server = 3,
+}
+
+/// Reply of [`TelnetConn::raw_command`].
+#[derive(Clone, Debug)]
+pub struct RawResponse {
+    status: QueryStatus,
+    /// Lines before the status line, still escaped
+    lines: Vec<String>,
+}
+
+impl RawResponse {
+    pub fn status(&self) -> &QueryStatus {
+        &self.status
+    }
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+}
+
+/// Optional columns of `channellist`, see [`TelnetConn::channel_list`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ChannelListFlags {
+    topic: bool,
+    flags: bool,
+    voice: bool,
+    limits: bool,
+}
+
+impl ChannelListFlags {
+    /// Add `channel_topic`
+    pub fn set_topic(&mut self, topic: bool) {
+        self.topic = topic;
+    }
+    /// Add the `channel_flag_*` columns
+    pub fn set_flags(&mut self, flags: bool) {
+        self.flags = flags;
+    }
+    /// Add codec and talk power columns
+    pub fn set_voice(&mut self, voice: bool) {
+        self.voice = voice;
+    }
+    /// Add the client limit columns
+    pub fn set_limits(&mut self, limits: bool) {
+        self.limits = limits;
+    }
+
+    fn options(&self) -> Vec<&'static str> {
+        [
+            (self.topic, "-topic"),
+            (self.flags, "-flags"),
+            (self.voice, "-voice"),
+            (self.limits, "-limits"),
+        ]
+        .into_iter()
+        .filter_map(|(set, option)| set.then_some(option))
+        .collect()
+    }
+}
+
+/// Optional columns of `clientlist`, see [`TelnetConn::client_list`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ClientListFlags {
+    uid: bool,
+    away: bool,
+    voice: bool,
+    groups: bool,
+    times: bool,
+}
+
+impl ClientListFlags {
+    /// Add `client_unique_identifier`
+    pub fn set_uid(&mut self, uid: bool) {
+        self.uid = uid;
+    }
+    /// Add `client_away` and the away message
+    pub fn set_away(&mut self, away: bool) {
+        self.away = away;
+    }
+    /// Add the talking and muted columns
+    pub fn set_voice(&mut self, voice: bool) {
+        self.voice = voice;
+    }
+    /// Add the server and channel group columns
+    pub fn set_groups(&mut self, groups: bool) {
+        self.groups = groups;
+    }
+    /// Add `client_idle_time` and the connection times
+    pub fn set_times(&mut self, times: bool) {
+        self.times = times;
+    }
+
+    fn options(&self) -> Vec<&'static str> {
+        [
+            (self.uid, "-uid"),
+            (self.away, "-away"),
+            (self.voice, "-voice"),
+            (self.groups, "-groups"),
+            (self.times, "-times"),
+        ]
+        .into_iter()
+        .filter_map(|(set, option)| set.then_some(option))
+        .collect()
+    }
+}
+
+/// Where `clientkick` removes the client from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KickReason {
+    /// Back into the default channel
+    Channel = 4,
+    /// Off the virtual server
+    Server = 5,
+}
+
+/// A ServerQuery session on top of a [`QueryTransport`].
+pub struct TelnetConn {
+    conn: Box<dyn QueryTransport>,
+    /// Bytes read but not yet split into lines
+    buffer: Vec<u8>,
+    /// Response lines read while polling for events
+    responses: Vec<String>,
+    /// Notifications read while waiting for a response
+    notifications: VecDeque<Notification>,
+    /// Time of the last command sent
+    last_activity: Instant,
+    /// `quit` was sent, nothing more to clean up on drop
+    closed: bool,
+    /// Time to wait for the banner and command replies
+    timeout: Duration,
+    /// Welcome message following the `TS3` greeting
+    welcome: String,
+    /// Log state changing commands instead of sending them
+    dry_run: bool,
+    /// Last id handed out for a channel created in dry run, counting down from -1
+    fake_cid: i32,
+    /// Pacing of the commands, see [`Self::set_rate_limit`]
+    rate_limit: Option<RateLimiter>,
+    /// Pause before retrying a command rejected by the anti-flood protection
+    flood_cooldown: Duration,
+    /// Time and round trip of the last successful [`Self::ping`]
+    last_ping: Option<(SystemTime, Duration)>,
+    /// Time the last complete command reply arrived
+    last_reply: Option<SystemTime>,
+    /// Size a response may reach before its status line arrives
+    max_response_bytes: usize,
+    /// Replies of timed out commands still to arrive, they are skipped when they do
+    late_replies: usize,
+    /// Tags the log lines of the thread using this connection
+    id: u64,
+    /// Start a stopped virtual server when selecting it
+    autostart_server: bool,
+}
+
+impl TelnetConn {
+    fn decode_status(data: Box<[u8]>) -> Result<(Option<QueryStatus>, String)> {
+        let (status, content) = Self::decode_status_unchecked(data)?;
+        if let Some(status) = &status {
+            if !status.is_ok() {
+                return Err(status.clone().into());
+            }
+        }
+        Ok((status, content))
+    }
+
+    /// Like [`Self::decode_status`] but leaves non ok status to the caller.
+    fn decode_status_unchecked(data: Box<[u8]>) -> Result<(Option<QueryStatus>, String)> {
+        let content = String::from_utf8(data.to_vec())
+            .map_err(|e| QueryError::Protocol(format!("Got FromUtf8Error: {:?}", e)))?;
+        // A reply without status line is left to the caller, a garbled one fails here
+        let status = lines(&content)
+            .find(|line| line.starts_with("error "))
+            .map(QueryStatus::try_from)
+            .transpose()?;
+        Ok((status, content))
+    }
+
+    fn decode_status_with_result<T: FromQueryString + Sized>(
+        data: Box<[u8]>,
+        //builder: Box<dyn Fn(&str) -> Result<T>>,
+    ) -> Result<(Option<QueryStatus>, Option<Vec<T>>)> {
+        let (status, content) = Self::decode_status(data)?;
+
+        for line in lines(&content) {
+            if !line.starts_with("error ") {
+                let mut v = Vec::new();
+                for element in line.split('|') {
+                    v.push(T::from_query(element)?);
+                }
+                return Ok((status, Some(v)));
+            }
+        }
+        Ok((status, None))
+    }
+
+    /// Connect to the raw (telnet) ServerQuery, usually on port 10011.
+    /// `timeout` applies to the banner and every command, see [`Self::set_timeout`].
+    pub fn connect(server: &str, port: u16, timeout: Duration) -> Result<Self> {
+        Self::builder(server, port).timeout(timeout).connect()
+    }
+
+    /// Like [`Self::connect`], choosing the addresses as `tcp` says.
+    pub fn connect_with(
+        server: &str,
+        port: u16,
+        tcp: &TcpOptions,
+        timeout: Duration,
+    ) -> Result<Self> {
+        Self::builder(server, port)
+            .tcp_options(tcp.clone())
+            .timeout(timeout)
+            .connect()
+    }
+
+    /// Configure a connection with more settings than [`Self::connect`] takes.
+    pub fn builder(server: &str, port: u16) -> TelnetConnBuilder {
+        TelnetConnBuilder::new(server, port)
+    }
+
+    /// Connect to the SSH ServerQuery, usually on port 10022. The session is
+    /// authenticated already, [`Self::login`] is not needed.
+    #[cfg(feature = "ssh")]
+    pub fn connect_ssh(
+        server: &str,
+        port: u16,
+        tcp: &TcpOptions,
+        user: &str,
+        password: &str,
+        timeout: Duration,
+    ) -> Result<Self> {
+        Self::builder(server, port)
+            .tcp_options(tcp.clone())
+            .ssh(user, password)
+            .timeout(timeout)
+            .connect()
+    }
+
+    /// Connect to the raw ServerQuery tunnelled through TLS, see
+    /// [`crate::transport::tls::TlsTransport::connect`] for `ca_file` and `insecure`.
+    #[cfg(feature = "tls")]
+    pub fn connect_tls(
+        server: &str,
+        port: u16,
+        tcp: &TcpOptions,
+        ca_file: Option<&std::path::Path>,
+        insecure: bool,
+        timeout: Duration,
+    ) -> Result<Self> {
+        Self::builder(server, port)
+            .tcp_options(tcp.clone())
+            .tls(true)
+            .tls_ca_file(ca_file.map(ToOwned::to_owned))
+            .tls_insecure(insecure)
+            .timeout(timeout)
+            .connect()
+    }
+
+    /// Start a session on an already connected transport, the greeting is consumed.
+    pub fn from_transport(conn: Box<dyn QueryTransport>, timeout: Duration) -> Result<Self> {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        crate::logging::set_connection_id(Some(id));
+        let mut self_ = Self {
+            conn,
+            buffer: Vec::new(),
+            responses: Vec::new(),
+            notifications: VecDeque::new(),
+            last_activity: Instant::now(),
+            closed: false,
+            timeout,
+            welcome: String::new(),
+            dry_run: false,
+            autostart_server: false,
+            fake_cid: 0,
+            rate_limit: None,
+            flood_cooldown: DEFAULT_FLOOD_COOLDOWN,
+            last_ping: None,
+            last_reply: None,
+            max_response_bytes: DEFAULT_MAX_RESPONSE_BYTES,
+            late_replies: 0,
+            id,
+        };
+        self_.read_banner()?;
+        Ok(self_)
+    }
+
+    /// Check the greeting starts with `TS3`, anything else means we are talking to
+    /// something else than a ServerQuery, e.g. the voice or file transfer port.
+    fn read_banner(&mut self) -> Result<()> {
+        let deadline = Instant::now() + self.timeout;
+        match self.next_line(deadline)? {
+            Some(line) if line == "TS3" => {}
+            Some(line) => {
+                return Err(QueryError::Protocol(format!(
+                    "Unexpected greeting {:?}, is this a ServerQuery port?",
+                    line
+                )))
+            }
+            None if !self.buffer.is_empty() => {
+                return Err(QueryError::Protocol(format!(
+                    "Unexpected greeting {:?}, is this a ServerQuery port?",
+                    String::from_utf8_lossy(&self.buffer)
+                )))
+            }
+            None => return Err(QueryError::Protocol("No greeting received".to_string())),
+        }
+        match self.next_line(deadline)? {
+            Some(welcome) => {
+                debug!("{}", welcome);
+                self.welcome = welcome;
+            }
+            None => warn!("Got no welcome message"),
+        }
+        Ok(())
+    }
+
+    /// Welcome message sent by the server after connecting.
+    pub fn welcome(&self) -> &str {
+        &self.welcome
+    }
+
+    /// Process wide unique id of this connection, the log lines of the thread it was
+    /// created on carry it as `conn_id`.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Only log `channelcreate`, `channeledit`, `channeldelete`, `clientmove` and `setclientchannelgroup`,
+    /// the created channels get negative ids. Other commands are still sent.
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+
+    /// Issue `serverstart` and select again when the selected virtual server is stopped.
+    pub fn set_autostart_server(&mut self, autostart_server: bool) {
+        self.autostart_server = autostart_server;
+    }
+
+    /// Pace the commands sent, `None` sends them as fast as possible.
+    pub fn set_rate_limit(&mut self, limiter: Option<RateLimiter>) {
+        self.rate_limit = limiter;
+    }
+
+    /// Give up on a response growing beyond `max` bytes without a status line,
+    /// a broken server streaming forever would exhaust the memory otherwise.
+    pub fn set_max_response_bytes(&mut self, max: usize) {
+        self.max_response_bytes = max;
+    }
+
+    /// Wait `cooldown` before retrying a command the server answered with
+    /// error 524 (client is flooding), see [`Self::write_and_read`].
+    pub fn set_flood_cooldown(&mut self, cooldown: Duration) {
+        self.flood_cooldown = cooldown;
+    }
+
+    /// Run `f` with a different reply timeout, e.g. for a slow `channellist` on a large server.
+    pub fn with_timeout<T>(
+        &mut self,
+        timeout: Duration,
+        f: impl FnOnce(&mut Self) -> Result<T>,
+    ) -> Result<T> {
+        let previous = std::mem::replace(&mut self.timeout, timeout);
+        let result = f(self);
+        self.timeout = previous;
+        result
+    }
+
+    fn read_data(&mut self, timeout: Duration) -> Result<Option<Box<[u8]>>> {
+        self.conn.read_timeout(timeout)
+    }
+
+    /// Next complete line with the terminator trimmed, `None` if none arrived before `deadline`.
+    fn next_line(&mut self, deadline: Instant) -> Result<Option<String>> {
+        loop {
+            // Lines end with \n\r, take either byte as terminator so \r\n or a
+            // lone \r don't leave a \r behind, empty lines are skipped below
+            while let Some(pos) = self.buffer.iter().position(|b| matches!(b, b'\n' | b'\r')) {
+                let line: Vec<u8> = self.buffer.drain(..=pos).collect();
+                let line = String::from_utf8(line)
+                    .map_err(|e| QueryError::Protocol(format!("Got FromUtf8Error: {:?}", e)))?;
+                if !line.trim().is_empty() {
+                    return Ok(Some(line.trim().to_string()));
+                }
+            }
+            if self.buffer.len() > self.max_response_bytes {
+                return Err(self.too_large());
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(None);
+            }
+            match self.read_data(remaining)? {
+                Some(data) => self.buffer.extend_from_slice(&data),
+                None => return Ok(None),
+            }
+        }
+    }
+
+    fn too_large(&mut self) -> QueryError {
+        // The rest of the stream can't be matched to commands anymore
+        self.buffer.clear();
+        QueryError::Protocol(format!(
+            "Response exceeds {} bytes without a status line, see --max-response-bytes",
+            self.max_response_bytes
+        ))
+    }
+
+    /// Read until a complete `error ` status line arrived, large replies are split
+    /// into several chunks by the telnet buffer. `timeout` bounds the whole response.
+    /// Notifications arriving in between are queued for [`Self::poll_event`].
+    /// A timeout fails with [`QueryError::IncompleteResponse`] if part of the reply
+    /// arrived, the rest of it is skipped once it does.
+    fn read_response(&mut self, timeout: Duration) -> Result<Box<[u8]>> {
+        let deadline = Instant::now() + timeout;
+        let mut lines = std::mem::take(&mut self.responses);
+        let mut size: usize = lines.iter().map(String::len).sum();
+        loop {
+            if let Some(pos) = lines.iter().position(|line| line.starts_with("error ")) {
+                if self.late_replies > 0 {
+                    debug!("Skipping the late reply of a timed out command");
+                    self.late_replies -= 1;
+                    lines.drain(..=pos);
+                    size = lines.iter().map(String::len).sum();
+                    continue;
+                }
+                self.responses = lines.split_off(pos + 1);
+                self.last_reply = Some(SystemTime::now());
+                return Ok(lines.join("\n").into_bytes().into_boxed_slice());
+            }
+            if size > self.max_response_bytes {
+                return Err(self.too_large());
+            }
+            match self.next_line(deadline)? {
+                Some(line) if line.starts_with("notify") => self
+                    .notifications
+                    .push_back(Notification::try_from(line.as_str())?),
+                Some(line) => {
+                    size += line.len();
+                    lines.push(line)
+                }
+                None => return Err(self.timed_out(lines)),
+            }
+        }
+    }
+
+    /// The status line of the current command didn't arrive in time, `lines` of
+    /// its reply did. Those of skipped late replies were dropped already.
+    fn timed_out(&mut self, mut lines: Vec<String>) -> QueryError {
+        self.late_replies += 1;
+        // A half received line stays buffered for its rest to complete it, even
+        // a split status line then still ends the late reply
+        if !self.buffer.is_empty() {
+            lines.push(String::from_utf8_lossy(&self.buffer).trim().to_string());
+        }
+        if lines.is_empty() {
+            return QueryError::Timeout;
+        }
+        QueryError::IncompleteResponse(lines.join("\n"))
+    }
+
+    /// Wait up to `timeout` seconds for a pushed notification. Command responses
+    /// read meanwhile are kept for the next [`Self::read_response`].
+    pub fn poll_event(&mut self, timeout: u64) -> Result<Option<Notification>> {
+        if let Some(notification) = self.notifications.pop_front() {
+            return Ok(Some(notification));
+        }
+        let deadline = Instant::now() + Duration::from_secs(timeout);
+        while let Some(line) = self.next_line(deadline)? {
+            if line.starts_with("notify") {
+                return Ok(Some(Notification::try_from(line.as_str())?));
+            }
+            self.responses.push(line);
+        }
+        Ok(None)
+    }
+
+    fn write_data(&mut self, payload: &str) -> Result<()> {
+        if let Some(limiter) = &mut self.rate_limit {
+            // A pipeline sends several commands at once, each takes a token
+            for _ in 0..payload.matches('\n').count().max(1) {
+                let wait = limiter.acquire(Instant::now());
+                if !wait.is_zero() {
+                    debug!("Rate limited, waiting {:?}", wait);
+                    std::thread::sleep(wait);
+                }
+            }
+        }
+        self.conn.write(payload.as_bytes()).map(|size| {
+            if size != payload.as_bytes().len() {
+                error!("Error")
+            }
+        })?;
+        self.last_activity = Instant::now();
+        Ok(())
+    }
+
+    /// [`Self::ping`] if no command was sent for `interval`, the server drops idle
+    /// query sessions. Returns whether a keepalive was sent.
+    pub fn keepalive(&mut self, interval: Duration, now: Instant) -> Result<bool> {
+        if now.saturating_duration_since(self.last_activity) < interval {
+            return Ok(false);
+        }
+        self.ping()?;
+        Ok(true)
+    }
+
+    /// Send a harmless `version` and measure the round trip.
+    pub fn ping(&mut self) -> Result<Duration> {
+        let start = Instant::now();
+        let data = self.write_and_read(&build_command("version", &[]), self.timeout)?;
+        Self::decode_status(data)?;
+        let latency = start.elapsed();
+        self.last_ping = Some((SystemTime::now(), latency));
+        Ok(latency)
+    }
+
+    /// Time and round trip of the last successful ping, also sent as keepalive.
+    pub fn last_ping(&self) -> Option<(SystemTime, Duration)> {
+        self.last_ping
+    }
+
+    /// Time the last reply to any command arrived, commands sent anyway make
+    /// the keepalive pings unnecessary.
+    pub fn last_reply(&self) -> Option<SystemTime> {
+        self.last_reply
+    }
+
+    /// Send a raw command line and return the response up to and including the status line.
+    /// A command rejected by the anti-flood protection is retried once after the cooldown.
+    pub fn write_and_read(&mut self, payload: &str, timeout: Duration) -> Result<Box<[u8]>> {
+        self.write_data(payload)?;
+        let data = self.read_response(timeout)?;
+        if !Self::is_flooding(&data) {
+            return Ok(data);
+        }
+        warn!(
+            "Server reports we are flooding, retrying {:?} in {:?}. Lower --commands-per-interval or whitelist our address in query_ip_allowlist.txt",
+            payload.split(' ').next().unwrap_or_default().trim_end(),
+            self.flood_cooldown
+        );
+        std::thread::sleep(self.flood_cooldown);
+        self.write_data(payload)?;
+        self.read_response(timeout)
+    }
+
+    /// Error 524, client is flooding.
+    fn is_flooding(data: &[u8]) -> bool {
+        matches!(
+            Self::decode_status_unchecked(data.into()),
+            Ok((Some(status), _)) if status.id() == 524
+        )
+    }
+
+    /// Queue several commands to send them in a single write, see [`Pipeline`].
+    pub fn pipeline(&mut self) -> Pipeline<'_> {
+        Pipeline {
+            conn: self,
+            commands: Vec::new(),
+        }
+    }
+
+    /// Send a state changing command. In dry run it is only logged and `fake_reply`
+    /// is answered with an ok status instead.
+    fn write_mutating(&mut self, payload: &str, fake_reply: &str) -> Result<Box<[u8]>> {
+        if self.dry_run {
+            info!("Dry run, not sending: {}", payload.trim_end());
+            return Ok(format!("{}\nerror id=0 msg=ok", fake_reply)
+                .into_bytes()
+                .into_boxed_slice());
+        }
+        self.write_and_read(payload, self.timeout)
+    }
+
+    /// Send a command whose reply is a single `key=value` line.
+    pub fn write_and_parse(
+        &mut self,
+        payload: &str,
+        timeout: Duration,
+    ) -> Result<(QueryStatus, HashMap<String, String>)> {
+        let data = self.write_and_read(payload, timeout)?;
+        Self::parse_reply(data)
+    }
+
+    /// Send a command whose reply is a `|` separated list, see [`parse_list`].
+    pub fn write_and_parse_list(
+        &mut self,
+        payload: &str,
+        timeout: Duration,
+    ) -> Result<(QueryStatus, Vec<HashMap<String, String>>)> {
+        let data = self.write_and_read(payload, timeout)?;
+        let (status, content) = Self::decode_status(data)?;
+        let status =
+            status.ok_or_else(|| QueryError::Protocol("Can't find status line.".to_string()))?;
+        let result = lines(&content)
+            .find(|line| !line.starts_with("error "))
+            .map(parse_list)
+            .unwrap_or_default();
+        Ok((status, result))
+    }
+
+    /// Split a reply into its status and the first `key=value` line.
+    fn parse_reply(data: Box<[u8]>) -> Result<(QueryStatus, HashMap<String, String>)> {
+        let (status, content) = Self::decode_status(data)?;
+        let status =
+            status.ok_or_else(|| QueryError::Protocol("Can't find status line.".to_string()))?;
+        let result = lines(&content)
+            .find(|line| !line.starts_with("error "))
+            .map(parse_kv)
+            .unwrap_or_default();
+        Ok((status, result))
+    }
+
+    /// Authenticate with ServerQuery credentials, they are escaped as needed.
+    /// A session authenticated already counts as logged in.
+    pub fn login(&mut self, user: &str, password: &str) -> Result<QueryStatus> {
+        let payload = build_command_with("login", &[user, password], &[], &[]);
+        let data = self.write_and_read(payload.as_str(), self.timeout)?;
+        let status = Self::decode_status_unchecked(data)?
+            .0
+            .ok_or_else(|| QueryError::Protocol("Can't find status line.".to_string()))?;
+        match status.id() {
+            0 => Ok(status),
+            ALREADY_LOGGED_IN => {
+                debug!("Already authenticated, not logging in again");
+                Ok(QueryStatus::new(0, "ok".to_string()))
+            }
+            _ => Err(status.into()),
+        }
+    }
+
+    /// Select the virtual server by its id (`use`).
+    /// A stopped server fails with [`QueryError::ServerNotRunning`], see [`Self::set_autostart_server`].
+    pub fn select_server(&mut self, server_id: i32) -> Result<QueryStatus> {
+        self.select_virtual_server(VirtualServer::Id(server_id))
+    }
+
+    /// Change `properties` of this query client, e.g. `client_away`.
+    pub fn client_update(&mut self, properties: &[(&str, &str)]) -> Result<QueryStatus> {
+        if properties.is_empty() {
+            return Err(QueryError::InvalidArgument(
+                "clientupdate needs at least one property".to_string(),
+            ));
+        }
+        let payload = build_command("clientupdate", properties);
+        let data = self.write_and_read(payload.as_str(), self.timeout)?;
+        Ok(Self::decode_status(data)?
+            .0
+            .ok_or_else(|| QueryError::Protocol("Can't find status line.".to_string()))?)
+    }
+
+    /// Show this query client as `name`. If it is taken (error 513), e.g. by the
+    /// session of a previous run not timed out yet, a number is appended.
+    /// Returns the nickname we got.
+    pub fn set_nickname(&mut self, name: &str) -> Result<String> {
+        let mut nickname = name.to_string();
+        let mut suffix = 0;
+        loop {
+            match self.client_update(&[("client_nickname", &nickname)]) {
+                Ok(_) => return Ok(nickname),
+                Err(QueryError::ServerError { id: 513, .. }) if suffix + 1 < NICKNAME_ATTEMPTS => {
+                    suffix += 1;
+                    nickname = format!("{}{}", name, suffix);
+                    debug!("Nickname in use, trying {}", nickname);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Ids of this query client and where it sits.
+    pub fn whoami(&mut self) -> Result<WhoAmI> {
+        let (_, result) = self.write_and_parse(&build_command("whoami", &[]), self.timeout)?;
+        WhoAmI::try_from(&result)
+    }
+
+    /// Select the virtual server by its voice port (`use port=`).
+    pub fn select_server_by_port(&mut self, port: u16) -> Result<QueryStatus> {
+        self.select_virtual_server(VirtualServer::Port(port))
+    }
+
+    /// Select the virtual server either way.
+    pub fn select_virtual_server(&mut self, server: VirtualServer) -> Result<QueryStatus> {
+        match self.send_use(server)? {
+            status if status.id() == 1033 && self.autostart_server => {
+                warn!("Virtual server {} is not running, starting it", server);
+                self.server_start(server)?;
+                match self.send_use(server)? {
+                    status if status.is_ok() => Ok(status),
+                    status => Err(status.into()),
+                }
+            }
+            status if status.id() == 1033 => Err(QueryError::ServerNotRunning(server.to_string())),
+            status if status.is_ok() => Ok(status),
+            status => Err(status.into()),
+        }
+    }
+
+    fn send_use(&mut self, server: VirtualServer) -> Result<QueryStatus> {
+        let payload = match server {
+            VirtualServer::Id(sid) => build_command_with("use", &[&sid.to_string()], &[], &[]),
+            VirtualServer::Port(port) => build_command("use", &[("port", &port.to_string())]),
+        };
+        let data = self.write_and_read(payload.as_str(), self.timeout)?;
+        Self::decode_status_unchecked(data)?
+            .0
+            .ok_or_else(|| QueryError::Protocol("Can't find status line.".to_string()))
+    }
+
+    /// Start a stopped virtual server (`serverstart`), a port is resolved with `serveridgetbyport`.
+    pub fn server_start(&mut self, server: VirtualServer) -> Result<QueryStatus> {
+        let sid = match server {
+            VirtualServer::Id(sid) => sid,
+            VirtualServer::Port(port) => {
+                let payload = build_command(
+                    "serveridgetbyport",
+                    &[("virtualserver_port", &port.to_string())],
+                );
+                let (_, result) = self.write_and_parse(&payload, self.timeout)?;
+                result
+                    .get("server_id")
+                    .and_then(|sid| sid.parse().ok())
+                    .ok_or_else(|| QueryError::Protocol("Missing server_id in reply".to_string()))?
+            }
+        };
+        let payload = build_command("serverstart", &[("sid", &sid.to_string())]);
+        let data = self.write_mutating(payload.as_str(), "")?;
+        Ok(Self::decode_status(data)?
+            .0
+            .ok_or_else(|| QueryError::Protocol("Can't find status line.".to_string()))?)
+    }
+
+    /// Version, build and platform of the server.
+    pub fn server_version(&mut self) -> Result<ServerVersion> {
+        let (_, result) = self.write_and_parse(&build_command("version", &[]), self.timeout)?;
+        ServerVersion::try_from(&result)
+    }
+
+    /// Settings of the selected virtual server (`serverinfo`), all fields as sent.
+    pub fn server_info(&mut self) -> Result<HashMap<String, String>> {
+        let (_, result) = self.write_and_parse(&build_command("serverinfo", &[]), self.timeout)?;
+        Ok(result)
+    }
+
+    /// Instance settings (`instanceinfo`), among them the ServerQuery flood limit
+    /// [`RateLimiter::from_flood_settings`] reads.
+    pub fn instance_info(&mut self) -> Result<HashMap<String, String>> {
+        let (_, result) =
+            self.write_and_parse(&build_command("instanceinfo", &[]), self.timeout)?;
+        Ok(result)
+    }
+
+    /// Instance wide statistics, needs a login.
+    pub fn host_info(&mut self) -> Result<HostInfo> {
+        let (_, result) = self.write_and_parse(&build_command("hostinfo", &[]), self.timeout)?;
+        HostInfo::try_from(&result)
+    }
+
+    /// Send `command` as is, a single line with the arguments already escaped, for
+    /// commands not wrapped here. A non ok status is returned rather than an error.
+    pub fn raw_command(&mut self, command: &str) -> Result<RawResponse> {
+        if command.contains(['\n', '\r']) {
+            return Err(QueryError::InvalidArgument(
+                "raw command must be a single line".to_string(),
+            ));
+        }
+        if command.trim().is_empty() {
+            return Err(QueryError::InvalidArgument(
+                "raw command is empty".to_string(),
+            ));
+        }
+        let payload = format!("{}{}", command.trim(), TERMINATOR);
+        let data = self.write_and_read(payload.as_str(), self.timeout)?;
+        let (status, content) = Self::decode_status_unchecked(data)?;
+        Ok(RawResponse {
+            status: status
+                .ok_or_else(|| QueryError::Protocol("Can't find status line.".to_string()))?,
+            lines: lines(&content)
+                .filter(|line| !line.starts_with("error "))
+                .map(ToString::to_string)
+                .collect(),
+        })
+    }
+
+    /// Virtual servers of the instance (`serverlist`), no server needs to be selected.
+    pub fn server_list(&mut self) -> Result<Vec<VirtualServerInfo>> {
+        let (_, records) =
+            self.write_and_parse_list(&build_command("serverlist", &[]), self.timeout)?;
+        records.iter().map(VirtualServerInfo::try_from).collect()
+    }
+
+    /// Subscribe to `event`, one of `server`, `channel`, `textserver`, `textchannel`
+    /// or `textprivate`. `id` selects the channel for channel events, 0 means all.
+    /// Registering an event twice (1541) is not treated as an error.
+    pub fn notify_register(&mut self, event: &str, id: Option<i32>) -> Result<QueryStatus> {
+        if !NOTIFY_EVENTS.contains(&event) {
+            return Err(QueryError::InvalidArgument(format!(
+                "Unknown notify event: {}",
+                event
+            )));
+        }
+        let id = id.map(|id| id.to_string());
+        let mut args = vec![("event", event)];
+        if let Some(id) = &id {
+            args.push(("id", id));
+        }
+        let data = self.write_and_read(
+            build_command("servernotifyregister", &args).as_str(),
+            self.timeout,
+        )?;
+        let status = Self::decode_status_unchecked(data)?
+            .0
+            .ok_or_else(|| QueryError::Protocol("Can't find status line.".to_string()))?;
+        match status.id() {
+            0 => Ok(status),
+            1541 => {
+                warn!("Notify event {} already registered", event);
+                Ok(status)
+            }
+            _ => Err(status.into()),
+        }
+    }
+
+    /// Drop all notification registrations of this session.
+    pub fn notify_unregister(&mut self) -> Result<QueryStatus> {
+        let data =
+            self.write_and_read(&build_command("servernotifyunregister", &[]), self.timeout)?;
+        Ok(Self::decode_status(data)?
+            .0
+            .ok_or_else(|| QueryError::Protocol("Can't find status line.".to_string()))?)
+    }
+
+    /// Deselect the virtual server and drop the authentication of this session.
+    pub fn logout(&mut self) -> Result<QueryStatus> {
+        let data = self.write_and_read(&build_command("logout", &[]), self.timeout)?;
+        Ok(Self::decode_status(data)?
+            .0
+            .ok_or_else(|| QueryError::Protocol("Can't find status line.".to_string()))?)
+    }
+
+    /// Close the session, the server drops the connection without a reply.
+    pub fn quit(&mut self) -> Result<()> {
+        self.closed = true;
+        self.write_data(&build_command("quit", &[]))
+    }
+
+    /// Create a channel, `properties` are extra `channelcreate` parameters such as
+    /// `cpid`, `channel_topic`, `channel_flag_permanent` or `channel_codec_quality`.
+    /// An unknown `channel_codec` or `channel_codec_quality` is refused before sending.
+    /// Returns the id of the new channel.
+    pub fn channel_create(&mut self, name: &str, properties: &[(&str, &str)]) -> Result<i32> {
+        for (key, max) in [
+            ("channel_codec", MAX_CODEC),
+            ("channel_codec_quality", MAX_CODEC_QUALITY),
+        ] {
+            if let Some((_, value)) = properties.iter().find(|(k, _)| *k == key) {
+                if !matches!(value.parse::<u8>(), Ok(value) if value <= max) {
+                    return Err(QueryError::InvalidArgument(format!(
+                        "{} must be between 0 and {}, got {}",
+                        key, max, value
+                    )));
+                }
+            }
+        }
+        let mut args = vec![("channel_name", name)];
+        args.extend_from_slice(properties);
+        let payload = build_command("channelcreate", &args);
+        let fake_reply = format!("cid={}", self.fake_cid - 1);
+        let data = self.write_mutating(payload.as_str(), &fake_reply)?;
+        if self.dry_run {
+            self.fake_cid -= 1;
+        }
+        let (_, result) = Self::parse_reply(data)?;
+        result
+            .get("cid")
+            .ok_or_else(|| {
+                QueryError::Protocol("Can't find cid in channelcreate reply.".to_string())
+            })?
+            .parse()
+            .map_err(|e| QueryError::Protocol(format!("Got error while parse cid: {:?}", e)))
+    }
+
+    /// Change `properties` of channel `cid`, e.g. `channel_order` to move it after another channel.
+    pub fn channel_edit(&mut self, cid: i32, properties: &[(&str, &str)]) -> Result<QueryStatus> {
+        let cid = cid.to_string();
+        let mut args = vec![("cid", cid.as_str())];
+        args.extend_from_slice(properties);
+        let payload = build_command("channeledit", &args);
+        let data = self.write_mutating(payload.as_str(), "")?;
+        Ok(Self::decode_status(data)?
+            .0
+            .ok_or_else(|| QueryError::Protocol("Can't find status line.".to_string()))?)
+    }
+
+    /// Move client `clid` into channel `cid`.
+    pub fn client_move(&mut self, clid: i32, cid: i32) -> Result<QueryStatus> {
+        let payload = build_command(
+            "clientmove",
+            &[("clid", &clid.to_string()), ("cid", &cid.to_string())],
+        );
+        let data = self.write_mutating(payload.as_str(), "")?;
+        let status = Self::decode_status_unchecked(data)?
+            .0
+            .ok_or_else(|| QueryError::Protocol("Can't find status line.".to_string()))?;
+        let reason = match status.id() {
+            0 => return Ok(status),
+            512 => "client not found",
+            768 => "channel not found",
+            770 => "client already in target channel",
+            _ => "server refused",
+        };
+        warn!("Can't move client {} to channel {}, {}", clid, cid, reason);
+        Err(status.into())
+    }
+
+    /// Kick client `clid` from its channel or the server, `msg` is shown to the client.
+    pub fn client_kick(&mut self, clid: i32, reason: KickReason, msg: &str) -> Result<QueryStatus> {
+        let payload = build_command(
+            "clientkick",
+            &[
+                ("clid", &clid.to_string()),
+                ("reasonid", &(reason as i32).to_string()),
+                ("reasonmsg", msg),
+            ],
+        );
+        let data = self.write_mutating(payload.as_str(), "")?;
+        let status = Self::decode_status_unchecked(data)?
+            .0
+            .ok_or_else(|| QueryError::Protocol("Can't find status line.".to_string()))?;
+        match status.id() {
+            0 => return Ok(status),
+            512 => warn!("Can't kick client {}, client not found", clid),
+            768 => warn!("Can't kick client {}, channel not found", clid),
+            _ => warn!("Can't kick client {}, server refused", clid),
+        }
+        Err(status.into())
+    }
+
+    /// Details of the client `clid`, an unknown client fails with error 512.
+    pub fn client_info(&mut self, clid: i32) -> Result<ClientInfo> {
+        let payload = build_command("clientinfo", &[("clid", &clid.to_string())]);
+        let (_, result) = self.write_and_parse(payload.as_str(), self.timeout)?;
+        ClientInfo::try_from(&result)
+    }
+
+    /// Database id of the identity `uid` (`clientgetdbidfromuid`).
+    pub fn client_dbid_from_uid(&mut self, uid: &str) -> Result<i64> {
+        let payload = build_command("clientgetdbidfromuid", &[("cluid", uid)]);
+        let (_, result) = self.write_and_parse(payload.as_str(), self.timeout)?;
+        parse_field(&result, "cldbid")
+    }
+
+    /// Send `msg` as a text message, `target` is the clid for [`TargetMode::Client`]
+    /// and ignored by the server otherwise.
+    pub fn send_text_message(
+        &mut self,
+        target_mode: TargetMode,
+        target: i32,
+        msg: &str,
+    ) -> Result<QueryStatus> {
+        let payload = build_command(
+            "sendtextmessage",
+            &[
+                ("targetmode", &(target_mode as i32).to_string()),
+                ("target", &target.to_string()),
+                ("msg", msg),
+            ],
+        );
+        let data = self.write_mutating(payload.as_str(), "")?;
+        Ok(Self::decode_status(data)?
+            .0
+            .ok_or_else(|| QueryError::Protocol("Can't find status line.".to_string()))?)
+    }
+
+    /// Poke client `clid`, showing `msg` in a popup.
+    pub fn poke_client(&mut self, clid: i32, msg: &str) -> Result<QueryStatus> {
+        let payload = build_command("clientpoke", &[("clid", &clid.to_string()), ("msg", msg)]);
+        let data = self.write_mutating(payload.as_str(), "")?;
+        Ok(Self::decode_status(data)?
+            .0
+            .ok_or_else(|| QueryError::Protocol("Can't find status line.".to_string()))?)
+    }
+
+    /// Put the client with database id `cldbid` into channel group `cgid` for channel `cid`.
+    pub fn set_client_channel_group(
+        &mut self,
+        cgid: i32,
+        cid: i32,
+        cldbid: i64,
+    ) -> Result<QueryStatus> {
+        let payload = build_command(
+            "setclientchannelgroup",
+            &[
+                ("cgid", &cgid.to_string()),
+                ("cid", &cid.to_string()),
+                ("cldbid", &cldbid.to_string()),
+            ],
+        );
+        let data = self.write_mutating(payload.as_str(), "")?;
+        Ok(Self::decode_status(data)?
+            .0
+            .ok_or_else(|| QueryError::Protocol("Can't find status line.".to_string()))?)
+    }
+
+    /// Ids of the regular server groups by name (`servergrouplist`).
+    pub fn server_group_list(&mut self) -> Result<HashMap<String, i32>> {
+        let (_, records) =
+            self.write_and_parse_list(&build_command("servergrouplist", &[]), self.timeout)?;
+        group_ids(&records, "sgid")
+    }
+
+    /// Ids of the regular channel groups by name (`channelgrouplist`).
+    pub fn channel_group_list(&mut self) -> Result<HashMap<String, i32>> {
+        let (_, records) =
+            self.write_and_parse_list(&build_command("channelgrouplist", &[]), self.timeout)?;
+        group_ids(&records, "cgid")
+    }
+
+    /// Delete channel `cid`, `force` also kicks the clients still inside.
+    /// Without `force` a channel with clients inside fails with [`QueryError::ChannelNotEmpty`].
+    pub fn channel_delete(&mut self, cid: i32, force: bool) -> Result<QueryStatus> {
+        let payload = build_command(
+            "channeldelete",
+            &[
+                ("cid", &cid.to_string()),
+                ("force", &(force as u8).to_string()),
+            ],
+        );
+        let data = self.write_mutating(payload.as_str(), "")?;
+        let status = Self::decode_status_unchecked(data)?
+            .0
+            .ok_or_else(|| QueryError::Protocol("Can't find status line.".to_string()))?;
+        match status.id() {
+            0 => Ok(status),
+            772 => Err(QueryError::ChannelNotEmpty(cid)),
+            _ => Err(status.into()),
+        }
+    }
+
+    pub fn query_clients(&mut self) -> Result<(QueryStatus, Vec<Client>)> {
+        let payload = build_command_with("clientlist", &[], &[], &["-uid"]);
+        let data = self.write_and_read(&payload, self.timeout)?;
+        let (status, clients) = Self::decode_status_with_result(data)?;
+
+        Ok((
+            status.ok_or_else(|| QueryError::Protocol("Can't find status line.".to_string()))?,
+            clients.ok_or_else(|| QueryError::Protocol("Can't find result line.".to_string()))?,
+        ))
+    }
+
+    /// All clients including ServerQuery ones, see [`Client::is_query`]. `flags`
+    /// select the optional columns of the reply.
+    pub fn client_list(&mut self, flags: ClientListFlags) -> Result<Vec<Client>> {
+        let payload = build_command_with("clientlist", &[], &[], &flags.options());
+        let (_, records) = self.write_and_parse_list(&payload, self.timeout)?;
+        records.iter().map(Client::try_from).collect()
+    }
+
+    /// All channels, `flags` select the optional columns of the reply.
+    pub fn channel_list(&mut self, flags: ChannelListFlags) -> Result<Vec<Channel>> {
+        let payload = build_command_with("channellist", &[], &[], &flags.options());
+        let (_, records) = self.write_and_parse_list(&payload, self.timeout)?;
+        records.iter().map(Channel::try_from).collect()
+    }
+
+    pub fn query_channels(&mut self) -> Result<(QueryStatus, Vec<Channel>)> {
+        let data = self.write_and_read(&build_command("channellist", &[]), self.timeout)?;
+        let (status, channels) = Self::decode_status_with_result(data)?;
+
+        Ok((
+            status.ok_or_else(|| QueryError::Protocol("Can't find status line.".to_string()))?,
+            channels.ok_or_else(|| QueryError::Protocol("Can't find result line.".to_string()))?,
+        ))
+    }
+}
+
+/// Commands sent together, the server answers them in order so the n-th status
+/// line belongs to the n-th command. Dry run does not apply, queue only commands
+/// that are fine to send.
+pub struct Pipeline<'a> {
+    conn: &'a mut TelnetConn,
+    commands: Vec<String>,
+}
+
+impl Pipeline<'_> {
+    /// Queue a command line, e.g. one made by [`build_command`].
+    pub fn push(&mut self, payload: &str) -> &mut Self {
+        self.commands.push(payload.to_string());
+        self
+    }
+
+    /// Send the queued commands and read one reply per command. The outer error is
+    /// a failed connection, the inner ones are the commands refused by the server.
+    pub fn send(self) -> Result<Vec<Result<(QueryStatus, HashMap<String, String>)>>> {
+        if self.commands.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.conn.write_data(&self.commands.concat())?;
+        let timeout = self.conn.timeout;
+        self.commands
+            .iter()
+            .map(|_| {
+                self.conn
+                    .read_response(timeout)
+                    .map(TelnetConn::parse_reply)
+            })
+            .collect()
+    }
+}
+
+impl Drop for TelnetConn {
+    /// Best effort, the connection may already be gone. Only `quit` is sent, it
+    /// waits for no reply that could hold up replacing a dead connection.
+    fn drop(&mut self) {
+        if self.closed {
+            return;
+        }
+        if let Err(e) = self.quit() {
+            warn!("Got error while quit: {:?}", e);
+        }
+    }
+}
+
+/// Settings of a [`TelnetConn`] to open, see [`TelnetConn::builder`]. Raw TCP
+/// unless [`Self::ssh`] or [`Self::tls`] is chosen.
+#[derive(Clone)]
+pub struct TelnetConnBuilder {
+    server: String,
+    port: u16,
+    timeout: Duration,
+    tcp: TcpOptions,
+    /// Read buffer of the raw telnet transport
+    buffer_size: usize,
+    /// User and password of the SSH handshake
+    ssh: Option<(String, String)>,
+    tls: bool,
+    tls_ca_file: Option<std::path::PathBuf>,
+    tls_insecure: bool,
+    rate_limit: Option<RateLimiter>,
+    flood_cooldown: Duration,
+    max_response_bytes: usize,
+    dry_run: bool,
+    autostart_server: bool,
+}
+
+impl TelnetConnBuilder {
+    pub fn new(server: &str, port: u16) -> Self {
+        Self {
+            server: server.to_string(),
+            port,
+            timeout: DEFAULT_TIMEOUT,
+            tcp: TcpOptions::default(),
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            ssh: None,
+            tls: false,
+            tls_ca_file: None,
+            tls_insecure: false,
+            rate_limit: None,
+            flood_cooldown: DEFAULT_FLOOD_COOLDOWN,
+            max_response_bytes: DEFAULT_MAX_RESPONSE_BYTES,
+            dry_run: false,
+            autostart_server: false,
+        }
+    }
+
+    /// See [`TelnetConn::set_timeout`].
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+    /// Local and remote address selection, see [`crate::transport::tcp_connect`].
+    pub fn tcp_options(mut self, tcp: TcpOptions) -> Self {
+        self.tcp = tcp;
+        self
+    }
+    /// Read buffer of the raw transport, ignored over SSH and TLS.
+    pub fn buffer_size(mut self, size: usize) -> Self {
+        self.buffer_size = size;
+        self
+    }
+    /// Use the SSH ServerQuery, the session is authenticated in the handshake.
+    pub fn ssh(mut self, user: &str, password: &str) -> Self {
+        self.ssh = Some((user.to_string(), password.to_string()));
+        self
+    }
+    /// Tunnel the raw ServerQuery through TLS.
+    pub fn tls(mut self, tls: bool) -> Self {
+        self.tls = tls;
+        self
+    }
+    /// PEM certificate trusted besides the system roots, TLS only.
+    pub fn tls_ca_file(mut self, ca_file: Option<std::path::PathBuf>) -> Self {
+        self.tls_ca_file = ca_file;
+        self
+    }
+    /// Accept any certificate, TLS only.
+    pub fn tls_insecure(mut self, insecure: bool) -> Self {
+        self.tls_insecure = insecure;
+        self
+    }
+    /// See [`TelnetConn::set_rate_limit`].
+    pub fn rate_limit(mut self, limiter: Option<RateLimiter>) -> Self {
+        self.rate_limit = limiter;
+        self
+    }
+    /// See [`TelnetConn::set_flood_cooldown`].
+    pub fn flood_cooldown(mut self, cooldown: Duration) -> Self {
+        self.flood_cooldown = cooldown;
+        self
+    }
+    /// See [`TelnetConn::set_max_response_bytes`].
+    pub fn max_response_bytes(mut self, max: usize) -> Self {
+        self.max_response_bytes = max;
+        self
+    }
+    /// See [`TelnetConn::set_dry_run`].
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+    /// See [`TelnetConn::set_autostart_server`].
+    pub fn autostart_server(mut self, autostart_server: bool) -> Self {
+        self.autostart_server = autostart_server;
+        self
+    }
+
+    /// Reject settings that contradict each other, [`Self::connect`] checks them
+    /// before opening anything.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |msg: &str| Err(QueryError::InvalidArgument(msg.to_string()));
+        if self.ssh.is_some() && self.tls {
+            return invalid("SSH and TLS are mutually exclusive, choose one of them");
+        }
+        if !self.tls && (self.tls_ca_file.is_some() || self.tls_insecure) {
+            return invalid("The TLS CA file and insecure mode need TLS");
+        }
+        if self.tls_ca_file.is_some() && self.tls_insecure {
+            return invalid("The TLS CA file is pointless when certificates aren't verified");
+        }
+        if self.buffer_size == 0 {
+            return invalid("The buffer size must not be 0");
+        }
+        Ok(())
+    }
+
+    /// Connect and apply the settings.
+    pub fn connect(self) -> Result<TelnetConn> {
+        self.validate()?;
+        let transport: Box<dyn QueryTransport> = if let Some((user, password)) = &self.ssh {
+            self.connect_ssh(user, password)?
+        } else if self.tls {
+            self.connect_tls()?
+        } else {
+            let stream = crate::transport::tcp_connect(&self.server, self.port, &self.tcp)?;
+            Box::new(telnet::Telnet::from_stream(
+                Box::new(stream),
+                self.buffer_size,
+            ))
+        };
+        self.from_transport(transport)
+    }
+
+    /// Start the session on an already connected transport and apply the settings,
+    /// the transport choice is up to the caller then.
+    pub fn from_transport(self, transport: Box<dyn QueryTransport>) -> Result<TelnetConn> {
+        let mut conn = TelnetConn::from_transport(transport, self.timeout)?;
+        conn.set_rate_limit(self.rate_limit);
+        conn.set_flood_cooldown(self.flood_cooldown);
+        conn.set_max_response_bytes(self.max_response_bytes);
+        conn.set_dry_run(self.dry_run);
+        conn.set_autostart_server(self.autostart_server);
+        Ok(conn)
+    }
+
+    #[cfg(feature = "ssh")]
+    fn connect_ssh(&self, user: &str, password: &str) -> Result<Box<dyn QueryTransport>> {
+        Ok(Box::new(crate::transport::ssh::SshTransport::connect(
+            &self.server,
+            self.port,
+            &self.tcp,
+            user,
+            password,
+        )?))
+    }
+
+    #[cfg(not(feature = "ssh"))]
+    fn connect_ssh(&self, _user: &str, _password: &str) -> Result<Box<dyn QueryTransport>> {
+        Err(QueryError::InvalidArgument(
+            "Built without SSH support, enable the ssh feature".to_string(),
+        ))
+    }
+
+    #[cfg(feature = "tls")]
+    fn connect_tls(&self) -> Result<Box<dyn QueryTransport>> {
+        Ok(Box::new(crate::transport::tls::TlsTransport::connect(
+            &self.server,
+            self.port,
+            &self.tcp,
+            self.tls_ca_file.as_deref(),
+            self.tls_insecure,
+        )?))
+    }
+
+    #[cfg(not(feature = "tls"))]
+    fn connect_tls(&self) -> Result<Box<dyn QueryTransport>> {
+        Err(QueryError::InvalidArgument(
+            "Built without TLS support, enable the tls feature".to_string(),
+        ))
+    }
+}
+
+/// Map the names of a group list to `id_key`. Only regular groups (`type=1`) are
+/// kept, templates and ServerQuery groups share their names.
+fn group_ids(records: &[HashMap<String, String>], id_key: &str) -> Result<HashMap<String, i32>> {
+    let mut groups = HashMap::new();
+    for record in records {
+        if record.get("type").map(String::as_str) != Some("1") {
+            continue;
+        }
+        let name: String = parse_field(record, "name")?;
+        groups.insert(name, parse_field(record, id_key)?);
+    }
+    Ok(groups)
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::datastructures::ServerStatus;
+    use crate::transport::mock::MockTransport;
+
+    pub const BANNER: &str = "TS3\n\rWelcome to the TeamSpeak 3 ServerQuery interface.\n\r";
+
+    pub fn mock_conn() -> (TelnetConn, MockTransport) {
+        let mock = MockTransport::default();
+        mock.push_read(BANNER);
+        let conn = TelnetConn::from_transport(Box::new(mock.clone()), DEFAULT_TIMEOUT).unwrap();
+        (conn, mock)
+    }
+
+    #[test]
+    fn test_banner() {
+        let (conn, _) = mock_conn();
+        assert_eq!(
+            conn.welcome(),
+            "Welcome to the TeamSpeak 3 ServerQuery interface."
+        );
+
+        // Split across reads
+        let mock = MockTransport::default();
+        mock.push_read("TS");
+        mock.push_read("3\n\rWelcome to the TeamSpeak 3 ServerQuery interface.\n\r");
+        let conn = TelnetConn::from_transport(Box::new(mock), DEFAULT_TIMEOUT).unwrap();
+        assert!(conn.welcome().starts_with("Welcome"));
+    }
+
+    #[test]
+    fn test_banner_empty() {
+        let mock = MockTransport::default();
+        assert!(matches!(
+            TelnetConn::from_transport(Box::new(mock), Duration::from_millis(10)),
+            Err(QueryError::Protocol(_))
+        ));
+    }
+
+    #[test]
+    fn test_banner_garbage() {
+        let mock = MockTransport::default();
+        mock.push_read("SSH-2.0-libssh_0.9.6\r\n");
+        assert!(matches!(
+            TelnetConn::from_transport(Box::new(mock), DEFAULT_TIMEOUT),
+            Err(QueryError::Protocol(_))
+        ));
+
+        let mock = MockTransport::default();
+        mock.push_read("\u{1}\u{2}\u{3}");
+        assert!(matches!(
+            TelnetConn::from_transport(Box::new(mock), Duration::from_millis(10)),
+            Err(QueryError::Protocol(_))
+        ));
+    }
+
+    #[test]
+    fn test_multi_chunk_response() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&[
+            "cid=1 pid=0 channel_order=0 channel_name=Lobby total_clients=1 channel_needed_subscribe_power=0|cid=2 pid=0 cha",
+            "nnel_order=1 channel_name=AFK total_clients=0 channel_needed_subscribe_power=0\n\rerror id=0 ",
+            "msg=ok\n\r",
+        ]);
+
+        let data = conn
+            .write_and_read("channellist\n", Duration::from_secs(2))
+            .unwrap();
+        let (status, content) = TelnetConn::decode_status(data).unwrap();
+        assert!(status.unwrap().is_ok());
+        assert!(content.contains("channel_name=Lobby"));
+        assert!(content.contains("|cid=2 pid=0 channel_order=1 channel_name=AFK"));
+        assert_eq!(mock.written(), vec!["channellist\n".to_string()]);
+    }
+
+    #[test]
+    fn test_write_and_parse() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&[
+            "virtualserver_status=online virtualserver_id=1 client_id=3 client_channel_id=1\n\r",
+            "error id=0 msg=ok\n\r",
+        ]);
+        let (status, result) = conn
+            .write_and_parse("whoami\n", Duration::from_secs(2))
+            .unwrap();
+        assert!(status.is_ok());
+        assert_eq!(result["virtualserver_status"], "online");
+        assert_eq!(result["client_id"], "3");
+
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        let (status, result) = conn
+            .write_and_parse("use 1\n", Duration::from_secs(2))
+            .unwrap();
+        assert!(status.is_ok());
+        assert!(result.is_empty());
+    }
+
+    #[test]
+    fn test_channel_create() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&["cid=42\nerror id=0 msg=ok\n"]);
+        let cid = conn
+            .channel_create(
+                "Alice's Channel",
+                &[
+                    ("cpid", "1"),
+                    ("channel_topic", "Spawned by autochannel"),
+                    ("channel_flag_permanent", "0"),
+                    ("channel_codec_quality", "10"),
+                ],
+            )
+            .unwrap();
+        assert_eq!(cid, 42);
+        assert_eq!(
+            mock.written(),
+            vec!["channelcreate channel_name=Alice's\\sChannel cpid=1 channel_topic=Spawned\\sby\\sautochannel channel_flag_permanent=0 channel_codec_quality=10\n".to_string()]
+        );
+
+        mock.push_reply(&["error id=771 msg=channel\\sname\\sis\\salready\\sin\\suse\n"]);
+        assert!(conn.channel_create("Alice's Channel", &[]).is_err());
+    }
+
+    #[test]
+    fn test_server_error_msg() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&[
+            "error id=2568 msg=insufficient\\sclient\\spermissions failed_permid=12345\n\r",
+        ]);
+        mock.push_reply(&[
+            "error id=1538 msg=invalid\\sparameter extra_msg=channel\\sname\\stoo\\slong\n\r",
+        ]);
+        assert!(matches!(
+            conn.channel_edit(42, &[("channel_order", "1")]),
+            Err(QueryError::ServerError { id: 2568, ref msg }) if msg == "insufficient client permissions"
+        ));
+        assert!(matches!(
+            conn.channel_edit(42, &[("channel_name", "x")]),
+            Err(QueryError::ServerError { id: 1538, ref msg }) if msg == "invalid parameter (channel name too long)"
+        ));
+    }
+
+    #[test]
+    fn test_channel_edit() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=768 msg=invalid\\schannelID\n\r"]);
+        assert!(conn
+            .channel_edit(43, &[("channel_order", "42")])
+            .unwrap()
+            .is_ok());
+        assert!(matches!(
+            conn.channel_edit(99, &[("channel_order", "42")]),
+            Err(QueryError::ServerError { id: 768, .. })
+        ));
+        assert_eq!(
+            mock.written(),
+            vec![
+                "channeledit cid=43 channel_order=42\n".to_string(),
+                "channeledit cid=99 channel_order=42\n".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_channel_delete() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=772 msg=channel\\snot\\sempty\n\r"]);
+        mock.push_reply(&["error id=768 msg=invalid\\schannelID\n\r"]);
+        assert!(conn.channel_delete(42, true).unwrap().is_ok());
+        assert!(matches!(
+            conn.channel_delete(43, false),
+            Err(QueryError::ChannelNotEmpty(43))
+        ));
+        assert!(matches!(
+            conn.channel_delete(44, false),
+            Err(QueryError::ServerError { id: 768, .. })
+        ));
+        assert_eq!(
+            mock.written(),
+            vec![
+                "channeldelete cid=42 force=1\n".to_string(),
+                "channeldelete cid=43 force=0\n".to_string(),
+                "channeldelete cid=44 force=0\n".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let (mut conn, mock) = mock_conn();
+        conn.set_rate_limit(Some(RateLimiter::new(2, Duration::from_millis(200))));
+        for _ in 0..4 {
+            mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        }
+        let start = Instant::now();
+        conn.keepalive(Duration::ZERO, Instant::now()).unwrap();
+        conn.keepalive(Duration::ZERO, Instant::now()).unwrap();
+        assert!(start.elapsed() < Duration::from_millis(100));
+        conn.keepalive(Duration::ZERO, Instant::now()).unwrap();
+        conn.keepalive(Duration::ZERO, Instant::now()).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert_eq!(mock.written().len(), 4);
+    }
+
+    #[test]
+    fn test_server_info() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&["virtualserver_name=TeamSpeak\\s]I[\\sServer virtualserver_antiflood_points_tick_reduce=5\n\rerror id=0 msg=ok\n\r"]);
+        mock.push_reply(&["serverinstance_serverquery_flood_commands=50 serverinstance_serverquery_flood_time=3\n\rerror id=0 msg=ok\n\r"]);
+        let info = conn.server_info().unwrap();
+        assert_eq!(info["virtualserver_name"], "TeamSpeak ]I[ Server");
+        let info = conn.instance_info().unwrap();
+        let limiter = RateLimiter::from_flood_settings(&info).unwrap();
+        assert_eq!(limiter.commands(), 40);
+        assert_eq!(limiter.interval(), Duration::from_secs(3));
+        assert_eq!(
+            mock.written(),
+            vec!["serverinfo\n".to_string(), "instanceinfo\n".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_client_update() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        assert!(conn
+            .client_update(&[
+                ("client_away", "1"),
+                ("client_away_message", "Watching channels")
+            ])
+            .unwrap()
+            .is_ok());
+        assert_eq!(
+            mock.written(),
+            vec![
+                "clientupdate client_away=1 client_away_message=Watching\\schannels\n".to_string()
+            ]
+        );
+        assert!(matches!(
+            conn.client_update(&[]),
+            Err(QueryError::InvalidArgument(_))
+        ));
+        assert_eq!(mock.written().len(), 1);
+    }
+
+    #[test]
+    fn test_set_nickname() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        assert_eq!(conn.set_nickname("Auto Channel").unwrap(), "Auto Channel");
+
+        mock.push_reply(&["error id=513 msg=nickname\\sis\\salready\\sin\\suse\n\r"]);
+        mock.push_reply(&["error id=513 msg=nickname\\sis\\salready\\sin\\suse\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        assert_eq!(conn.set_nickname("Auto Channel").unwrap(), "Auto Channel2");
+        assert_eq!(
+            mock.written(),
+            vec![
+                "clientupdate client_nickname=Auto\\sChannel\n".to_string(),
+                "clientupdate client_nickname=Auto\\sChannel\n".to_string(),
+                "clientupdate client_nickname=Auto\\sChannel1\n".to_string(),
+                "clientupdate client_nickname=Auto\\sChannel2\n".to_string(),
+            ]
+        );
+
+        for _ in 0..10 {
+            mock.push_reply(&["error id=513 msg=nickname\\sis\\salready\\sin\\suse\n\r"]);
+        }
+        assert!(matches!(
+            conn.set_nickname("Bot"),
+            Err(QueryError::ServerError { id: 513, .. })
+        ));
+        assert_eq!(mock.written().len(), 14);
+    }
+
+    #[test]
+    fn test_line_endings() {
+        let (status, fields) = TelnetConn::parse_reply(
+            b"\r\nvirtualserver_name=Test\r\nerror id=0 msg=ok\r\n"
+                .to_vec()
+                .into_boxed_slice(),
+        )
+        .unwrap();
+        assert!(status.is_ok());
+        assert_eq!(fields["virtualserver_name"], "Test");
+        assert_eq!(fields.len(), 1);
+
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&["cid=42\r\nerror id=0 msg=ok\r\n"]);
+        mock.push_reply(&["cid=1 channel_name=Lobby|cid=2 channel_name=AFK\rerror id=0 msg=ok\r"]);
+        mock.push_reply(&["error id=768 msg=invalid\\schannelID\r\n"]);
+        assert_eq!(conn.channel_create("Bob", &[]).unwrap(), 42);
+        let (_, channels) = conn
+            .write_and_parse_list("channellist\n", Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(channels[1]["channel_name"], "AFK");
+        assert!(matches!(
+            conn.channel_delete(7, false),
+            Err(QueryError::ServerError { id: 768, ref msg }) if msg == "invalid channelID"
+        ));
+    }
+
+    #[test]
+    fn test_max_response_bytes() {
+        let (mut conn, mock) = mock_conn();
+        conn.set_max_response_bytes(64);
+        let record = "cid=1 channel_name=Lobby|".repeat(10);
+        mock.push_reply(&[&record, &record, "\n\rerror id=0 msg=ok\n\r"]);
+        let e = conn
+            .write_and_read("channellist\n", Duration::from_secs(1))
+            .unwrap_err();
+        assert!(e.to_string().contains("exceeds 64 bytes"));
+
+        // Many short lines add up as well
+        let (mut conn, mock) = mock_conn();
+        conn.set_max_response_bytes(64);
+        mock.push_reply(&[&"cid=1\n\r".repeat(20), "error id=0 msg=ok\n\r"]);
+        assert!(matches!(
+            conn.write_and_read("channellist\n", Duration::from_secs(1)),
+            Err(QueryError::Protocol(_))
+        ));
+
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&[&record, &record, "\n\rerror id=0 msg=ok\n\r"]);
+        assert!(conn
+            .write_and_read("channellist\n", Duration::from_secs(1))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_ping() {
+        let (mut conn, mock) = mock_conn();
+        assert!(conn.last_ping().is_none());
+        assert!(conn.last_reply().is_none());
+        mock.push_reply(&[
+            "version=3.13.6 build=1623234157 platform=Linux\n\rerror id=0 msg=ok\n\r",
+        ]);
+        let latency = conn.ping().unwrap();
+        assert!(latency < Duration::from_secs(1));
+        assert_eq!(conn.last_ping().unwrap().1, latency);
+        let replied = conn.last_reply().unwrap();
+
+        // Nobody answers
+        conn.set_timeout(Duration::from_millis(50));
+        assert!(matches!(conn.ping(), Err(QueryError::Timeout)));
+        assert_eq!(conn.last_ping().unwrap().1, latency);
+        assert_eq!(conn.last_reply(), Some(replied));
+    }
+
+    #[test]
+    fn test_flood_retry() {
+        let (mut conn, mock) = mock_conn();
+        conn.set_flood_cooldown(Duration::from_millis(100));
+        mock.push_reply(&["error id=524 msg=client\\sis\\sflooding\n\r"]);
+        mock.push_reply(&["cid=42\n\rerror id=0 msg=ok\n\r"]);
+        let start = Instant::now();
+        assert_eq!(conn.channel_create("Bob", &[]).unwrap(), 42);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(
+            mock.written(),
+            vec![
+                "channelcreate channel_name=Bob\n".to_string(),
+                "channelcreate channel_name=Bob\n".to_string(),
+            ]
+        );
+
+        // Only retried once
+        mock.push_reply(&["error id=524 msg=client\\sis\\sflooding\n\r"]);
+        mock.push_reply(&["error id=524 msg=client\\sis\\sflooding\n\r"]);
+        assert!(matches!(
+            conn.channel_create("Bob", &[]),
+            Err(QueryError::ServerError { id: 524, .. })
+        ));
+        assert_eq!(mock.written().len(), 4);
+    }
+
+    #[test]
+    fn test_write_and_parse_list() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&[
+            "cid=1 channel_name=Lobby|cid=2 channel_name=Talk\\s1\n\rerror id=0 msg=ok\n\r",
+        ]);
+        let (status, channels) = conn
+            .write_and_parse_list("channellist\n", Duration::from_secs(1))
+            .unwrap();
+        assert!(status.is_ok());
+        assert_eq!(channels.len(), 2);
+        assert_eq!(channels[1]["channel_name"], "Talk 1");
+    }
+
+    #[test]
+    fn test_channel_create_codec() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&["cid=42\n\rerror id=0 msg=ok\n\r"]);
+        let cid = conn
+            .channel_create(
+                "Alice's Channel",
+                &[("channel_codec", "4"), ("channel_codec_quality", "10")],
+            )
+            .unwrap();
+        assert_eq!(cid, 42);
+        assert_eq!(
+            mock.written(),
+            vec!["channelcreate channel_name=Alice's\\sChannel channel_codec=4 channel_codec_quality=10\n".to_string()]
+        );
+
+        for properties in [
+            [("channel_codec", "4"), ("channel_codec_quality", "11")],
+            [("channel_codec", "6"), ("channel_codec_quality", "5")],
+            [("channel_codec", "4"), ("channel_codec_quality", "-1")],
+        ] {
+            match conn.channel_create("Alice's Channel", &properties) {
+                Err(QueryError::InvalidArgument(msg)) => assert!(msg.contains("must be between")),
+                result => panic!("Unexpected result: {:?}", result),
+            }
+        }
+        assert_eq!(mock.written().len(), 1);
+    }
+
+    #[test]
+    fn test_client_kick() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        assert!(conn
+            .client_kick(7, KickReason::Channel, "Go away")
+            .unwrap()
+            .is_ok());
+        assert!(conn
+            .client_kick(8, KickReason::Server, "Spam|ads/links")
+            .unwrap()
+            .is_ok());
+        assert_eq!(
+            mock.written(),
+            vec![
+                "clientkick clid=7 reasonid=4 reasonmsg=Go\\saway\n".to_string(),
+                "clientkick clid=8 reasonid=5 reasonmsg=Spam\\pads\\/links\n".to_string(),
+            ]
+        );
+
+        mock.push_reply(&["error id=512 msg=invalid\\sclientID\n\r"]);
+        assert!(matches!(
+            conn.client_kick(7, KickReason::Server, ""),
+            Err(QueryError::ServerError { id: 512, .. })
+        ));
+        mock.push_reply(&["error id=768 msg=invalid\\schannelID\n\r"]);
+        assert!(matches!(
+            conn.client_kick(7, KickReason::Channel, ""),
+            Err(QueryError::ServerError { id: 768, .. })
+        ));
+    }
+
+    #[test]
+    fn test_client_move() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        assert!(conn.client_move(7, 42).unwrap().is_ok());
+        assert_eq!(
+            mock.written(),
+            vec!["clientmove clid=7 cid=42\n".to_string()]
+        );
+
+        mock.push_reply(&["error id=512 msg=invalid\\sclientID\n\r"]);
+        match conn.client_move(7, 42).unwrap_err() {
+            QueryError::ServerError { id, msg } => {
+                assert_eq!(id, 512);
+                assert_eq!(msg, "invalid clientID");
+            }
+            e => panic!("Unexpected error: {:?}", e),
+        }
+
+        mock.push_reply(&["error id=768 msg=invalid\\schannelID\n\r"]);
+        assert!(matches!(
+            conn.client_move(7, 42),
+            Err(QueryError::ServerError { id: 768, .. })
+        ));
+
+        mock.push_reply(&["error id=770 msg=already\\smember\\sof\\schannel\n\r"]);
+        assert!(matches!(
+            conn.client_move(7, 42),
+            Err(QueryError::ServerError { id: 770, .. })
+        ));
+    }
+
+    #[test]
+    fn test_login() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        assert!(conn.login("serveradmin", "password").unwrap().is_ok());
+
+        mock.push_reply(&["error id=520 msg=invalid\\sloginname\\sor\\spassword\n\r"]);
+        match conn.login("serveradmin", "wrong").unwrap_err() {
+            QueryError::ServerError { id, msg } => {
+                assert_eq!(id, 520);
+                assert_eq!(msg, "invalid loginname or password");
+            }
+            e => panic!("Unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_login_already_authenticated() {
+        // The SSH handshake authenticated the session already
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&["error id=3331 msg=already\\slogged\\sin\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        assert!(conn.login("serveradmin", "password").unwrap().is_ok());
+        assert!(conn
+            .select_virtual_server(VirtualServer::Id(1))
+            .unwrap()
+            .is_ok());
+        assert_eq!(
+            mock.written(),
+            vec![
+                "login serveradmin password\n".to_string(),
+                "use 1\n".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_login_escape() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        assert!(conn.login("query admin", "p a|ss\\wo/rd").unwrap().is_ok());
+        assert_eq!(
+            mock.written(),
+            vec!["login query\\sadmin p\\sa\\pss\\\\wo\\/rd\n".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_login_select_create_move() {
+        let mock = MockTransport::default();
+        mock.push_read(BANNER);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        mock.push_reply(&["cid=42\n\r", "error id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+
+        let mut conn = TelnetConn::from_transport(Box::new(mock.clone()), DEFAULT_TIMEOUT).unwrap();
+        assert!(conn.login("serveradmin", "pass word").unwrap().is_ok());
+        assert!(conn
+            .select_virtual_server(VirtualServer::Id(1))
+            .unwrap()
+            .is_ok());
+        let cid = conn
+            .channel_create("Alice's Channel", &[("cpid", "1")])
+            .unwrap();
+        assert!(conn.client_move(5, cid).unwrap().is_ok());
+
+        assert_eq!(
+            mock.written(),
+            vec![
+                "login serveradmin pass\\sword\n".to_string(),
+                "use 1\n".to_string(),
+                "channelcreate channel_name=Alice's\\sChannel cpid=1\n".to_string(),
+                "clientmove clid=5 cid=42\n".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_whoami() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&[
+            "virtualserver_status=online virtualserver_id=1 virtualserver_unique_identifier=gvhQ8jrDpqb6sNHOKaZmmEufnDA= virtualserver_port=9987 client_id=3 client_channel_id=5 client_nickname=serveradmin client_database_id=1 client_login_name=serveradmin client_unique_identifier=serveradmin client_origin_server_id=0\n\r",
+            "error id=0 msg=ok\n\r",
+        ]);
+        let whoami = conn.whoami().unwrap();
+        assert_eq!(whoami.client_id(), 3);
+        assert_eq!(whoami.channel_id(), 5);
+        assert_eq!(whoami.virtualserver_port(), 9987);
+        assert_eq!(mock.written(), vec!["whoami\n".to_string()]);
+    }
+
+    #[test]
+    fn test_logout() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        assert!(conn.logout().unwrap().is_ok());
+        conn.quit().unwrap();
+        drop(conn);
+        assert_eq!(
+            mock.written(),
+            vec!["logout\n".to_string(), "quit\n".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_quit_on_drop() {
+        let (conn, mock) = mock_conn();
+        drop(conn);
+        assert_eq!(mock.written(), vec!["quit\n".to_string()]);
+    }
+
+    #[test]
+    fn test_select_server() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        assert!(conn
+            .select_virtual_server(VirtualServer::Id(2))
+            .unwrap()
+            .is_ok());
+        assert!(conn
+            .select_virtual_server(VirtualServer::Port(9987))
+            .unwrap()
+            .is_ok());
+        assert_eq!(
+            mock.written(),
+            vec!["use 2\n".to_string(), "use port=9987\n".to_string()]
+        );
+
+        mock.push_reply(&["error id=1024 msg=invalid\\sserverID\n\r"]);
+        assert!(matches!(
+            conn.select_server_by_port(1),
+            Err(QueryError::ServerError { id: 1024, .. })
+        ));
+    }
+
+    #[test]
+    fn test_select_stopped_server() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&["error id=1033 msg=server\\sis\\snot\\srunning\n\r"]);
+        let err = conn.select_server(2).unwrap_err();
+        assert!(matches!(err, QueryError::ServerNotRunning(ref server) if server == "2"));
+        assert_eq!(
+            err.to_string(),
+            "Virtual server 2 is not running, start it with serverstart or --autostart-server"
+        );
+        assert_eq!(mock.written(), vec!["use 2\n".to_string()]);
+    }
+
+    #[test]
+    fn test_autostart_server() {
+        let (mut conn, mock) = mock_conn();
+        conn.set_autostart_server(true);
+        mock.push_reply(&["error id=1033 msg=server\\sis\\snot\\srunning\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        assert!(conn.select_server(2).unwrap().is_ok());
+
+        mock.push_reply(&["error id=1033 msg=server\\sis\\snot\\srunning\n\r"]);
+        mock.push_reply(&["server_id=3\n\rerror id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        assert!(conn
+            .select_virtual_server(VirtualServer::Port(9988))
+            .unwrap()
+            .is_ok());
+        assert_eq!(
+            mock.written(),
+            vec![
+                "use 2\n".to_string(),
+                "serverstart sid=2\n".to_string(),
+                "use 2\n".to_string(),
+                "use port=9988\n".to_string(),
+                "serveridgetbyport virtualserver_port=9988\n".to_string(),
+                "serverstart sid=3\n".to_string(),
+                "use port=9988\n".to_string(),
+            ]
+        );
+
+        // Still stopped after serverstart, e.g. a suspended license
+        mock.push_reply(&["error id=1033 msg=server\\sis\\snot\\srunning\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=1033 msg=server\\sis\\snot\\srunning\n\r"]);
+        assert!(matches!(
+            conn.select_server(2),
+            Err(QueryError::ServerError { id: 1033, .. })
+        ));
+    }
+
+    #[test]
+    fn test_channel_list() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&["cid=1 pid=0 channel_order=0 channel_name=Default\\sChannel total_clients=2 channel_needed_subscribe_power=0|cid=5 pid=1 channel_order=1 channel_name=Lobby total_clients=0 channel_needed_subscribe_power=0\n\rerror id=0 msg=ok\n\r"]);
+        let channels = conn.channel_list(ChannelListFlags::default()).unwrap();
+        assert_eq!(channels.len(), 2);
+        assert_eq!(channels[0].cid(), 1);
+        assert_eq!(channels[0].channel_name(), "Default Channel");
+        assert_eq!(channels[0].total_clients(), 2);
+        assert_eq!(channels[1].pid(), 1);
+        assert_eq!(channels[1].channel_flag_permanent(), None);
+        assert_eq!(channels[1].channel_topic(), None);
+
+        let mut flags = ChannelListFlags::default();
+        flags.set_topic(true);
+        flags.set_flags(true);
+        mock.push_reply(&["cid=1 pid=0 channel_order=0 channel_name=Default\\sChannel channel_topic=Welcome\\s:) channel_flag_default=1 channel_flag_password=0 channel_flag_permanent=1 channel_flag_semi_permanent=0 total_clients=2 channel_needed_subscribe_power=0|cid=9 pid=5 channel_order=0 channel_name=Room\\s1 channel_topic channel_flag_default=0 channel_flag_password=0 channel_flag_permanent=0 channel_flag_semi_permanent=0 total_clients=1 channel_needed_subscribe_power=0\n\rerror id=0 msg=ok\n\r"]);
+        let channels = conn.channel_list(flags).unwrap();
+        assert_eq!(channels[0].channel_flag_permanent(), Some(true));
+        assert_eq!(channels[0].channel_flag_default(), Some(true));
+        assert_eq!(channels[0].channel_topic(), Some("Welcome :)"));
+        assert_eq!(channels[1].channel_flag_permanent(), Some(false));
+        assert_eq!(channels[1].channel_topic(), Some(""));
+        assert_eq!(channels[1].total_clients(), 1);
+        assert_eq!(
+            mock.written(),
+            vec![
+                "channellist\n".to_string(),
+                "channellist -topic -flags\n".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_client_list() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&["clid=1 cid=1 client_database_id=1 client_nickname=serveradmin client_type=1|clid=7 cid=5 client_database_id=23 client_nickname=Alice\\sB client_type=0\n\rerror id=0 msg=ok\n\r"]);
+        let clients = conn.client_list(ClientListFlags::default()).unwrap();
+        assert_eq!(clients.len(), 2);
+        assert!(clients[0].is_query());
+        assert_eq!(clients[1].clid(), 7);
+        assert_eq!(clients[1].cid(), 5);
+        assert_eq!(clients[1].client_nickname(), "Alice B");
+        assert!(!clients[1].is_query());
+        assert_eq!(clients[1].client_unique_identifier(), "");
+
+        let mut flags = ClientListFlags::default();
+        flags.set_uid(true);
+        mock.push_reply(&["clid=1 cid=1 client_database_id=1 client_nickname=serveradmin client_type=1 client_unique_identifier=serveradmin|clid=7 cid=5 client_database_id=23 client_nickname=Alice\\sB client_type=0 client_unique_identifier=P5H2hrN6+gpQI4n\\/dXp3p17vtY0=\n\rerror id=0 msg=ok\n\r"]);
+        let clients = conn.client_list(flags).unwrap();
+        let people: Vec<_> = clients.iter().filter(|client| !client.is_query()).collect();
+        assert_eq!(people.len(), 1);
+        assert_eq!(
+            people[0].client_unique_identifier(),
+            "P5H2hrN6+gpQI4n/dXp3p17vtY0="
+        );
+        assert_eq!(clients[0].client_unique_identifier(), "serveradmin");
+        assert_eq!(
+            mock.written(),
+            vec!["clientlist\n".to_string(), "clientlist -uid\n".to_string()]
+        );
+    }
+
+    pub const CHANNEL_GROUP_LIST: &str = "cgid=1 name=Channel\\sAdmin type=0 iconid=100 savedb=1 sortid=0 namemode=0 n_modifyp=75 n_member_addp=0 n_member_removep=0|cgid=5 name=Channel\\sAdmin type=1 iconid=100 savedb=1 sortid=0 namemode=0 n_modifyp=75 n_member_addp=0 n_member_removep=0|cgid=6 name=Operator type=1 iconid=200 savedb=1 sortid=0 namemode=0 n_modifyp=75 n_member_addp=0 n_member_removep=0|cgid=8 name=Guest type=1 iconid=0 savedb=0 sortid=0 namemode=0 n_modifyp=75 n_member_addp=0 n_member_removep=0\n\rerror id=0 msg=ok\n\r";
+
+    #[test]
+    fn test_group_lists() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&["sgid=1 name=Guest\\sServer\\sQuery type=2 iconid=0 savedb=0 sortid=0 namemode=0 n_modifyp=0 n_member_addp=0 n_member_removep=0|sgid=6 name=Server\\sAdmin type=1 iconid=300 savedb=1 sortid=0 namemode=0 n_modifyp=75 n_member_addp=0 n_member_removep=0|sgid=7 name=Normal type=1 iconid=0 savedb=1 sortid=0 namemode=0 n_modifyp=75 n_member_addp=0 n_member_removep=0\n\rerror id=0 msg=ok\n\r"]);
+        let groups = conn.server_group_list().unwrap();
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups["Server Admin"], 6);
+        assert_eq!(groups["Normal"], 7);
+
+        mock.push_reply(&[CHANNEL_GROUP_LIST]);
+        let groups = conn.channel_group_list().unwrap();
+        // The template of the same name is ignored
+        assert_eq!(groups["Channel Admin"], 5);
+        assert_eq!(groups["Guest"], 8);
+        assert_eq!(groups.len(), 3);
+        assert_eq!(
+            mock.written(),
+            vec![
+                "servergrouplist\n".to_string(),
+                "channelgrouplist\n".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_server_list() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&["virtualserver_id=1 virtualserver_port=9987 virtualserver_status=online virtualserver_clientsonline=3 virtualserver_queryclientsonline=1 virtualserver_maxclients=32 virtualserver_name=Main|virtualserver_id=4 virtualserver_port=9990 virtualserver_status=online\\svirtual virtualserver_clientsonline=0 virtualserver_maxclients=32 virtualserver_name=Staging\n\rerror id=0 msg=ok\n\r"]);
+        let servers = conn.server_list().unwrap();
+        assert_eq!(servers.len(), 2);
+        assert_eq!(servers[1].virtualserver_id(), 4);
+        assert_eq!(servers[1].virtualserver_port(), 9990);
+        assert_eq!(servers[1].virtualserver_status(), &ServerStatus::Virtual);
+        assert_eq!(servers[1].virtualserver_clientsonline(), Some(0));
+        assert_eq!(mock.written(), vec!["serverlist\n".to_string()]);
+    }
+
+    #[test]
+    fn test_raw_command() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&[
+            "version=3.13.7 build=1655727713 platform=Linux\n\rerror id=0 msg=ok\n\r",
+        ]);
+        let response = conn.raw_command("version").unwrap();
+        assert!(response.status().is_ok());
+        assert_eq!(
+            response.lines(),
+            ["version=3.13.7 build=1655727713 platform=Linux".to_string()]
+        );
+
+        mock.push_reply(&["error id=256 msg=command\\snot\\sfound\n\r"]);
+        let response = conn.raw_command("bogus").unwrap();
+        assert_eq!(response.status().id(), 256);
+        assert!(response.lines().is_empty());
+
+        assert!(matches!(
+            conn.raw_command("version\nquit"),
+            Err(QueryError::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            conn.raw_command("version\rquit"),
+            Err(QueryError::InvalidArgument(_))
+        ));
+        assert_eq!(
+            mock.written(),
+            vec!["version\n".to_string(), "bogus\n".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_server_version() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&[
+            "version=3.13.7 build=1655727713 platform=Linux\n\rerror id=0 msg=ok\n\r",
+        ]);
+        let version = conn.server_version().unwrap();
+        assert_eq!(version.version(), "3.13.7");
+        assert_eq!(version.build(), 1655727713);
+        assert_eq!(mock.written(), vec!["version\n".to_string()]);
+    }
+
+    #[test]
+    fn test_client_info() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&[
+            "cid=12 client_idle_time=2571 client_unique_identifier=abc= client_nickname=Alice client_database_id=23 client_type=0\n\r",
+            "error id=0 msg=ok\n\r",
+        ]);
+        let info = conn.client_info(5).unwrap();
+        assert_eq!(info.cid(), 12);
+        assert_eq!(info.client_database_id(), 23);
+        assert_eq!(mock.written(), vec!["clientinfo clid=5\n".to_string()]);
+
+        mock.push_reply(&["error id=512 msg=invalid\\sclientID\n\r"]);
+        assert!(matches!(
+            conn.client_info(99),
+            Err(QueryError::ServerError { id: 512, .. })
+        ));
+    }
+
+    #[test]
+    fn test_set_client_channel_group() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&["cluid=abc\\/def= cldbid=4\n\rerror id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        assert_eq!(conn.client_dbid_from_uid("abc/def=").unwrap(), 4);
+        assert!(conn.set_client_channel_group(5, 42, 4).unwrap().is_ok());
+        assert_eq!(
+            mock.written(),
+            vec![
+                "clientgetdbidfromuid cluid=abc\\/def=\n".to_string(),
+                "setclientchannelgroup cgid=5 cid=42 cldbid=4\n".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_send_text_message() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        let msg = "Your channel was created\nHave fun";
+        assert!(conn
+            .send_text_message(TargetMode::Client, 5, msg)
+            .unwrap()
+            .is_ok());
+        assert!(conn
+            .send_text_message(TargetMode::Server, 1, "Bye all")
+            .unwrap()
+            .is_ok());
+        assert!(conn.poke_client(5, msg).unwrap().is_ok());
+        assert_eq!(
+            mock.written(),
+            vec![
+                "sendtextmessage targetmode=1 target=5 msg=Your\\schannel\\swas\\screated\\nHave\\sfun\n".to_string(),
+                "sendtextmessage targetmode=3 target=1 msg=Bye\\sall\n".to_string(),
+                "clientpoke clid=5 msg=Your\\schannel\\swas\\screated\\nHave\\sfun\n".to_string(),
+            ]
+        );
+
+        mock.push_reply(&["error id=512 msg=invalid\\sclientID\n\r"]);
+        assert!(matches!(
+            conn.poke_client(9, "hi"),
+            Err(QueryError::ServerError { id: 512, .. })
+        ));
+    }
+
+    #[test]
+    fn test_pipeline() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&[
+            "error id=0 msg=ok\n\rerror id=770 msg=already\\smember\\sof\\schannel\n\r",
+            "virtualserver_status=online virtualserver_id=1 client_id=3 client_channel_id=5\n\r",
+            "error id=0 msg=ok\n\r",
+        ]);
+        let mut pipeline = conn.pipeline();
+        pipeline
+            .push("use 1\n")
+            .push(&build_command("clientmove", &[("clid", "7"), ("cid", "5")]))
+            .push("whoami\n");
+        let replies = pipeline.send().unwrap();
+
+        assert_eq!(
+            mock.written(),
+            vec!["use 1\nclientmove clid=7 cid=5\nwhoami\n".to_string()]
+        );
+        assert_eq!(replies.len(), 3);
+        assert!(replies[0].as_ref().unwrap().0.is_ok());
+        assert!(matches!(
+            replies[1],
+            Err(QueryError::ServerError { id: 770, .. })
+        ));
+        let (status, whoami) = replies[2].as_ref().unwrap();
+        assert!(status.is_ok());
+        assert_eq!(whoami["client_id"], "3");
+
+        assert!(conn.pipeline().send().unwrap().is_empty());
+        assert_eq!(mock.written().len(), 1);
+    }
+
+    #[test]
+    fn test_builder() {
+        let mock = MockTransport::default();
+        mock.push_read(BANNER);
+        let mut conn = TelnetConn::builder("127.0.0.1", 10011)
+            .timeout(Duration::from_millis(500))
+            .max_response_bytes(64)
+            .dry_run(true)
+            .from_transport(Box::new(mock.clone()))
+            .unwrap();
+        assert_eq!(conn.timeout(), Duration::from_millis(500));
+        assert_eq!(
+            conn.channel_create("Alice's Channel", &[("cpid", "1")])
+                .unwrap(),
+            -1
+        );
+        assert!(mock.written().is_empty());
+        mock.push_reply(&[&"cid=1\n\r".repeat(20), "error id=0 msg=ok\n\r"]);
+        assert!(matches!(
+            conn.write_and_read("channellist\n", Duration::from_secs(1)),
+            Err(QueryError::Protocol(_))
+        ));
+
+        let mock = MockTransport::default();
+        mock.push_read(BANNER);
+        let mut conn = TelnetConn::builder("127.0.0.1", 10011)
+            .rate_limit(Some(RateLimiter::new(1, Duration::from_millis(200))))
+            .from_transport(Box::new(mock.clone()))
+            .unwrap();
+        assert_eq!(conn.timeout(), DEFAULT_TIMEOUT);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        let started = Instant::now();
+        conn.write_and_read("whoami\n", DEFAULT_TIMEOUT).unwrap();
+        conn.write_and_read("whoami\n", DEFAULT_TIMEOUT).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(150));
+    }
+
+    #[test]
+    fn test_builder_validate() {
+        let builder = TelnetConn::builder("127.0.0.1", 10011);
+        assert!(builder.validate().is_ok());
+        assert!(builder
+            .clone()
+            .tls(true)
+            .tls_insecure(true)
+            .validate()
+            .is_ok());
+        for builder in [
+            builder.clone().ssh("serveradmin", "secret").tls(true),
+            builder.clone().tls_insecure(true),
+            builder
+                .clone()
+                .tls(true)
+                .tls_ca_file(Some("ca.pem".into()))
+                .tls_insecure(true),
+            builder.clone().buffer_size(0),
+        ] {
+            // Rejected before connecting to the closed port
+            assert!(matches!(
+                builder.connect(),
+                Err(QueryError::InvalidArgument(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let (mut conn, mock) = mock_conn();
+        conn.set_dry_run(true);
+        assert_eq!(
+            conn.channel_create("Alice's Channel", &[("cpid", "1")])
+                .unwrap(),
+            -1
+        );
+        assert_eq!(
+            conn.channel_create("Bob's Channel", &[("cpid", "1")])
+                .unwrap(),
+            -2
+        );
+        assert!(conn.client_move(5, -1).unwrap().is_ok());
+        assert!(conn.set_client_channel_group(5, -1, 4).unwrap().is_ok());
+        assert!(conn.channel_delete(-1, true).unwrap().is_ok());
+        assert!(mock.written().is_empty());
+
+        mock.push_reply(&[
+            "virtualserver_status=online virtualserver_id=1 virtualserver_port=9987 client_id=3 client_channel_id=5\n\r",
+            "error id=0 msg=ok\n\r",
+        ]);
+        mock.push_reply(&["clid=3 cid=5 client_database_id=1 client_nickname=serveradmin client_type=1\n\rerror id=0 msg=ok\n\r"]);
+        assert_eq!(conn.whoami().unwrap().client_id(), 3);
+        assert!(conn.write_and_read("clientlist\n", DEFAULT_TIMEOUT).is_ok());
+        assert_eq!(
+            mock.written(),
+            vec!["whoami\n".to_string(), "clientlist\n".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_notify_register() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        assert!(conn.notify_register("channel", Some(0)).unwrap().is_ok());
+        assert!(conn.notify_register("textprivate", None).unwrap().is_ok());
+        assert!(conn.notify_unregister().unwrap().is_ok());
+        assert_eq!(
+            mock.written(),
+            vec![
+                "servernotifyregister event=channel id=0\n".to_string(),
+                "servernotifyregister event=textprivate\n".to_string(),
+                "servernotifyunregister\n".to_string(),
+            ]
+        );
+        assert!(conn.notify_register("unknown", None).is_err());
+    }
+
+    #[test]
+    fn test_notify_register_already_registered() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&["error id=1541 msg=already\\sregistered\n\r"]);
+        let status = conn.notify_register("server", None).unwrap();
+        assert_eq!(status.id(), 1541);
+
+        mock.push_reply(&["error id=2568 msg=insufficient\\sclient\\spermissions\n\r"]);
+        assert!(conn.notify_register("server", None).is_err());
+    }
+
+    #[test]
+    fn test_poll_event() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_read("notifycliententerview cfid=0 ctid=1 reasonid=0 clid=5 client_nickname=Alice\n\rnotifyclientmo");
+        mock.push_read("ved ctid=42 reasonid=1 clid=5\n\r");
+
+        let event = conn.poll_event(1).unwrap().unwrap();
+        assert_eq!(event.name(), "notifycliententerview");
+        assert_eq!(event.get("client_nickname"), Some("Alice"));
+        let event = conn.poll_event(1).unwrap().unwrap();
+        assert_eq!(event.name(), "notifyclientmoved");
+        assert_eq!(event.get("ctid"), Some("42"));
+        assert!(conn.poll_event(1).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_notification_during_response() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&[
+            "notifyclientleftview cfid=42 ctid=0 reasonid=8 clid=5\n\r",
+            "cid=42\n\rerror id=0 msg=ok\n\r",
+        ]);
+        let (_, result) = conn
+            .write_and_parse("channelcreate channel_name=a\n", Duration::from_secs(2))
+            .unwrap();
+        assert_eq!(result["cid"], "42");
+
+        let event = conn.poll_event(1).unwrap().unwrap();
+        assert_eq!(event.name(), "notifyclientleftview");
+    }
+
+    #[test]
+    fn test_response_routed_from_poll_event() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_read("version=3.13.7 build=1655727713 platform=Linux\n\rerror id=0 msg=ok\n\r");
+        assert!(conn.poll_event(1).unwrap().is_none());
+        let (_, result) = conn
+            .write_and_parse("version\n", Duration::from_secs(2))
+            .unwrap();
+        assert_eq!(result["version"], "3.13.7");
+    }
+
+    #[test]
+    fn test_keepalive() {
+        let (mut conn, mock) = mock_conn();
+        let interval = Duration::from_secs(60);
+        let start = Instant::now();
+
+        assert!(!conn.keepalive(interval, start).unwrap());
+        assert!(mock.written().is_empty());
+
+        mock.push_reply(&[
+            "version=3.13.7 build=1655727713 platform=Linux\n\rerror id=0 msg=ok\n\r",
+        ]);
+        assert!(conn.keepalive(interval, start + interval).unwrap());
+        assert_eq!(mock.written(), vec!["version\n".to_string()]);
+
+        // Traffic resets the idle timer
+        assert!(!conn
+            .keepalive(interval, Instant::now() + Duration::from_secs(30))
+            .unwrap());
+    }
+
+    #[test]
+    fn test_malformed_status_line() {
+        let (mut conn, mock) = mock_conn();
+        for reply in [
+            "error id=abc msg=ok\n\r",
+            "error msg=ok\n\r",
+            "error id=0 msg=ok failed_permid=\n\r",
+        ] {
+            mock.push_reply(&[reply]);
+            assert!(matches!(
+                conn.server_version(),
+                Err(QueryError::Protocol(ref msg)) if msg.contains("status")
+            ));
+        }
+        // Still in sync afterwards
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        assert!(conn.ping().is_ok());
+    }
+
+    #[test]
+    fn test_response_without_status_line_times_out() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&["cid=1 pid=0 channel_name=Lobby|cid=2 pid=0\n\rcid=3 pid=0 chan"]);
+        assert!(matches!(
+            conn.write_and_read("channellist\n", Duration::from_millis(100)),
+            Err(QueryError::IncompleteResponse(ref partial))
+                if partial == "cid=1 pid=0 channel_name=Lobby|cid=2 pid=0\ncid=3 pid=0 chan"
+        ));
+
+        // The rest arrives late and is not taken for the reply of the next command
+        mock.push_read("nel_name=Games\n\rerror id=0 msg=ok\n\r");
+        mock.push_reply(&[
+            "version=3.13.7 build=1655727713 platform=Linux\n\rerror id=0 msg=ok\n\r",
+        ]);
+        assert_eq!(conn.server_version().unwrap().version(), "3.13.7");
+
+        // Nothing at all arrived
+        assert!(matches!(
+            conn.write_and_read("channellist\n", Duration::from_millis(50)),
+            Err(QueryError::Timeout)
+        ));
+    }
+
+    #[test]
+    fn test_status_line_split_by_timeout() {
+        let (mut conn, mock) = mock_conn();
+        mock.push_reply(&["cid=1 pid=0 channel_name=Lobby\n\rerror id=0 ms"]);
+        assert!(matches!(
+            conn.write_and_read("channellist\n", Duration::from_millis(100)),
+            Err(QueryError::IncompleteResponse(ref partial))
+                if partial == "cid=1 pid=0 channel_name=Lobby\nerror id=0 ms"
+        ));
+
+        mock.push_read("g=ok\n\r");
+        for version in ["3.13.7", "3.13.8"] {
+            mock.push_reply(&[&format!(
+                "version={} build=1655727713 platform=Linux\n\rerror id=0 msg=ok\n\r",
+                version
+            )]);
+            assert_eq!(conn.server_version().unwrap().version(), version);
+        }
+    }
+
+    #[test]
+    fn test_slow_reply() {
+        let (mut conn, mock) = mock_conn();
+        conn.set_timeout(Duration::from_millis(100));
+
+        mock.set_delay(Duration::from_millis(20));
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        assert!(conn.select_server(1).unwrap().is_ok());
+
+        mock.set_delay(Duration::from_millis(300));
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        let status = conn
+            .with_timeout(Duration::from_secs(1), |conn| {
+                conn.login("serveradmin", "password")
+            })
+            .unwrap();
+        assert!(status.is_ok());
+        assert_eq!(conn.timeout(), Duration::from_millis(100));
+
+        mock.push_reply(&["error id=0 msg=ok\n\r"]);
+        assert!(matches!(conn.select_server(1), Err(QueryError::Timeout)));
+    }
+
+    /// Host and serveradmin password of the server the live tests run against,
+    /// `None` skips them.
+    fn live_server() -> Option<(String, String)> {
+        match (std::env::var("QUERY_HOST"), std::env::var("QUERY_PASSWORD")) {
+            (Ok(host), Ok(password)) => Some((host, password)),
+            _ => {
+                eprintln!("QUERY_HOST or QUERY_PASSWORD not set, skipping");
+                None
+            }
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a live server in QUERY_HOST and QUERY_PASSWORD"]
+    fn test_connection() {
+        let (host, password) = match live_server() {
+            Some(server) => server,
+            None => return,
+        };
+        let mut conn = TelnetConn::connect(&host, 10011, DEFAULT_TIMEOUT).unwrap();
+
+        let result = conn.login("serveradmin", &password).unwrap();
+
+        assert!(result.is_ok());
+
+        let result = conn.select_server(1).unwrap();
+        assert!(result.is_ok());
+
+        // At least our own query client
+        let (status, clients) = conn.query_clients().unwrap();
+        assert!(status.is_ok());
+        assert!(!clients.is_empty());
+
+        // Every virtual server has at least its default channel
+        let (status, channels) = conn.query_channels().unwrap();
+        assert!(status.is_ok());
+        assert!(!channels.is_empty());
+    }
+
+    #[cfg(feature = "ssh")]
+    #[test]
+    #[ignore = "needs a live server in QUERY_HOST and QUERY_PASSWORD"]
+    fn test_ssh_connection() {
+        let (host, password) = match live_server() {
+            Some(server) => server,
+            None => return,
+        };
+        let mut conn = TelnetConn::connect_ssh(
+            &host,
+            10022,
+            &TcpOptions::default(),
+            "serveradmin",
+            &password,
+            DEFAULT_TIMEOUT,
+        )
+        .unwrap();
+
+        let result = conn.select_server(1).unwrap();
+        assert!(result.is_ok());
+
+        let (status, channels) = conn.query_channels().unwrap();
+        assert!(status.is_ok());
+        // Every virtual server has at least its default channel
+        assert!(!channels.is_empty());
+    }
+}
@@ -1,12 +1,30 @@
+use crate::error::{QueryError, Result};
+use std::collections::HashMap;
+use std::str::FromStr;
+
 pub trait FromQueryString {
-    fn from_query(data: &str) -> anyhow::Result<Self>
+    fn from_query(data: &str) -> Result<Self>
     where
         Self: Sized;
 }
 
+/// Parse `key` of a reply parsed with [`crate::protocol::parse_kv`].
+pub(crate) fn parse_field<T: FromStr>(fields: &HashMap<String, String>, key: &str) -> Result<T>
+where
+    T::Err: std::fmt::Debug,
+{
+    fields
+        .get(key)
+        .ok_or_else(|| QueryError::Protocol(format!("Missing field {} in reply", key)))?
+        .parse()
+        .map_err(|e| QueryError::Protocol(format!("Got error while parse {}: {:?}", key, e)))
+}
+
 pub mod channel {
-    use crate::FromQueryString;
+    use crate::datastructures::{parse_field, FromQueryString};
+    use crate::error::{QueryError, Result};
     use serde_derive::Deserialize;
+    use std::collections::HashMap;
 
     #[derive(Clone, Debug, Default, Deserialize)]
     pub struct Channel {
@@ -16,6 +34,12 @@ pub mod channel {
         channel_name: String,
         total_clients: i64,
         channel_needed_subscribe_power: i64,
+        /// Only sent with `-topic`
+        channel_topic: Option<String>,
+        /// Only sent with `-flags`
+        channel_flag_permanent: Option<bool>,
+        /// Only sent with `-flags`, set on the channel clients join on connect
+        channel_flag_default: Option<bool>,
     }
 
     impl Channel {
@@ -37,32 +61,74 @@ pub mod channel {
         pub fn channel_needed_subscribe_power(&self) -> i64 {
             self.channel_needed_subscribe_power
         }
+        pub fn channel_topic(&self) -> Option<&str> {
+            self.channel_topic.as_deref()
+        }
+        pub fn channel_flag_permanent(&self) -> Option<bool> {
+            self.channel_flag_permanent
+        }
+        pub fn channel_flag_default(&self) -> Option<bool> {
+            self.channel_flag_default
+        }
+    }
+
+    /// A `channellist` record parsed with [`crate::protocol::parse_list`].
+    impl TryFrom<&HashMap<String, String>> for Channel {
+        type Error = QueryError;
+
+        fn try_from(fields: &HashMap<String, String>) -> Result<Self, Self::Error> {
+            let optional = |key: &str| -> Result<Option<i64>> {
+                fields
+                    .get(key)
+                    .map(|_| parse_field(fields, key))
+                    .transpose()
+            };
+            Ok(Self {
+                cid: parse_field(fields, "cid")?,
+                pid: parse_field(fields, "pid")?,
+                channel_order: optional("channel_order")?.unwrap_or_default(),
+                channel_name: parse_field(fields, "channel_name")?,
+                total_clients: optional("total_clients")?.unwrap_or_default(),
+                channel_needed_subscribe_power: optional("channel_needed_subscribe_power")?
+                    .unwrap_or_default(),
+                channel_topic: fields.get("channel_topic").cloned(),
+                channel_flag_permanent: optional("channel_flag_permanent")?.map(|flag| flag != 0),
+                channel_flag_default: optional("channel_flag_default")?.map(|flag| flag != 0),
+            })
+        }
     }
 
     impl FromQueryString for Channel {
-        fn from_query(data: &str) -> anyhow::Result<Self>
+        fn from_query(data: &str) -> Result<Self>
         where
             Self: Sized,
         {
-            Ok(serde_teamspeak_querystring::from_str(data)
-                .map_err(|e| anyhow::anyhow!("Got parser error: {:?}", e))?)
+            serde_teamspeak_querystring::from_str(data)
+                .map_err(|e| QueryError::Protocol(format!("Got parser error: {:?}", e)))
         }
     }
 }
 
 pub mod client {
-    use crate::datastructures::FromQueryString;
-    use anyhow::anyhow;
+    use crate::datastructures::{parse_field, FromQueryString};
+    use crate::error::{QueryError, Result};
     use serde_derive::Deserialize;
+    use std::collections::HashMap;
 
     #[derive(Clone, Debug, Default, Deserialize)]
     pub struct Client {
         clid: i64,
         cid: i64,
         client_database_id: i64,
+        /// 1 for ServerQuery clients, 0 for voice clients
         client_type: i64,
+        /// Only sent with `-uid`, empty otherwise
         client_unique_identifier: String,
         client_nickname: String,
+        /// Only sent with `-away`
+        client_away: Option<bool>,
+        /// Milliseconds since the last activity, only sent with `-times`
+        client_idle_time: Option<u64>,
     }
 
     impl Client {
@@ -84,15 +150,55 @@ pub mod client {
         pub fn client_nickname(&self) -> &str {
             &self.client_nickname
         }
+        pub fn client_away(&self) -> Option<bool> {
+            self.client_away
+        }
+        pub fn client_idle_time(&self) -> Option<u64> {
+            self.client_idle_time
+        }
+        /// A ServerQuery client like us rather than a person.
+        pub fn is_query(&self) -> bool {
+            self.client_type == 1
+        }
+    }
+
+    /// A `clientlist` record parsed with [`crate::protocol::parse_list`].
+    impl TryFrom<&HashMap<String, String>> for Client {
+        type Error = QueryError;
+
+        fn try_from(fields: &HashMap<String, String>) -> Result<Self, Self::Error> {
+            let optional = |key: &str| -> Result<Option<i64>> {
+                fields
+                    .get(key)
+                    .map(|_| parse_field(fields, key))
+                    .transpose()
+            };
+            Ok(Self {
+                clid: parse_field(fields, "clid")?,
+                cid: parse_field(fields, "cid")?,
+                client_database_id: optional("client_database_id")?.unwrap_or_default(),
+                client_type: parse_field(fields, "client_type")?,
+                client_unique_identifier: fields
+                    .get("client_unique_identifier")
+                    .cloned()
+                    .unwrap_or_default(),
+                client_nickname: parse_field(fields, "client_nickname")?,
+                client_away: optional("client_away")?.map(|away| away != 0),
+                client_idle_time: fields
+                    .get("client_idle_time")
+                    .map(|_| parse_field(fields, "client_idle_time"))
+                    .transpose()?,
+            })
+        }
     }
 
     impl FromQueryString for Client {
-        fn from_query(data: &str) -> anyhow::Result<Self>
+        fn from_query(data: &str) -> Result<Self>
         where
             Self: Sized,
         {
-            Ok(serde_teamspeak_querystring::from_str(data)
-                .map_err(|e| anyhow::anyhow!("Got parser error: {:?}", e))?)
+            serde_teamspeak_querystring::from_str(data)
+                .map_err(|e| QueryError::Protocol(format!("Got parser error: {:?}", e)))
         }
     }
 
@@ -117,17 +223,27 @@ pub mod client {
 }
 
 pub mod query_status {
-    use anyhow::anyhow;
+    use crate::error::QueryError;
+    use crate::protocol::parse_kv;
 
     #[derive(Clone, Debug)]
     pub struct QueryStatus {
         id: i32,
         msg: String,
+        /// Additional detail some errors carry, e.g. why a parameter was rejected
+        extra_msg: Option<String>,
+        /// Permission missing when the command failed with insufficient permissions
+        failed_permid: Option<i32>,
     }
 
     impl QueryStatus {
         pub fn new(id: i32, msg: String) -> Self {
-            Self { id, msg }
+            Self {
+                id,
+                msg,
+                extra_msg: None,
+                failed_permid: None,
+            }
         }
 
         pub fn id(&self) -> i32 {
@@ -136,6 +252,12 @@ pub mod query_status {
         pub fn msg(&self) -> &str {
             &self.msg
         }
+        pub fn extra_msg(&self) -> Option<&str> {
+            self.extra_msg.as_deref()
+        }
+        pub fn failed_permid(&self) -> Option<i32> {
+            self.failed_permid
+        }
 
         pub fn is_ok(&self) -> bool {
             self.id == 0
@@ -143,27 +265,772 @@ pub mod query_status {
     }
 
     impl TryFrom<&str> for QueryStatus {
-        type Error = anyhow::Error;
+        type Error = QueryError;
 
         fn try_from(value: &str) -> Result<Self, Self::Error> {
-            let (_, line) = value
-                .split_once("error ")
-                .ok_or_else(|| anyhow!("Split error: {}", value))?;
-            let (id, msg) = line
-                .split_once(' ')
-                .ok_or_else(|| anyhow!("Split error: {}", line))?;
-            debug_assert!(id.contains('='));
-            debug_assert!(msg.contains('='));
-            let (_, id) = id.split_once('=').unwrap();
-            let (_, msg) = msg.split_once('=').unwrap();
-            Ok(Self::new(
-                id.parse()
-                    .map_err(|e| anyhow!("Got parse error: {:?}", e))?,
-                msg.replace("\\s", " ").to_string(),
+            let line = value
+                .trim()
+                .strip_prefix("error ")
+                .ok_or_else(|| QueryError::Protocol(format!("Not a status line: {}", value)))?;
+            let mut fields = parse_kv(line);
+            let parse = |key: &str, field: &str| {
+                field.parse().map_err(|e| {
+                    QueryError::Protocol(format!(
+                        "Got error while parse {} of status {}: {:?}",
+                        key, line, e
+                    ))
+                })
+            };
+            let id = parse(
+                "id",
+                fields.get("id").ok_or_else(|| {
+                    QueryError::Protocol(format!("Missing id in status: {}", line))
+                })?,
+            )?;
+            let failed_permid = fields
+                .get("failed_permid")
+                .map(|permid| parse("failed_permid", permid))
+                .transpose()?;
+            Ok(Self {
+                id,
+                msg: fields.remove("msg").unwrap_or_default(),
+                extra_msg: fields.remove("extra_msg"),
+                failed_permid,
+            })
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use crate::datastructures::QueryStatus;
+
+        #[test]
+        fn test_ok() {
+            let result = QueryStatus::try_from("error id=0 msg=ok").unwrap();
+            assert!(result.is_ok());
+            assert_eq!(result.msg(), "ok");
+            assert_eq!(result.extra_msg(), None);
+            assert_eq!(result.failed_permid(), None);
+        }
+
+        #[test]
+        fn test_failed_permid() {
+            let result = QueryStatus::try_from(
+                "error id=2568 msg=insufficient\\sclient\\spermissions failed_permid=12345",
+            )
+            .unwrap();
+            assert_eq!(result.id(), 2568);
+            assert_eq!(result.msg(), "insufficient client permissions");
+            assert_eq!(result.failed_permid(), Some(12345));
+            assert_eq!(result.extra_msg(), None);
+        }
+
+        #[test]
+        fn test_extra_msg() {
+            let result = QueryStatus::try_from(
+                "error id=1538 msg=invalid\\sparameter extra_msg=channel\\sname\\p\\/too\\slong",
+            )
+            .unwrap();
+            assert_eq!(result.id(), 1538);
+            assert_eq!(result.msg(), "invalid parameter");
+            assert_eq!(result.extra_msg(), Some("channel name|/too long"));
+        }
+
+        #[test]
+        fn test_invalid() {
+            assert!(QueryStatus::try_from("error msg=ok").is_err());
+            assert!(QueryStatus::try_from("error id=abc msg=ok").is_err());
+            assert!(QueryStatus::try_from("cid=1").is_err());
+            assert!(QueryStatus::try_from("error").is_err());
+            assert!(QueryStatus::try_from("error id").is_err());
+            assert!(QueryStatus::try_from("error id= msg=ok").is_err());
+            assert!(QueryStatus::try_from("error id=99999999999 msg=ok").is_err());
+            assert!(QueryStatus::try_from("error id=0 msg=ok failed_permid=x").is_err());
+            // Not mistaken for a status in the middle of a line
+            assert!(QueryStatus::try_from("cid=1 error id=0 msg=ok").is_err());
+        }
+    }
+}
+
+pub mod notification {
+    use crate::datastructures::parse_field;
+    use crate::error::{QueryError, Result};
+    use crate::protocol::parse_kv;
+    use std::collections::HashMap;
+
+    /// Event pushed by the server after `servernotifyregister`.
+    #[derive(Clone, Debug)]
+    pub struct Notification {
+        name: String,
+        fields: HashMap<String, String>,
+    }
+
+    impl Notification {
+        pub fn name(&self) -> &str {
+            &self.name
+        }
+        pub fn fields(&self) -> &HashMap<String, String> {
+            &self.fields
+        }
+        pub fn get(&self, key: &str) -> Option<&str> {
+            self.fields.get(key).map(String::as_str)
+        }
+
+        /// Typed view of the notification, failing if a known event misses a field.
+        pub fn event(&self) -> Result<Event> {
+            let int = |key| parse_field::<i32>(&self.fields, key);
+            let text = |key| self.get(key).unwrap_or_default().to_string();
+            Ok(match self.name.as_str() {
+                "notifycliententerview" => Event::ClientEnterView {
+                    clid: int("clid")?,
+                    cid: int("ctid")?,
+                    client_unique_identifier: text("client_unique_identifier"),
+                    client_nickname: text("client_nickname"),
+                    client_type: self
+                        .get("client_type")
+                        .map_or(Ok(0), |_| int("client_type"))?,
+                },
+                "notifyclientleftview" => Event::ClientLeftView {
+                    clid: int("clid")?,
+                    cfid: int("cfid")?,
+                    reasonid: int("reasonid")?,
+                },
+                "notifyclientmoved" => Event::ClientMoved {
+                    clid: int("clid")?,
+                    ctid: int("ctid")?,
+                    reasonid: int("reasonid")?,
+                },
+                "notifychannelcreated" => Event::ChannelCreated {
+                    cid: int("cid")?,
+                    cpid: int("cpid")?,
+                    channel_name: text("channel_name"),
+                },
+                "notifyclientupdated" => Event::ClientUpdated {
+                    clid: int("clid")?,
+                    client_nickname: self.get("client_nickname").map(ToString::to_string),
+                },
+                "notifychanneledited" => Event::ChannelEdited { cid: int("cid")? },
+                "notifychannelmoved" => Event::ChannelMoved {
+                    cid: int("cid")?,
+                    cpid: int("cpid")?,
+                    order: int("order")?,
+                },
+                "notifychanneldeleted" => Event::ChannelDeleted { cid: int("cid")? },
+                "notifyserverstop" => Event::ServerStop {
+                    reasonmsg: self.get("reasonmsg").map(ToString::to_string),
+                },
+                "notifytextmessage" => Event::TextMessage {
+                    targetmode: int("targetmode")?,
+                    msg: text("msg"),
+                    invokerid: int("invokerid")?,
+                    invokername: text("invokername"),
+                    invokeruid: text("invokeruid"),
+                },
+                _ => Event::Other {
+                    name: self.name.clone(),
+                    fields: self.fields.clone(),
+                },
+            })
+        }
+    }
+
+    /// Known notifications with their fields, see [`Notification::event`].
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub enum Event {
+        ClientEnterView {
+            clid: i32,
+            /// Channel the client appeared in, `ctid`
+            cid: i32,
+            client_unique_identifier: String,
+            client_nickname: String,
+            /// 1 for ServerQuery clients
+            client_type: i32,
+        },
+        ClientLeftView {
+            clid: i32,
+            cfid: i32,
+            reasonid: i32,
+        },
+        ClientMoved {
+            clid: i32,
+            ctid: i32,
+            reasonid: i32,
+        },
+        /// Properties of a client changed, only those that did are sent
+        ClientUpdated {
+            clid: i32,
+            client_nickname: Option<String>,
+        },
+        ChannelCreated {
+            cid: i32,
+            cpid: i32,
+            channel_name: String,
+        },
+        ChannelEdited {
+            cid: i32,
+        },
+        ChannelMoved {
+            cid: i32,
+            cpid: i32,
+            /// cid of the channel above
+            order: i32,
+        },
+        ChannelDeleted {
+            cid: i32,
+        },
+        /// The server is shutting down, the connection closes next
+        ServerStop {
+            reasonmsg: Option<String>,
+        },
+        TextMessage {
+            targetmode: i32,
+            msg: String,
+            invokerid: i32,
+            invokername: String,
+            invokeruid: String,
+        },
+        /// Any other notification, as sent
+        Other {
+            name: String,
+            fields: HashMap<String, String>,
+        },
+    }
+
+    impl TryFrom<&str> for Notification {
+        type Error = QueryError;
+
+        fn try_from(value: &str) -> Result<Self, Self::Error> {
+            let value = value.trim();
+            if !value.starts_with("notify") {
+                return Err(QueryError::Protocol(format!(
+                    "Not a notification: {}",
+                    value
+                )));
+            }
+            let (name, payload) = value.split_once(' ').unwrap_or((value, ""));
+            Ok(Self {
+                name: name.to_string(),
+                fields: parse_kv(payload),
+            })
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use crate::datastructures::{Event, Notification};
+
+        fn event(line: &str) -> Event {
+            Notification::try_from(line).unwrap().event().unwrap()
+        }
+
+        #[test]
+        fn test_events() {
+            assert_eq!(
+                event("notifycliententerview cfid=0 ctid=1 reasonid=0 clid=5 client_unique_identifier=abcdef= client_nickname=Alice\\sBob client_type=0"),
+                Event::ClientEnterView {
+                    clid: 5,
+                    cid: 1,
+                    client_unique_identifier: "abcdef=".to_string(),
+                    client_nickname: "Alice Bob".to_string(),
+                    client_type: 0,
+                }
+            );
+            assert_eq!(
+                event("notifyclientleftview cfid=42 ctid=0 reasonid=8 reasonmsg=leaving clid=5"),
+                Event::ClientLeftView {
+                    clid: 5,
+                    cfid: 42,
+                    reasonid: 8,
+                }
+            );
+            assert_eq!(
+                event("notifyclientmoved ctid=42 reasonid=1 clid=5"),
+                Event::ClientMoved {
+                    clid: 5,
+                    ctid: 42,
+                    reasonid: 1,
+                }
+            );
+            assert_eq!(
+                event("notifychannelcreated cid=13 cpid=10 channel_name=Racing\\s&\\pMore invokerid=3"),
+                Event::ChannelCreated {
+                    cid: 13,
+                    cpid: 10,
+                    channel_name: "Racing &|More".to_string(),
+                }
+            );
+            assert_eq!(
+                event("notifyclientupdated clid=5 client_nickname=Alice\\sB"),
+                Event::ClientUpdated {
+                    clid: 5,
+                    client_nickname: Some("Alice B".to_string()),
+                }
+            );
+            assert_eq!(
+                event("notifyclientupdated clid=5 client_away=1"),
+                Event::ClientUpdated {
+                    clid: 5,
+                    client_nickname: None,
+                }
+            );
+            assert_eq!(
+                event("notifychanneledited cid=13 reasonid=10 invokerid=3 channel_topic=Go"),
+                Event::ChannelEdited { cid: 13 }
+            );
+            assert_eq!(
+                event("notifychannelmoved cid=13 cpid=11 order=12 reasonid=1 invokerid=3"),
+                Event::ChannelMoved {
+                    cid: 13,
+                    cpid: 11,
+                    order: 12,
+                }
+            );
+            assert_eq!(
+                event("notifychanneldeleted cid=13 invokerid=3"),
+                Event::ChannelDeleted { cid: 13 }
+            );
+            assert_eq!(
+                event("notifytextmessage targetmode=1 msg=!help\\splease target=1 invokerid=5 invokername=Alice invokeruid=abcdef="),
+                Event::TextMessage {
+                    targetmode: 1,
+                    msg: "!help please".to_string(),
+                    invokerid: 5,
+                    invokername: "Alice".to_string(),
+                    invokeruid: "abcdef=".to_string(),
+                }
+            );
+            assert_eq!(
+                event("notifyserverstop reasonmsg=Maintenance\\sat\\s3am"),
+                Event::ServerStop {
+                    reasonmsg: Some("Maintenance at 3am".to_string())
+                }
+            );
+            assert_eq!(
+                event("notifyserverstop"),
+                Event::ServerStop { reasonmsg: None }
+            );
+            match event("notifyserveredited reasonid=10 virtualserver_name=Test") {
+                Event::Other { name, fields } => {
+                    assert_eq!(name, "notifyserveredited");
+                    assert_eq!(fields["virtualserver_name"], "Test");
+                }
+                e => panic!("Unexpected event: {:?}", e),
+            }
+            assert!(Notification::try_from("notifyclientmoved ctid=42 clid=5")
+                .unwrap()
+                .event()
+                .is_err());
+        }
+
+        #[test]
+        fn test_client_enter_view() {
+            let result = Notification::try_from("notifycliententerview cfid=0 ctid=1 reasonid=0 clid=5 client_unique_identifier=abcdef= client_nickname=Alice\\sBob client_type=0").unwrap();
+            assert_eq!(result.name(), "notifycliententerview");
+            assert_eq!(result.get("ctid"), Some("1"));
+            assert_eq!(result.get("clid"), Some("5"));
+            assert_eq!(result.get("client_nickname"), Some("Alice Bob"));
+        }
+
+        #[test]
+        fn test_client_moved() {
+            let result =
+                Notification::try_from("notifyclientmoved ctid=42 reasonid=1 clid=5\r").unwrap();
+            assert_eq!(result.name(), "notifyclientmoved");
+            assert_eq!(result.get("ctid"), Some("42"));
+            assert_eq!(result.get("reasonid"), Some("1"));
+            assert_eq!(result.fields().len(), 3);
+        }
+
+        #[test]
+        fn test_not_notification() {
+            assert!(Notification::try_from("error id=0 msg=ok").is_err());
+        }
+    }
+}
+
+pub mod whoami {
+    use crate::datastructures::parse_field;
+    use crate::error::QueryError;
+    use std::collections::HashMap;
+
+    /// Reply of `whoami`, ids are 0 while no virtual server is selected.
+    #[derive(Clone, Debug)]
+    pub struct WhoAmI {
+        client_id: i32,
+        channel_id: i32,
+        virtualserver_id: i32,
+        virtualserver_port: u16,
+    }
+
+    impl WhoAmI {
+        pub fn client_id(&self) -> i32 {
+            self.client_id
+        }
+        pub fn channel_id(&self) -> i32 {
+            self.channel_id
+        }
+        pub fn virtualserver_id(&self) -> i32 {
+            self.virtualserver_id
+        }
+        pub fn virtualserver_port(&self) -> u16 {
+            self.virtualserver_port
+        }
+    }
+
+    impl TryFrom<&HashMap<String, String>> for WhoAmI {
+        type Error = QueryError;
+
+        fn try_from(fields: &HashMap<String, String>) -> Result<Self, Self::Error> {
+            Ok(Self {
+                client_id: parse_field(fields, "client_id")?,
+                channel_id: parse_field(fields, "client_channel_id")?,
+                virtualserver_id: parse_field(fields, "virtualserver_id")?,
+                virtualserver_port: parse_field(fields, "virtualserver_port")?,
+            })
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use crate::datastructures::WhoAmI;
+        use crate::protocol::parse_kv;
+
+        const TEST_STRING: &str = "virtualserver_status=online virtualserver_id=1 virtualserver_unique_identifier=gvhQ8jrDpqb6sNHOKaZmmEufnDA= virtualserver_port=9987 client_id=1 client_channel_id=1 client_nickname=serveradmin\\sfrom\\s127.0.0.1:51974 client_database_id=1 client_login_name=serveradmin client_unique_identifier=serveradmin client_origin_server_id=0";
+
+        #[test]
+        fn test() {
+            let result = WhoAmI::try_from(&parse_kv(TEST_STRING)).unwrap();
+            assert_eq!(result.client_id(), 1);
+            assert_eq!(result.channel_id(), 1);
+            assert_eq!(result.virtualserver_id(), 1);
+            assert_eq!(result.virtualserver_port(), 9987);
+        }
+
+        #[test]
+        fn test_missing_field() {
+            assert!(WhoAmI::try_from(&parse_kv("virtualserver_status=unknown")).is_err());
+        }
+    }
+}
+
+pub mod client_info {
+    use crate::datastructures::parse_field;
+    use crate::error::QueryError;
+    use std::collections::HashMap;
+
+    /// Reply of `clientinfo`, the fields we use.
+    #[derive(Clone, Debug)]
+    pub struct ClientInfo {
+        cid: i32,
+        client_database_id: i64,
+        client_type: i32,
+        client_unique_identifier: String,
+        client_nickname: String,
+    }
+
+    impl ClientInfo {
+        pub fn cid(&self) -> i32 {
+            self.cid
+        }
+        pub fn client_database_id(&self) -> i64 {
+            self.client_database_id
+        }
+        pub fn client_type(&self) -> i32 {
+            self.client_type
+        }
+        pub fn client_unique_identifier(&self) -> &str {
+            &self.client_unique_identifier
+        }
+        pub fn client_nickname(&self) -> &str {
+            &self.client_nickname
+        }
+    }
+
+    impl TryFrom<&HashMap<String, String>> for ClientInfo {
+        type Error = QueryError;
+
+        fn try_from(fields: &HashMap<String, String>) -> Result<Self, Self::Error> {
+            Ok(Self {
+                cid: parse_field(fields, "cid")?,
+                client_database_id: parse_field(fields, "client_database_id")?,
+                client_type: parse_field(fields, "client_type")?,
+                client_unique_identifier: parse_field(fields, "client_unique_identifier")?,
+                client_nickname: parse_field(fields, "client_nickname")?,
+            })
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use crate::datastructures::ClientInfo;
+        use crate::protocol::parse_kv;
+
+        const TEST_STRING: &str = "cid=12 client_idle_time=2571 client_unique_identifier=P5H2hrN6+gpQI4n\\/dXp3p17vtY0= client_nickname=Alice\\sB\\pob client_version=3.5.6\\s[Build:\\s1606312422] client_platform=Windows client_input_muted=0 client_output_muted=0 client_outputonly_muted=0 client_input_hardware=1 client_output_hardware=1 client_default_channel client_meta_data client_is_recording=0 client_version_sign=abc client_security_hash client_login_name client_database_id=23 client_channel_group_id=8 client_servergroups=7 client_created=1650000000 client_lastconnected=1656000000 client_totalconnections=12 client_away=0 client_away_message client_type=0 client_flag_avatar client_talk_power=0 client_description connection_client_ip=192.0.2.7";
+
+        #[test]
+        fn test() {
+            let result = ClientInfo::try_from(&parse_kv(TEST_STRING)).unwrap();
+            assert_eq!(result.cid(), 12);
+            assert_eq!(result.client_database_id(), 23);
+            assert_eq!(result.client_type(), 0);
+            assert_eq!(
+                result.client_unique_identifier(),
+                "P5H2hrN6+gpQI4n/dXp3p17vtY0="
+            );
+            assert_eq!(result.client_nickname(), "Alice B|ob");
+        }
+    }
+}
+
+pub mod server_version {
+    use crate::datastructures::parse_field;
+    use crate::error::QueryError;
+    use std::collections::HashMap;
+
+    /// Reply of `version`.
+    #[derive(Clone, Debug)]
+    pub struct ServerVersion {
+        version: String,
+        build: u64,
+        platform: String,
+    }
+
+    impl ServerVersion {
+        pub fn version(&self) -> &str {
+            &self.version
+        }
+        pub fn build(&self) -> u64 {
+            self.build
+        }
+        pub fn platform(&self) -> &str {
+            &self.platform
+        }
+    }
+
+    impl TryFrom<&HashMap<String, String>> for ServerVersion {
+        type Error = QueryError;
+
+        fn try_from(fields: &HashMap<String, String>) -> Result<Self, Self::Error> {
+            Ok(Self {
+                version: parse_field(fields, "version")?,
+                build: parse_field(fields, "build")?,
+                platform: parse_field(fields, "platform")?,
+            })
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use crate::datastructures::ServerVersion;
+        use crate::protocol::parse_kv;
+
+        #[test]
+        fn test() {
+            let result = ServerVersion::try_from(&parse_kv(
+                "version=3.13.7 build=1655727713 platform=Linux",
             ))
+            .unwrap();
+            assert_eq!(result.version(), "3.13.7");
+            assert_eq!(result.build(), 1655727713);
+            assert_eq!(result.platform(), "Linux");
+
+            let result = ServerVersion::try_from(&parse_kv(
+                "version=3.0.13.8 build=1500452811 platform=FreeBSD\\s(amd64)",
+            ))
+            .unwrap();
+            assert_eq!(result.platform(), "FreeBSD (amd64)");
+        }
+    }
+}
+
+pub mod host_info {
+    use crate::datastructures::parse_field;
+    use crate::error::QueryError;
+    use std::collections::HashMap;
+
+    /// Part of the `hostinfo` reply, the instance wide statistics.
+    #[derive(Clone, Debug)]
+    pub struct HostInfo {
+        instance_uptime: u64,
+        host_timestamp_utc: i64,
+        virtualservers_running_total: u32,
+        virtualservers_total_clients_online: u32,
+    }
+
+    impl HostInfo {
+        pub fn instance_uptime(&self) -> u64 {
+            self.instance_uptime
+        }
+        pub fn host_timestamp_utc(&self) -> i64 {
+            self.host_timestamp_utc
+        }
+        pub fn virtualservers_running_total(&self) -> u32 {
+            self.virtualservers_running_total
+        }
+        pub fn virtualservers_total_clients_online(&self) -> u32 {
+            self.virtualservers_total_clients_online
+        }
+    }
+
+    impl TryFrom<&HashMap<String, String>> for HostInfo {
+        type Error = QueryError;
+
+        fn try_from(fields: &HashMap<String, String>) -> Result<Self, Self::Error> {
+            Ok(Self {
+                instance_uptime: parse_field(fields, "instance_uptime")?,
+                host_timestamp_utc: parse_field(fields, "host_timestamp_utc")?,
+                virtualservers_running_total: parse_field(fields, "virtualservers_running_total")?,
+                virtualservers_total_clients_online: parse_field(
+                    fields,
+                    "virtualservers_total_clients_online",
+                )?,
+            })
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use crate::datastructures::HostInfo;
+        use crate::protocol::parse_kv;
+
+        const TEST_STRING: &str = "instance_uptime=3652 host_timestamp_utc=1656000000 virtualservers_running_total=1 virtualservers_total_maxclients=32 virtualservers_total_clients_online=2 virtualservers_total_channels_online=5 connection_filetransfer_bandwidth_sent=0 connection_packets_sent_total=1024";
+
+        #[test]
+        fn test() {
+            let result = HostInfo::try_from(&parse_kv(TEST_STRING)).unwrap();
+            assert_eq!(result.instance_uptime(), 3652);
+            assert_eq!(result.host_timestamp_utc(), 1656000000);
+            assert_eq!(result.virtualservers_running_total(), 1);
+            assert_eq!(result.virtualservers_total_clients_online(), 2);
+        }
+    }
+}
+
+pub mod server_list {
+    use crate::datastructures::parse_field;
+    use crate::error::QueryError;
+    use std::collections::HashMap;
+    use std::str::FromStr;
+
+    /// `virtualserver_status` in `serverlist`.
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub enum ServerStatus {
+        Online,
+        Offline,
+        /// Running without accepting voice connections, e.g. during a snapshot deploy
+        Virtual,
+        /// A status this version doesn't know
+        Other(String),
+    }
+
+    impl FromStr for ServerStatus {
+        type Err = std::convert::Infallible;
+
+        fn from_str(status: &str) -> Result<Self, Self::Err> {
+            Ok(match status {
+                "online" => Self::Online,
+                "offline" => Self::Offline,
+                "online virtual" => Self::Virtual,
+                other => Self::Other(other.to_string()),
+            })
+        }
+    }
+
+    impl std::fmt::Display for ServerStatus {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                Self::Online => f.write_str("online"),
+                Self::Offline => f.write_str("offline"),
+                Self::Virtual => f.write_str("online virtual"),
+                Self::Other(status) => f.write_str(status),
+            }
+        }
+    }
+
+    /// A record of `serverlist`, one per virtual server of the instance.
+    #[derive(Clone, Debug)]
+    pub struct VirtualServerInfo {
+        virtualserver_id: i32,
+        virtualserver_port: u16,
+        virtualserver_status: ServerStatus,
+        /// Missing while the server is offline
+        virtualserver_clientsonline: Option<u32>,
+        virtualserver_name: String,
+    }
+
+    impl VirtualServerInfo {
+        pub fn virtualserver_id(&self) -> i32 {
+            self.virtualserver_id
+        }
+        pub fn virtualserver_port(&self) -> u16 {
+            self.virtualserver_port
+        }
+        pub fn virtualserver_status(&self) -> &ServerStatus {
+            &self.virtualserver_status
+        }
+        pub fn virtualserver_clientsonline(&self) -> Option<u32> {
+            self.virtualserver_clientsonline
+        }
+        pub fn virtualserver_name(&self) -> &str {
+            &self.virtualserver_name
+        }
+    }
+
+    impl TryFrom<&HashMap<String, String>> for VirtualServerInfo {
+        type Error = QueryError;
+
+        fn try_from(fields: &HashMap<String, String>) -> Result<Self, Self::Error> {
+            Ok(Self {
+                virtualserver_id: parse_field(fields, "virtualserver_id")?,
+                virtualserver_port: parse_field(fields, "virtualserver_port")?,
+                virtualserver_status: parse_field(fields, "virtualserver_status")?,
+                virtualserver_clientsonline: fields
+                    .get("virtualserver_clientsonline")
+                    .map(|_| parse_field(fields, "virtualserver_clientsonline"))
+                    .transpose()?,
+                virtualserver_name: fields
+                    .get("virtualserver_name")
+                    .cloned()
+                    .unwrap_or_default(),
+            })
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use crate::datastructures::{ServerStatus, VirtualServerInfo};
+        use crate::protocol::parse_list;
+
+        const TEST_STRING: &str = "virtualserver_id=1 virtualserver_port=9987 virtualserver_status=online virtualserver_clientsonline=3 virtualserver_queryclientsonline=1 virtualserver_maxclients=32 virtualserver_uptime=3600 virtualserver_name=TeamSpeak\\s]I[\\sServer virtualserver_autostart=1 virtualserver_machine_id|virtualserver_id=2 virtualserver_port=9988 virtualserver_status=offline virtualserver_maxclients=32 virtualserver_name=Backup virtualserver_autostart=0 virtualserver_machine_id";
+
+        #[test]
+        fn test() {
+            let servers: Vec<_> = parse_list(TEST_STRING)
+                .iter()
+                .map(VirtualServerInfo::try_from)
+                .collect::<Result<_, _>>()
+                .unwrap();
+            assert_eq!(servers.len(), 2);
+            assert_eq!(servers[0].virtualserver_id(), 1);
+            assert_eq!(servers[0].virtualserver_port(), 9987);
+            assert_eq!(servers[0].virtualserver_status(), &ServerStatus::Online);
+            assert_eq!(servers[0].virtualserver_clientsonline(), Some(3));
+            assert_eq!(servers[0].virtualserver_name(), "TeamSpeak ]I[ Server");
+            assert_eq!(servers[1].virtualserver_status(), &ServerStatus::Offline);
+            assert_eq!(servers[1].virtualserver_clientsonline(), None);
+            assert_eq!(
+                "other instance".parse::<ServerStatus>().unwrap(),
+                ServerStatus::Other("other instance".to_string())
+            );
         }
     }
 }
 
+pub use client_info::ClientInfo;
+pub use host_info::HostInfo;
+pub use notification::{Event, Notification};
 pub use query_status::QueryStatus;
 use serde::Deserialize;
+pub use server_list::{ServerStatus, VirtualServerInfo};
+pub use server_version::ServerVersion;
+pub use whoami::WhoAmI;
@@ -0,0 +1,59 @@
+use crate::datastructures::QueryStatus;
+use thiserror::Error;
+
+/// Errors returned by the ServerQuery client.
+#[derive(Debug, Error)]
+pub enum QueryError {
+    #[error("Got error while connect to {addr}: {source}")]
+    Connect {
+        addr: String,
+        source: std::io::Error,
+    },
+    #[error("Got IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Connection closed by server")]
+    Closed,
+    /// `notifyserverstop`, with the reason given by the server if any.
+    #[error("Server is shutting down: {0}")]
+    ServerStopped(String),
+    #[error("Authentication failed: {0}")]
+    Auth(String),
+    #[error("Got protocol error: {0}")]
+    Protocol(String),
+    #[error("Timed out while waiting for status line")]
+    Timeout,
+    /// Part of the reply, without its status line, arrived before the timeout.
+    #[error("Incomplete response, timed out after receiving {} bytes", .0.len())]
+    IncompleteResponse(String),
+    /// The server answered with a non ok `error` line.
+    #[error("Got non ok status: id={id} msg={msg}")]
+    ServerError { id: i32, msg: String },
+    /// `channeldelete` without force on a channel with clients inside (error 772).
+    #[error("Channel {0} is not empty, delete it with force to kick the clients")]
+    ChannelNotEmpty(i32),
+    /// `use` on a stopped virtual server (error 1033).
+    #[error("Virtual server {0} is not running, start it with serverstart or --autostart-server")]
+    ServerNotRunning(String),
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
+    #[error("Invalid config: {0}")]
+    Config(String),
+    #[error("Invalid state file: {0}")]
+    State(String),
+}
+
+impl From<QueryStatus> for QueryError {
+    fn from(status: QueryStatus) -> Self {
+        // The extra message is the only hint why e.g. a parameter was rejected
+        let msg = match status.extra_msg() {
+            Some(extra_msg) => format!("{} ({})", status.msg(), extra_msg),
+            None => status.msg().to_string(),
+        };
+        Self::ServerError {
+            id: status.id(),
+            msg,
+        }
+    }
+}
+
+pub type Result<T, E = QueryError> = std::result::Result<T, E>;
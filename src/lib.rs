@@ -0,0 +1,35 @@
+//! TeamSpeak ServerQuery client and the auto-channel engine built on it.
+//!
+//! [`TelnetConn`] wraps a ServerQuery session and exposes the commands as methods,
+//! [`autochannel::AutoChannel`] reacts to the pushed notifications. Both need the
+//! default `sync` feature, the `async` feature adds [`async_connection::AsyncTelnetConn`].
+
+#[cfg(feature = "async")]
+pub mod async_connection;
+#[cfg(feature = "sync")]
+pub mod autochannel;
+pub mod channel_tree;
+#[cfg(feature = "sync")]
+pub mod config;
+#[cfg(feature = "sync")]
+pub mod connection;
+pub mod datastructures;
+pub mod error;
+pub mod logging;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod protocol;
+mod random;
+pub mod rate_limit;
+pub mod reconnect;
+pub mod state;
+pub mod transport;
+
+#[cfg(feature = "sync")]
+pub use connection::{
+    ChannelListFlags, ClientListFlags, KickReason, RawResponse, TargetMode, TelnetConn,
+    TelnetConnBuilder, VirtualServer,
+};
+pub use datastructures::{Event, Notification, QueryStatus};
+pub use error::QueryError;
+pub use transport::QueryTransport;
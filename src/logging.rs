@@ -0,0 +1,240 @@
+use log::kv::{self, Key, VisitSource};
+use log::{LevelFilter, Record};
+use serde_json::{Map, Value};
+use std::cell::Cell;
+use std::fmt::Display;
+use std::io::Write;
+use std::str::FromStr;
+
+/// Output format of the log lines.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// The human readable `env_logger` format
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format {}, expected text or json", s)),
+        }
+    }
+}
+
+thread_local! {
+    /// Connection the current thread logs for, see [`set_connection_id`]
+    static CONNECTION_ID: Cell<Option<u64>> = Cell::new(None);
+    static ATTEMPT: Cell<u32> = Cell::new(0);
+}
+
+/// Tag the log lines of the current thread with the connection it serves. Every
+/// server runs on its own thread, so the lines of different connections stay apart.
+pub fn set_connection_id(id: Option<u64>) {
+    CONNECTION_ID.with(|cell| cell.set(id));
+}
+
+/// Tag the log lines of the current thread with the reconnect attempt, 0 for the
+/// first connection.
+pub fn set_attempt(attempt: u32) {
+    ATTEMPT.with(|cell| cell.set(attempt));
+}
+
+/// Connection id and attempt the current thread logs for.
+pub fn connection() -> Option<(u64, u32)> {
+    CONNECTION_ID
+        .with(Cell::get)
+        .map(|id| (id, ATTEMPT.with(Cell::get)))
+}
+
+/// Level selected by `-q` or the number of `-v`. `None` without either, the level
+/// is then left to `RUST_LOG`, or the env_logger default if that is unset.
+pub fn verbosity(verbose: u64, quiet: bool) -> Option<LevelFilter> {
+    match (quiet, verbose) {
+        (true, _) => Some(LevelFilter::Error),
+        (false, 0) => None,
+        (false, 1) => Some(LevelFilter::Warn),
+        (false, 2) => Some(LevelFilter::Info),
+        (false, 3) => Some(LevelFilter::Debug),
+        (false, _) => Some(LevelFilter::Trace),
+    }
+}
+
+/// Install the global logger, filtered by `level` or else by `RUST_LOG` as usual.
+pub fn init(format: LogFormat, level: Option<LevelFilter>) {
+    let mut builder = match level {
+        Some(level) => {
+            let mut builder = env_logger::Builder::new();
+            builder.filter_level(level);
+            builder
+        }
+        None => env_logger::Builder::from_default_env(),
+    };
+    match format {
+        LogFormat::Text => builder.format(|buf, record| {
+            let line = format_text(buf.timestamp(), record);
+            writeln!(buf, "{}", line)
+        }),
+        LogFormat::Json => builder.format(|buf, record| {
+            let line = format_json(buf.timestamp(), record);
+            writeln!(buf, "{}", line)
+        }),
+    };
+    builder.init();
+}
+
+/// Render a record like `env_logger` does, with the connection of the thread if any.
+pub fn format_text(timestamp: impl Display, record: &Record) -> String {
+    let connection = match connection() {
+        Some((id, attempt)) => format!(" conn={} attempt={}", id, attempt),
+        None => String::new(),
+    };
+    format!(
+        "[{} {:<5} {}{}] {}",
+        timestamp,
+        record.level().as_str(),
+        record.target(),
+        connection,
+        record.args()
+    )
+}
+
+/// Render a record as a single JSON line.
+///
+/// The key-values attached at the call site (`info!(event = "channel_created", cid = 42; ...)`)
+/// become top-level fields besides `timestamp`, `level`, `target` and `message`.
+pub fn format_json(timestamp: impl Display, record: &Record) -> String {
+    let mut fields = Map::new();
+    fields.insert("timestamp".to_string(), timestamp.to_string().into());
+    fields.insert("level".to_string(), record.level().as_str().into());
+    fields.insert("target".to_string(), record.target().into());
+    fields.insert("message".to_string(), record.args().to_string().into());
+    if let Some((id, attempt)) = connection() {
+        fields.insert("conn_id".to_string(), id.into());
+        fields.insert("attempt".to_string(), u64::from(attempt).into());
+    }
+    // Visiting only fails if we return an error ourselves
+    let _ = record.key_values().visit(&mut Fields(&mut fields));
+    Value::Object(fields).to_string()
+}
+
+struct Fields<'a>(&'a mut Map<String, Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        let value = match value.to_i64() {
+            Some(number) => number.into(),
+            None => value.to_string().into(),
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{format_json, format_text, set_attempt, set_connection_id, verbosity, LogFormat};
+    use log::{Level, LevelFilter, Record};
+
+    #[test]
+    fn test_format_json() {
+        let kvs = [("event", "channel_created"), ("creator_uid", "abc\"=")];
+        let cid = [("cid", 42)];
+        let records = [
+            Record::builder()
+                .level(Level::Info)
+                .target("teamspeak_autochannel::autochannel")
+                .args(format_args!("Created channel 42 for client \"bob\"\n(3)"))
+                .key_values(&kvs)
+                .build(),
+            Record::builder()
+                .level(Level::Warn)
+                .target("teamspeak_autochannel")
+                .args(format_args!("Channel 42 is empty"))
+                .key_values(&cid)
+                .build(),
+        ];
+        let output: String = records
+            .iter()
+            .map(|record| format_json("2022-05-01T12:00:00Z", record) + "\n")
+            .collect();
+
+        let lines: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["timestamp"], "2022-05-01T12:00:00Z");
+        assert_eq!(lines[0]["level"], "INFO");
+        assert_eq!(lines[0]["target"], "teamspeak_autochannel::autochannel");
+        assert_eq!(
+            lines[0]["message"],
+            "Created channel 42 for client \"bob\"\n(3)"
+        );
+        assert_eq!(lines[0]["event"], "channel_created");
+        assert_eq!(lines[0]["creator_uid"], "abc\"=");
+        assert_eq!(lines[1]["level"], "WARN");
+        assert_eq!(lines[1]["cid"], 42);
+    }
+
+    #[test]
+    fn test_connection_fields() {
+        let record = Record::builder()
+            .level(Level::Info)
+            .target("teamspeak_autochannel")
+            .args(format_args!("Connected"))
+            .build();
+        let timestamp = "2022-05-01T12:00:00Z";
+        let json = |record: &Record| -> serde_json::Value {
+            serde_json::from_str(&format_json(timestamp, record)).unwrap()
+        };
+        // Tests run on their own threads, nothing is set here yet
+        assert_eq!(
+            format_text(timestamp, &record),
+            "[2022-05-01T12:00:00Z INFO  teamspeak_autochannel] Connected"
+        );
+
+        set_connection_id(Some(7));
+        set_attempt(2);
+        assert_eq!(
+            format_text(timestamp, &record),
+            "[2022-05-01T12:00:00Z INFO  teamspeak_autochannel conn=7 attempt=2] Connected"
+        );
+        assert_eq!(json(&record)["conn_id"].as_u64(), Some(7));
+        assert_eq!(json(&record)["attempt"].as_u64(), Some(2));
+
+        // Set by every new connection
+        #[cfg(feature = "sync")]
+        {
+            let (conn, _) = crate::connection::test::mock_conn();
+            assert_ne!(conn.id(), 7);
+            assert_eq!(json(&record)["conn_id"].as_u64(), Some(conn.id()));
+        }
+        set_connection_id(None);
+        set_attempt(0);
+    }
+
+    #[test]
+    fn test_verbosity() {
+        assert_eq!(verbosity(0, false), None);
+        assert_eq!(verbosity(1, false), Some(LevelFilter::Warn));
+        assert_eq!(verbosity(2, false), Some(LevelFilter::Info));
+        assert_eq!(verbosity(3, false), Some(LevelFilter::Debug));
+        assert_eq!(verbosity(4, false), Some(LevelFilter::Trace));
+        assert_eq!(verbosity(9, false), Some(LevelFilter::Trace));
+        assert_eq!(verbosity(0, true), Some(LevelFilter::Error));
+    }
+
+    #[test]
+    fn test_log_format() {
+        assert_eq!("json".parse(), Ok(LogFormat::Json));
+        assert_eq!("text".parse(), Ok(LogFormat::Text));
+        assert!("xml".parse::<LogFormat>().is_err());
+    }
+}
@@ -1,213 +1,870 @@
-mod datastructures;
-
-use crate::datastructures::channel::Channel;
-use crate::datastructures::client::Client;
-use crate::datastructures::{FromQueryString, QueryStatus};
 use anyhow::anyhow;
-use clap::{arg, Command};
-use log::{error, warn};
-use serde::Deserialize;
+use clap::{arg, Arg, ArgMatches, Command};
+use log::{debug, error, info, warn};
+use std::cell::RefCell;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
-use telnet::Event;
-
-struct TelnetConn {
-    conn: telnet::Telnet,
-}
+use teamspeak_autochannel::autochannel::{serve, setup_session, AutoChannel};
+use teamspeak_autochannel::channel_tree::render_tree;
+use teamspeak_autochannel::config::{Config, Transport};
+use teamspeak_autochannel::logging::{self, LogFormat};
+#[cfg(feature = "metrics")]
+use teamspeak_autochannel::metrics::{self, Metrics};
+use teamspeak_autochannel::protocol::{build_command, build_command_with, escape};
+use teamspeak_autochannel::rate_limit::RateLimiter;
+use teamspeak_autochannel::reconnect::{reconnect, sleep_while, Backoff, DEFAULT_JITTER};
+use teamspeak_autochannel::state::State;
+use teamspeak_autochannel::{QueryError, TelnetConn};
 
-impl TelnetConn {
-    fn decode_status(data: Box<[u8]>) -> anyhow::Result<(Option<QueryStatus>, String)> {
-        let content =
-            String::from_utf8(data.to_vec()).map_err(|e| anyhow!("Got FromUtf8Error: {:?}", e))?;
-
-        debug_assert!(content.contains("error id="));
-
-        for line in content.lines() {
-            if line.trim().starts_with("error ") {
-                let status = QueryStatus::try_from(line)?;
-                if !status.is_ok() {
-                    return Err(anyhow!(
-                        "Got non ok status: id={} msg={}",
-                        status.id(),
-                        status.msg()
-                    ));
-                }
-
-                return Ok((Some(status), content));
+/// Connect and authenticate, over SSH if configured.
+fn open(config: &Config) -> anyhow::Result<TelnetConn> {
+    let (server, port) = (config.server(), config.port());
+    let (user, password) = (config.user(), config.password());
+    let commands = config.commands_per_interval();
+    let mut builder = TelnetConn::builder(server, port)
+        .timeout(Duration::from_secs(config.timeout_secs()))
+        .tcp_options(config.tcp_options())
+        .rate_limit(
+            (commands > 0)
+                .then(|| RateLimiter::new(commands, Duration::from_secs(config.interval_secs()))),
+        )
+        .flood_cooldown(Duration::from_secs(config.flood_cooldown_secs()))
+        .max_response_bytes(config.max_response_bytes())
+        .autostart_server(config.autostart_server());
+    match config.transport() {
+        Transport::Raw => {}
+        Transport::Ssh => builder = builder.ssh(user, password),
+        Transport::Tls => {
+            if config.tls_insecure() {
+                warn!("TLS certificate verification is disabled");
             }
+            builder = builder
+                .tls(true)
+                .tls_ca_file(config.tls_ca_file().map(ToOwned::to_owned))
+                .tls_insecure(config.tls_insecure());
         }
-        Ok((None, content))
     }
-
-    fn decode_status_with_result<T: FromQueryString + Sized>(
-        data: Box<[u8]>,
-        //builder: Box<dyn Fn(&str) -> anyhow::Result<T>>,
-    ) -> anyhow::Result<(Option<QueryStatus>, Option<Vec<T>>)> {
-        let (status, content) = Self::decode_status(data)?;
-
-        for line in content.lines() {
-            if !line.starts_with("error ") {
-                let mut v = Vec::new();
-                for element in line.split('|') {
-                    v.push(T::from_query(element)?);
+    let mut conn = builder.connect()?;
+    if config.transport() != Transport::Ssh {
+        conn.login(user, password)?;
+    }
+    if commands > 0 && !config.rate_limit_configured() {
+        match conn.instance_info() {
+            Ok(info) => match RateLimiter::from_flood_settings(&info) {
+                Some(limiter) => {
+                    debug!(
+                        "Following the server flood limit, {} commands per {:?}",
+                        limiter.commands(),
+                        limiter.interval()
+                    );
+                    conn.set_rate_limit(Some(limiter));
                 }
-                return Ok((status, Some(v)));
-            }
+                None => debug!("Server flood limit unknown, keeping the default rate limit"),
+            },
+            Err(e) => warn!("Got error while query instance info: {:?}", e),
         }
-        Ok((status, None))
     }
+    Ok(conn)
+}
 
-    fn connect(server: &str, port: u16) -> anyhow::Result<Self> {
-        let conn = telnet::Telnet::connect((server, port), 512)
-            .map_err(|e| anyhow!("Got error while connect to {}:{} {:?}", server, port, e))?;
-        let mut self_ = Self { conn };
-
-        let content = self_
-            .read_data(1)
-            .map_err(|e| anyhow!("Got error while read content: {:?}", e))?;
+/// Process wide state shared by the event loops of all servers.
+struct Shared {
+    running: AtomicBool,
+    #[cfg(feature = "metrics")]
+    metrics: Option<Arc<Metrics>>,
+}
 
-        if content.is_none() {
-            warn!("Read none");
+/// Run the auto-channel for every configured server, each on its own thread and
+/// connection. `load` reads the config again on SIGHUP.
+fn run(config: &Config, load: impl Fn() -> anyhow::Result<Config> + Sync) -> anyhow::Result<()> {
+    #[cfg(feature = "metrics")]
+    let metrics = match config.metrics_addr() {
+        Some(addr) => {
+            let metrics = Arc::new(Metrics::new());
+            metrics.set_expected_connections(config.targets().len());
+            metrics.set_ready_within(Duration::from_secs(config.ready_within_secs()));
+            metrics::serve(addr, metrics.clone())
+                .map_err(|e| anyhow!("Got error while listen on {}: {:?}", addr, e))?;
+            Some(metrics)
         }
-
-        Ok(self_)
+        None => None,
+    };
+    #[cfg(not(feature = "metrics"))]
+    if config.metrics_addr().is_some() {
+        return Err(anyhow!(
+            "Built without metrics support, enable the metrics feature"
+        ));
     }
-
-    fn read_data(&mut self, timeout: u64) -> anyhow::Result<Option<Box<[u8]>>> {
-        match self
-            .conn
-            .read_timeout(Duration::from_secs(timeout))
-            .map_err(|e| anyhow!("Got error while read data: {:?}", e))?
-        {
-            Event::Data(data) => Ok(Some(data)),
-            Event::TimedOut => Ok(None),
-            Event::NoData => Ok(None),
-            Event::Error(e) => Err(anyhow!("Got error: {:?}", e)),
-            _ => Err(anyhow!("Got unknown error")),
-        }
+    let shared = Arc::new(Shared {
+        running: AtomicBool::new(true),
+        #[cfg(feature = "metrics")]
+        metrics,
+    });
+    {
+        let shared = shared.clone();
+        ctrlc::set_handler(move || shared.running.store(false, Ordering::SeqCst))
+            .map_err(|e| anyhow!("Got error while set signal handler: {:?}", e))?;
     }
 
-    fn write_data(&mut self, payload: &str) -> anyhow::Result<()> {
-        self.conn
-            .write(payload.as_bytes())
-            .map(|size| {
-                if size != payload.as_bytes().len() {
-                    error!("Error")
-                }
+    let targets = config.targets();
+    let results: Vec<anyhow::Result<()>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = targets
+            .iter()
+            .enumerate()
+            .map(|(index, target)| {
+                let (shared, load, count) = (&shared, &load, targets.len());
+                scope.spawn(move || {
+                    let reload = || {
+                        let targets = load()?.targets();
+                        if targets.len() != count {
+                            warn!("Adding or removing servers needs a restart");
+                        }
+                        targets
+                            .into_iter()
+                            .nth(index)
+                            .ok_or_else(|| anyhow!("servers[{}] is gone from the config", index))
+                    };
+                    let result = staff(target, shared, reload);
+                    if let (Err(e), true) = (&result, count > 1) {
+                        error!("Stopped serving {:?}: {:?}", target.virtual_server(), e);
+                    }
+                    result
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|_| Err(anyhow!("Event loop panicked")))
             })
-            .map_err(|e| anyhow!("Got error while send data: {:?}", e))?;
-        Ok(())
+            .collect()
+    });
+    results.into_iter().collect()
+}
+
+/// Run the auto-channel on one virtual server until shutdown.
+fn staff(
+    config: &Config,
+    shared: &Shared,
+    load: impl Fn() -> anyhow::Result<Config>,
+) -> anyhow::Result<()> {
+    let connect = || -> anyhow::Result<TelnetConn> {
+        let mut conn = open(config)?;
+        conn.set_dry_run(config.dry_run());
+        setup_session(
+            &mut conn,
+            None,
+            config.virtual_server(),
+            config.nickname(),
+            !config.admin_uids().is_empty(),
+        )?;
+        Ok(conn)
+    };
+    let mut conn = connect()?;
+    match conn.server_version() {
+        Ok(version) if !version.version().starts_with("3.") => warn!(
+            "Untested server version {} build {}",
+            version.version(),
+            version.build()
+        ),
+        Ok(version) => info!(
+            "Connected to server {} build {} on {}",
+            version.version(),
+            version.build(),
+            version.platform()
+        ),
+        Err(e) => warn!("Got error while query server version: {:?}", e),
+    }
+
+    let mut auto_channel = AutoChannel::with_rules(Vec::new());
+    auto_channel.configure(&config.resolve_names(&mut conn)?);
+    #[cfg(feature = "metrics")]
+    if let Some(metrics) = &shared.metrics {
+        auto_channel.set_metrics(metrics.clone());
+        metrics.connection_up();
     }
 
-    fn write_and_read(&mut self, payload: &str, timeout: u64) -> anyhow::Result<Box<[u8]>> {
-        self.write_data(&payload)?;
-        Ok(self
-            .read_data(timeout)?
-            .ok_or_else(|| anyhow!("Return data is None"))?)
+    if let Some(path) = config.state_file() {
+        let state = State::load(path)?;
+        auto_channel.set_state_file(Some(path.to_path_buf()));
+        auto_channel.restore(&mut conn, &state)?;
     }
 
-    fn login(&mut self, user: &str, password: &str) -> anyhow::Result<QueryStatus> {
-        let payload = format!("login {} {}\n\r", user, password);
-        let data = self.write_and_read(payload.as_str(), 2)?;
-        Ok(Self::decode_status(data)?
-            .0
-            .ok_or_else(|| anyhow!("Can't find status line."))?)
+    let keepalive = Duration::from_secs(config.keepalive_secs());
+    let mut backoff = Backoff::new(
+        Duration::from_secs(1),
+        Duration::from_secs(config.max_backoff_secs()),
+    );
+    backoff.set_jitter(DEFAULT_JITTER);
+    backoff.set_max_attempts(config.max_reconnect_attempts());
+    let reload_requested = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    signal_hook::flag::register(signal_hook::consts::SIGHUP, reload_requested.clone())
+        .map_err(|e| anyhow!("Got error while set SIGHUP handler: {:?}", e))?;
+    // The config last applied, its channel names are looked up again after a reconnect
+    let current = RefCell::new(config.clone());
+    let mut reload = || {
+        if !reload_requested.swap(false, Ordering::SeqCst) {
+            return None;
+        }
+        info!("Reloading the configuration");
+        match load() {
+            Ok(reloaded) => {
+                let ignored = config.restart_required(&reloaded);
+                if !ignored.is_empty() {
+                    warn!(
+                        "Ignoring changed setting(s) until restart: {}",
+                        ignored.join(", ")
+                    );
+                }
+                *current.borrow_mut() = reloaded.clone();
+                Some(reloaded)
+            }
+            Err(e) => {
+                error!(
+                    "Got error while reload config, keeping the old one: {:?}",
+                    e
+                );
+                None
+            }
+        }
+    };
+    while shared.running.load(Ordering::SeqCst) {
+        let e = match serve(
+            &mut conn,
+            &mut auto_channel,
+            keepalive,
+            &shared.running,
+            &mut reload,
+        ) {
+            Ok(()) => break,
+            Err(e) => e,
+        };
+        error!("Connection lost: {:?}", e);
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &shared.metrics {
+            metrics.connection_down();
+        }
+        let reconnected = reconnect(&connect, &mut backoff, &shared.running, |delay| {
+            sleep_while(&shared.running, delay)
+        })
+        .map_err(|e| {
+            anyhow!(
+                "Giving up after {} reconnect attempts: {:?}",
+                backoff.attempt(),
+                e
+            )
+        })?;
+        conn = match reconnected {
+            Some(conn) => conn,
+            // Nothing to clean up on the lost connection
+            None => {
+                info!("Shutting down");
+                return Ok(());
+            }
+        };
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &shared.metrics {
+            metrics.inc_reconnects();
+            metrics.connection_up();
+        }
+        let current = current.borrow();
+        if current.has_channel_names() {
+            if let Err(e) = auto_channel.reload(&mut conn, &current) {
+                error!("Got error while resolve the channel names again: {:?}", e);
+            }
+        }
     }
+    info!("Shutting down");
+    #[cfg(feature = "metrics")]
+    if let Some(metrics) = &shared.metrics {
+        metrics.connection_down();
+    }
+    auto_channel.shutdown(
+        &mut conn,
+        Duration::from_secs(current.borrow().shutdown_timeout_secs()),
+    );
+    Ok(())
+}
 
-    fn select_server(&mut self, server_id: i32) -> anyhow::Result<QueryStatus> {
-        let payload = format!("use {}\n\r", server_id);
-        let data = self.write_and_read(payload.as_str(), 2)?;
-        Ok(Self::decode_status(data)?
-            .0
-            .ok_or_else(|| anyhow!("Can't find status line."))?)
+/// Print the server version, instance statistics and who we are logged in as.
+fn check(config: &Config) -> anyhow::Result<()> {
+    let mut conn = open(config)?;
+    let version = conn.server_version()?;
+    println!(
+        "version: {}\nbuild: {}\nplatform: {}",
+        version.version(),
+        version.build(),
+        version.platform()
+    );
+    let host_info = conn.host_info()?;
+    println!(
+        "uptime: {}s\nvirtual servers running: {}\nclients online: {}",
+        host_info.instance_uptime(),
+        host_info.virtualservers_running_total(),
+        host_info.virtualservers_total_clients_online()
+    );
+    match conn.server_list() {
+        Ok(servers) => {
+            for server in servers {
+                println!(
+                    "  sid {} port {} {}: {}, clients online: {}",
+                    server.virtualserver_id(),
+                    server.virtualserver_port(),
+                    server.virtualserver_name(),
+                    server.virtualserver_status(),
+                    server
+                        .virtualserver_clientsonline()
+                        .map(|clients| clients.to_string())
+                        .unwrap_or_else(|| "-".to_string())
+                );
+            }
+        }
+        Err(e) => warn!("Got error while list virtual servers: {:?}", e),
     }
+    conn.select_virtual_server(config.virtual_server())?;
+    let whoami = conn.whoami()?;
+    println!(
+        "client id: {}\nvirtual server: {} (port {})",
+        whoami.client_id(),
+        whoami.virtualserver_id(),
+        whoami.virtualserver_port()
+    );
+    Ok(())
+}
 
-    fn query_clients(&mut self) -> anyhow::Result<(QueryStatus, Vec<Client>)> {
-        let data = self.write_and_read("clientlist -uid\n\r", 2)?;
-        let (status, clients) = Self::decode_status_with_result(data)?;
+/// Print the channel tree of the selected virtual server.
+fn list_channels(config: &Config) -> anyhow::Result<()> {
+    let mut conn = open(config)?;
+    conn.select_virtual_server(config.virtual_server())?;
+    let timeout = conn.timeout();
+    let (_, channels) = conn.write_and_parse_list(
+        &build_command_with("channellist", &[], &[], &["-topic", "-flags"]),
+        timeout,
+    )?;
+    let (_, clients) = conn.write_and_parse_list(&build_command("clientlist", &[]), timeout)?;
+    print!("{}", render_tree(&channels, &clients)?);
+    Ok(())
+}
 
-        Ok((
-            status.ok_or_else(|| anyhow!("Can't find status line."))?,
-            clients.ok_or_else(|| anyhow!("Can't find result line."))?,
-        ))
+/// Send `command`, or every line of stdin, on the selected virtual server and print
+/// the replies as received.
+fn raw(config: &Config, command: Option<&str>) -> anyhow::Result<()> {
+    let mut conn = open(config)?;
+    conn.select_virtual_server(config.virtual_server())?;
+    let commands: Vec<String> = match command {
+        Some(command) => vec![command.to_string()],
+        None => std::io::stdin()
+            .lines()
+            .collect::<std::io::Result<Vec<_>>>()?
+            .into_iter()
+            .filter(|line| !line.trim().is_empty())
+            .collect(),
+    };
+    let mut failed = None;
+    for command in commands {
+        let response = conn.raw_command(&command)?;
+        for line in response.lines() {
+            println!("{}", line);
+        }
+        let status = response.status();
+        println!("error id={} msg={}", status.id(), escape(status.msg()));
+        if !status.is_ok() {
+            failed = Some(status.clone());
+        }
+    }
+    match failed {
+        Some(status) => Err(QueryError::from(status).into()),
+        None => Ok(()),
     }
+}
 
-    fn query_channels(&mut self) -> anyhow::Result<(QueryStatus, Vec<Channel>)> {
-        let data = self.write_and_read("channellist\n\r", 2)?;
-        let (status, channels) = Self::decode_status_with_result(data)?;
+/// Parse an optional argument, an invalid value is an error naming it.
+fn parse_arg<T: std::str::FromStr>(name: &str, value: Option<&str>) -> anyhow::Result<Option<T>>
+where
+    T::Err: std::fmt::Debug,
+{
+    value
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|e| anyhow!("Got error while parse --{} {:?}: {:?}", name, value, e))
+        })
+        .transpose()
+}
 
-        Ok((
-            status.ok_or_else(|| anyhow!("Can't find status line."))?,
-            channels.ok_or_else(|| anyhow!("Can't find result line."))?,
-        ))
+/// Password given on the command line, either positional, from an environment
+/// variable or from a file. At most one source may be used, it overrides the
+/// password of the config file.
+fn read_password(
+    positional: Option<&str>,
+    env: Option<&str>,
+    file: Option<&Path>,
+) -> anyhow::Result<Option<String>> {
+    match (positional, env, file) {
+        (None, None, None) => Ok(None),
+        (Some(password), None, None) => Ok(Some(password.to_string())),
+        (None, Some(var), None) => std::env::var(var)
+            .map(Some)
+            .map_err(|e| anyhow!("Got error while read password from ${}: {:?}", var, e)),
+        (None, None, Some(path)) => std::fs::read_to_string(path)
+            .map(|content| Some(content.trim_end_matches(['\n', '\r']).to_string()))
+            .map_err(|e| {
+                anyhow!(
+                    "Got error while read password from {}: {:?}",
+                    path.display(),
+                    e
+                )
+            }),
+        _ => Err(anyhow!(
+            "Only one of <PASSWORD>, --password-env and --password-file may be given"
+        )),
     }
 }
 
-fn staff(server: &str, port: u16, user: &str, password: &str, sid: &str) -> anyhow::Result<()> {
-    let mut conn = TelnetConn::connect(server, port)?;
-    let status = conn.login(user, password)?;
-    if !status.is_ok() {
-        return Err(anyhow!("Login failed. {:?}", status));
+/// Settings shared by all subcommands.
+fn cli_config(matches: &ArgMatches) -> anyhow::Result<Config> {
+    let mut config = Config::default();
+    config.set_server(matches.value_of("server").map(ToString::to_string));
+    config.set_port(parse_arg("port", matches.value_of("port"))?);
+    config.set_bind(parse_arg("bind", matches.value_of("bind"))?);
+    config.set_prefer_ipv4(matches.is_present("prefer-ipv4").then_some(true));
+    config.set_prefer_ipv6(matches.is_present("prefer-ipv6").then_some(true));
+    config.set_proxy(matches.value_of("proxy").map(ToString::to_string));
+    config.set_user(matches.value_of("USER").map(ToString::to_string));
+    config.set_password(read_password(
+        matches.value_of("PASSWORD"),
+        matches.value_of("password-env"),
+        matches.value_of("password-file").map(Path::new),
+    )?);
+    config.set_sid(parse_arg("sid", matches.value_of("sid"))?);
+    config.set_server_port(parse_arg("server-port", matches.value_of("server-port"))?);
+    config.set_autostart_server(matches.is_present("autostart-server").then_some(true));
+    config.set_timeout_secs(parse_arg("timeout-secs", matches.value_of("timeout-secs"))?);
+    config.set_commands_per_interval(parse_arg(
+        "commands-per-interval",
+        matches.value_of("commands-per-interval"),
+    )?);
+    config.set_interval_secs(parse_arg(
+        "interval-secs",
+        matches.value_of("interval-secs"),
+    )?);
+    config.set_flood_cooldown_secs(parse_arg(
+        "flood-cooldown-secs",
+        matches.value_of("flood-cooldown-secs"),
+    )?);
+    config.set_max_response_bytes(parse_arg(
+        "max-response-bytes",
+        matches.value_of("max-response-bytes"),
+    )?);
+    config.set_ssh(matches.is_present("ssh").then_some(true));
+    config.set_tls(matches.is_present("tls").then_some(true));
+    config.set_tls_ca_file(matches.value_of("tls-ca-file").map(Into::into));
+    config.set_tls_insecure(matches.is_present("tls-insecure").then_some(true));
+    Ok(config)
+}
+
+/// Settings of the `run` subcommand.
+fn run_config(matches: &ArgMatches) -> anyhow::Result<Config> {
+    let mut config = cli_config(matches)?;
+    // A channel id, or else a name or path resolved once connected
+    match matches.value_of("parent-channel") {
+        Some(value) if value.parse::<i32>().is_err() => {
+            config.set_parent_channel_name(Some(value.to_string()))
+        }
+        value => config.set_parent_channel(parse_arg("parent-channel", value)?),
     }
-    let status = conn.select_server(
-        sid.parse()
-            .map_err(|e| anyhow!("Got error while parse sid: {:?}", e))?,
-    )?;
-    if !status.is_ok() {
-        return Err(anyhow!("Select server id failed: {:?}", status));
+    config.set_spawn_parent(parse_arg("spawn-parent", matches.value_of("spawn-parent"))?);
+    config.set_watch_subtree(parse_arg(
+        "watch-subtree",
+        matches.value_of("watch-subtree"),
+    )?);
+    config.set_max_channels_per_user(parse_arg(
+        "max-channels-per-user",
+        matches.value_of("max-channels-per-user"),
+    )?);
+    config.set_max_channels_total(parse_arg(
+        "max-channels-total",
+        matches.value_of("max-channels-total"),
+    )?);
+    config.set_creator_channel_group(parse_arg(
+        "creator-channel-group",
+        matches.value_of("creator-channel-group"),
+    )?);
+    config.set_creator_channel_group_name(
+        matches
+            .value_of("creator-channel-group-name")
+            .map(ToString::to_string),
+    );
+    config.set_channel_codec(parse_arg(
+        "channel-codec",
+        matches.value_of("channel-codec"),
+    )?);
+    config.set_channel_codec_quality(parse_arg(
+        "channel-codec-quality",
+        matches.value_of("channel-codec-quality"),
+    )?);
+    config.set_channel_max_clients(parse_arg(
+        "channel-max-clients",
+        matches.value_of("channel-max-clients"),
+    )?);
+    config.set_channel_password(
+        matches
+            .value_of("channel-password")
+            .map(ToString::to_string),
+    );
+    config.set_channel_topic_template(
+        matches
+            .value_of("channel-topic-template")
+            .map(ToString::to_string),
+    );
+    config.set_channel_description_template(
+        matches
+            .value_of("channel-description-template")
+            .map(ToString::to_string),
+    );
+    config.set_channel_lifetime(parse_arg(
+        "channel-lifetime",
+        matches.value_of("channel-lifetime"),
+    )?);
+    config.set_channel_name_template(
+        matches
+            .value_of("channel-name-template")
+            .map(ToString::to_string),
+    );
+    config.set_channel_name_attempts(parse_arg(
+        "channel-name-attempts",
+        matches.value_of("channel-name-attempts"),
+    )?);
+    config.set_on_name_collision(parse_arg(
+        "on-name-collision",
+        matches.value_of("on-name-collision"),
+    )?);
+    config.set_empty_grace_secs(parse_arg(
+        "empty-grace-secs",
+        matches.value_of("empty-grace-secs"),
+    )?);
+    config.set_spawn_cooldown_secs(parse_arg(
+        "spawn-cooldown-secs",
+        matches.value_of("spawn-cooldown-secs"),
+    )?);
+    config.set_rejoin_existing(matches.is_present("rejoin-existing").then_some(true));
+    config.set_afk_channel(parse_arg("afk-channel", matches.value_of("afk-channel"))?);
+    config.set_afk_idle_secs(parse_arg(
+        "afk-idle-secs",
+        matches.value_of("afk-idle-secs"),
+    )?);
+    config.set_admin_uids(matches.value_of("admin-uids").map(|uids| {
+        uids.split(',')
+            .map(str::trim)
+            .filter(|uid| !uid.is_empty())
+            .map(ToString::to_string)
+            .collect()
+    }));
+    config.set_keepalive_secs(parse_arg(
+        "keepalive-secs",
+        matches.value_of("keepalive-secs"),
+    )?);
+    config.set_shutdown_timeout_secs(parse_arg(
+        "shutdown-timeout-secs",
+        matches.value_of("shutdown-timeout-secs"),
+    )?);
+    config.set_max_backoff_secs(parse_arg(
+        "max-backoff-secs",
+        matches.value_of("max-backoff-secs"),
+    )?);
+    config.set_max_reconnect_attempts(parse_arg(
+        "max-reconnect-attempts",
+        matches.value_of("max-reconnect-attempts"),
+    )?);
+    config.set_nickname(matches.value_of("nickname").map(ToString::to_string));
+    config.set_dry_run(matches.is_present("dry-run").then_some(true));
+    config.set_state_file(matches.value_of("state-file").map(Into::into));
+    config.set_metrics_addr(matches.value_of("metrics-addr").map(ToString::to_string));
+    config.set_ready_within_secs(parse_arg(
+        "ready-within-secs",
+        matches.value_of("ready-within-secs"),
+    )?);
+    Ok(config)
+}
+
+/// The `--config` file with the `--config-dir` drop-ins, overridden by the arguments.
+fn load_config(matches: &ArgMatches, args: Config) -> anyhow::Result<Config> {
+    let mut config = match matches.value_of("config") {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    if let Some(dir) = matches.value_of("config-dir") {
+        config = config.with_drop_ins(dir)?;
     }
-    Ok(())
+    Ok(config.merge(args))
 }
 
-fn main() -> anyhow::Result<()> {
-    let matches = Command::new(env!("CARGO_PKG_NAME"))
+/// Login arguments, every subcommand takes them.
+fn credential_args() -> [Arg<'static>; 4] {
+    [
+        arg!([USER] "Teamspeak ServerQuery user"),
+        arg!([PASSWORD] "Teamspeak ServerQuery password, visible in the process list"),
+        arg!(--"password-env" [VAR] "Read the password from this environment variable")
+            .conflicts_with_all(&["PASSWORD", "password-file"]),
+        arg!(--"password-file" [FILE] "Read the password from this file, e.g. a systemd credential")
+            .conflicts_with("PASSWORD"),
+    ]
+}
+
+fn cli() -> Command<'static> {
+    Command::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
-        .args(&[
-            arg!(--server [SERVER] "Teamspeak ServerQuery server address"),
-            arg!(--port [PORT] "Teamspeak ServerQuery server port"),
-            arg!(<USER> "Teamspeak ServerQuery user"),
-            arg!(<PASSWORD> "Teamspeak ServerQuery password"),
-            arg!(--sid [SID] "Teamspeak ServerQuery server id"),
-        ])
-        .get_matches();
-    env_logger::Builder::from_default_env().init();
-    staff(
-        matches.value_of("SERVER").unwrap_or("localhost"),
-        matches
-            .value_of("PORT")
-            .unwrap_or("10011")
-            .parse()
-            .unwrap_or_else(|e| {
-                warn!("Got parse error: {:?}", e);
-                10011
-            }),
-        matches.value_of("USER").unwrap(),
-        matches.value_of("PASSWORD").unwrap(),
-        matches.value_of("SID").unwrap_or("1"),
-    )?;
-    Ok(())
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .args(
+            [
+                arg!(-c --config [FILE] "Load settings from a TOML file, arguments override it. SIGHUP reloads it"),
+                arg!(--"config-dir" [DIR] "Add the watch rules of the *.toml files in this directory, in lexical order"),
+                arg!(--server [SERVER] "Teamspeak ServerQuery server address"),
+                arg!(--port [PORT] "Teamspeak ServerQuery server port"),
+                arg!(--bind [IP] "Connect from this local address, e.g. the one whitelisted on the server"),
+                arg!(--"prefer-ipv4" "Try the IPv4 addresses of the server first"),
+                arg!(--"prefer-ipv6" "Try the IPv6 addresses of the server first").conflicts_with("prefer-ipv4"),
+                arg!(--proxy [URL] "Connect through this SOCKS5 proxy, socks5://[user:password@]host:port"),
+                arg!(--sid [SID] "Teamspeak ServerQuery server id, 1 unless --server-port is given").conflicts_with("server-port"),
+                arg!(--"server-port" [PORT] "Select the virtual server by its voice port instead of sid, conflicts with --sid"),
+                arg!(--"autostart-server" "Start the virtual server if it is stopped"),
+                arg!(--"timeout-secs" [SECS] "Time to wait for a reply to each command"),
+                arg!(--"commands-per-interval" [COUNT] "Commands sent per --interval-secs at most, 0 for no limit (default: the server flood limit, else 8)"),
+                arg!(--"interval-secs" [SECS] "Interval of the command rate limit (default 3)"),
+                arg!(--"flood-cooldown-secs" [SECS] "Pause before retrying a command rejected as flooding (default 3)"),
+                arg!(--"max-response-bytes" [BYTES] "Give up on a response larger than this (default 1 MiB)"),
+                arg!(--ssh "Use the SSH ServerQuery (default port 10022)"),
+                arg!(--tls "Wrap the raw ServerQuery in TLS, for a TLS terminator like stunnel (tls feature)")
+                    .conflicts_with("ssh"),
+                arg!(--"tls-ca-file" [FILE] "Also trust the CA certificates in this PEM file"),
+                arg!(--"tls-insecure" "Accept any TLS certificate, exposes the password to anyone on the path"),
+                arg!(--"log-format" [FORMAT] "Log as human readable text (default) or JSON lines")
+                    .possible_values(["text", "json"]),
+                arg!(-v --verbose ... "Log more, -v warnings, -vv info, -vvv debug, -vvvv trace (overrides RUST_LOG)"),
+                arg!(-q --quiet "Log errors only (overrides RUST_LOG)").conflicts_with("verbose"),
+            ]
+            .map(|arg| arg.global(true)),
+        )
+        .subcommand(
+            Command::new("run")
+                .about("Spawn a channel for every client joining the watched channels")
+                .args(credential_args())
+                .args(&[
+                    arg!(--"parent-channel" [CHANNEL] "Channel watched for spawning sub-channels, by id, name or path like Lobby/Games, the default channel if unset"),
+                    arg!(--"spawn-parent" [CID] "Create the spawned channels below this channel instead of the watched one"),
+                    arg!(--"watch-subtree" [CID] "Watch every channel below this one too, following changes of the channel tree"),
+                    arg!(--"max-channels-per-user" [COUNT] "Channels a single identity may own at once"),
+                    arg!(--"max-channels-total" [COUNT] "Channels spawned at once across all users"),
+                    arg!(--"creator-channel-group" [CGID] "Channel group given to the client a channel was spawned for"),
+                    arg!(--"creator-channel-group-name" [NAME] "Like --creator-channel-group, by the name of the group")
+                        .conflicts_with("creator-channel-group"),
+                    arg!(--"channel-codec" [CODEC] "Codec of spawned channels, 4 is Opus Voice and 5 Opus Music"),
+                    arg!(--"channel-codec-quality" [QUALITY] "Codec quality of spawned channels, 0-10"),
+                    arg!(--"channel-max-clients" [COUNT] "Clients a spawned channel holds at most (default: unlimited)"),
+                    arg!(--"channel-password" [TEMPLATE] "Password of spawned channels, {random} generates one and pokes it to the creator"),
+                    arg!(--"channel-topic-template" [TEMPLATE] "Topic of spawned channels, with the placeholders of the name"),
+                    arg!(--"channel-description-template" [TEMPLATE] "Description of spawned channels, with the placeholders of the name"),
+                    arg!(--"channel-lifetime" [LIFETIME] "Spawn temporary, semi-permanent or permanent channels, temporary ones are deleted by the server"),
+                    arg!(--"channel-name-template" [TEMPLATE] "Name of spawned channels, supports {client_nickname}, {client_uid} and {datetime}"),
+                    arg!(--"channel-name-attempts" [COUNT] "Names tried when a channel name is taken, appending (2), (3), ... (default 5)"),
+                    arg!(--"on-name-collision" [STRATEGY] "When a channel name is taken: suffix it (default), skip spawning or reuse the channel of that name"),
+                    arg!(--"empty-grace-secs" [SECS] "Delete a spawned channel only after it stayed empty this long"),
+                    arg!(--"spawn-cooldown-secs" [SECS] "Spawn at most one channel per identity within this time"),
+                    arg!(--"rejoin-existing" "Move clients back into the channel they own instead of spawning another one"),
+                    arg!(--"afk-channel" [CID] "Move clients idle for --afk-idle-secs to this channel"),
+                    arg!(--"afk-idle-secs" [SECS] "Idle time after which clients are moved to --afk-channel"),
+                    arg!(--"admin-uids" [UIDS] "Comma separated identities allowed to send !commands in private messages"),
+                    arg!(--"keepalive-secs" [SECS] "Send a keepalive after this many idle seconds"),
+                    arg!(--"shutdown-timeout-secs" [SECS] "Give up the cleanup on exit after this many seconds"),
+                    arg!(--"max-backoff-secs" [SECS] "Upper bound of the delay between reconnect attempts"),
+                    arg!(--"max-reconnect-attempts" [N] "Exit after this many failed reconnect attempts, 0 retries forever"),
+                    arg!(--nickname [NAME] "Nickname of the query client, a number is appended if it is taken"),
+                    arg!(--"dry-run" "Log channel changes and moves instead of doing them"),
+                    arg!(--"state-file" [FILE] "Remember the spawned channels in this JSON file across restarts"),
+                    arg!(--"metrics-addr" [ADDR] "Serve Prometheus metrics and /healthz, /readyz on this address (metrics feature)"),
+                    arg!(--"ready-within-secs" [SECS] "Fail /readyz after this many seconds without events or pings (default 180)"),
+                ]),
+        )
+        .subcommand(
+            Command::new("check")
+                .about("Connect, print the server version and our identity, then exit")
+                .args(credential_args()),
+        )
+        .subcommand(
+            Command::new("list-channels")
+                .about("Print the channels of the virtual server")
+                .args(credential_args()),
+        )
+        .subcommand(
+            Command::new("raw")
+                .about("Send an escaped ServerQuery command and print the reply, one per line of stdin without COMMAND")
+                .args(credential_args())
+                .arg(arg!([COMMAND] "Command line to send, e.g. \"serverinfo\"")),
+        )
+}
+
+fn main() -> anyhow::Result<()> {
+    let matches = cli().get_matches();
+    let log_format = match matches.value_of("log-format") {
+        Some(format) => format.parse().map_err(|e| anyhow!("{}", e))?,
+        None => LogFormat::default(),
+    };
+    let level = logging::verbosity(
+        matches.occurrences_of("verbose"),
+        matches.is_present("quiet"),
+    );
+    logging::init(log_format, level);
+
+    match matches.subcommand() {
+        Some(("run", matches)) => {
+            let load = || {
+                let config = load_config(matches, run_config(matches)?)?;
+                config.validate()?;
+                Ok(config)
+            };
+            run(&load()?, load)
+        }
+        Some(("check", matches)) => {
+            let config = load_config(matches, cli_config(matches)?)?;
+            config.validate_login()?;
+            check(&config)
+        }
+        Some(("list-channels", matches)) => {
+            let config = load_config(matches, cli_config(matches)?)?;
+            config.validate_login()?;
+            list_channels(&config)
+        }
+        Some(("raw", matches)) => {
+            let config = load_config(matches, cli_config(matches)?)?;
+            config.validate_login()?;
+            raw(&config, matches.value_of("COMMAND"))
+        }
+        _ => unreachable!("clap requires a subcommand"),
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use super::*;
+    use super::{cli, parse_arg, read_password, run_config};
+    use std::path::Path;
 
     #[test]
-    fn test_connection() {
-        let mut conn = TelnetConn::connect(env!("QUERY_HOST"), 10011).unwrap();
+    fn test_subcommands() {
+        let matches = cli()
+            .try_get_matches_from([
+                "teamspeak-autochannel",
+                "--server",
+                "ts.example.com",
+                "run",
+                "serveradmin",
+                "secret",
+                "--parent-channel",
+                "5",
+                "--port",
+                "10012",
+            ])
+            .unwrap();
+        let (name, run) = matches.subcommand().unwrap();
+        assert_eq!(name, "run");
+        let config = run_config(run).unwrap();
+        assert_eq!(config.server(), "ts.example.com");
+        assert_eq!(config.port(), 10012);
+        assert_eq!(config.user(), "serveradmin");
+        assert_eq!(config.parent_channel(), 5);
 
-        let result = conn.login("serveradmin", env!("QUERY_PASSWORD")).unwrap();
+        // An invalid number is an error naming the flag, not a silent default
+        let matches = cli()
+            .try_get_matches_from([
+                "teamspeak-autochannel",
+                "run",
+                "serveradmin",
+                "secret",
+                "--empty-grace-secs",
+                "ten",
+            ])
+            .unwrap();
+        let (_, run) = matches.subcommand().unwrap();
+        let e = run_config(run).unwrap_err();
+        assert!(e.to_string().contains("--empty-grace-secs"));
 
-        assert!(result.is_ok());
+        let matches = cli()
+            .try_get_matches_from(["teamspeak-autochannel", "check", "serveradmin", "secret"])
+            .unwrap();
+        assert_eq!(matches.subcommand_name(), Some("check"));
 
-        let result = conn.select_server(1).unwrap();
-        assert!(result.is_ok());
+        let matches = cli()
+            .try_get_matches_from([
+                "teamspeak-autochannel",
+                "list-channels",
+                "--sid",
+                "2",
+                "serveradmin",
+                "secret",
+            ])
+            .unwrap();
+        let (name, list) = matches.subcommand().unwrap();
+        assert_eq!(name, "list-channels");
+        assert_eq!(list.value_of("sid"), Some("2"));
 
-        let (status, clients) = conn.query_clients().unwrap();
-        assert!(status.is_ok());
-        dbg!(clients);
+        // Daemon settings are not accepted by the ops subcommands
+        assert!(cli()
+            .try_get_matches_from(["teamspeak-autochannel", "check", "--parent-channel", "5"])
+            .is_err());
+        assert!(cli()
+            .try_get_matches_from(["teamspeak-autochannel"])
+            .is_err());
+    }
+
+    #[test]
+    fn test_parse_arg() {
+        assert_eq!(
+            parse_arg::<u16>("port", Some("10011")).unwrap(),
+            Some(10011)
+        );
+        assert_eq!(parse_arg::<u16>("port", None).unwrap(), None);
+        let e = parse_arg::<u16>("port", Some("1o011")).unwrap_err();
+        assert!(e.to_string().contains("--port \"1o011\""));
+        assert!(parse_arg::<u16>("port", Some("65536")).is_err());
+        assert!(parse_arg::<i32>("sid", Some("one")).is_err());
+    }
 
-        let (status, channel) = conn.query_channels().unwrap();
-        assert!(status.is_ok());
-        dbg!(channel);
+    #[test]
+    fn test_password_env() {
+        std::env::set_var("TEAMSPEAK_AUTOCHANNEL_TEST_PASSWORD", "s3cr3t");
+        assert_eq!(
+            read_password(None, Some("TEAMSPEAK_AUTOCHANNEL_TEST_PASSWORD"), None).unwrap(),
+            Some("s3cr3t".to_string())
+        );
+        assert!(read_password(None, Some("TEAMSPEAK_AUTOCHANNEL_TEST_UNSET"), None).is_err());
+    }
+
+    #[test]
+    fn test_password_file() {
+        let path = std::env::temp_dir().join(format!(
+            "teamspeak-autochannel-password-{}",
+            std::process::id()
+        ));
+        std::fs::write(&path, "pass word\r\n\n").unwrap();
+        let password = read_password(None, None, Some(&path));
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(password.unwrap(), Some("pass word".to_string()));
+        assert!(read_password(None, None, Some(Path::new("/nonexistent/password"))).is_err());
+    }
+
+    #[test]
+    fn test_password_sources() {
+        assert_eq!(read_password(None, None, None).unwrap(), None);
+        assert_eq!(
+            read_password(Some("argv"), None, None).unwrap(),
+            Some("argv".to_string())
+        );
+        assert!(read_password(Some("argv"), Some("VAR"), None).is_err());
+        assert!(read_password(None, Some("VAR"), Some(Path::new("file"))).is_err());
     }
 }
@@ -0,0 +1,346 @@
+//! Prometheus counters of the auto-channel activity and the health checks, served
+//! over a minimal HTTP endpoint.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+
+/// Counters and gauges exported by [`serve`], shared with the event loop.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    channels_created: AtomicU64,
+    channels_deleted: AtomicU64,
+    clients_moved: AtomicU64,
+    reconnects: AtomicU64,
+    active_temp_channels: AtomicU64,
+    /// Round trip of the last ping in microseconds
+    ping_micros: AtomicU64,
+    /// Unix time of the last successful ping
+    last_ping: AtomicU64,
+    /// Unix time of the last event received
+    last_event: AtomicU64,
+    /// Unix time of the last reply to any command
+    last_reply: AtomicU64,
+    /// Query connections up, and how many there should be for `/readyz`
+    connections: AtomicU64,
+    expected_connections: AtomicU64,
+    /// Seconds without events or replies after which `/readyz` fails
+    ready_within: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let metrics = Self::default();
+        metrics.set_expected_connections(1);
+        metrics.set_ready_within(DEFAULT_READY_WITHIN);
+        metrics
+    }
+
+    pub fn inc_channels_created(&self) {
+        self.channels_created.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn inc_channels_deleted(&self) {
+        self.channels_deleted.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn inc_clients_moved(&self) {
+        self.clients_moved.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn inc_reconnects(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+    /// One server's managed channels went from `before` to `after`, the gauge
+    /// is the sum over all servers.
+    pub fn update_active_temp_channels(&self, before: usize, after: usize) {
+        self.active_temp_channels
+            .fetch_add(after as u64, Ordering::Relaxed);
+        self.active_temp_channels
+            .fetch_sub(before as u64, Ordering::Relaxed);
+    }
+
+    /// Record a successful ping of the query connection at `at`.
+    pub fn set_ping(&self, latency: Duration, at: SystemTime) {
+        self.ping_micros
+            .store(latency.as_micros() as u64, Ordering::Relaxed);
+        let unix = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.last_ping.store(unix.as_secs(), Ordering::Relaxed);
+    }
+
+    /// Record an event received at `at`.
+    pub fn set_last_event(&self, at: SystemTime) {
+        let unix = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.last_event.store(unix.as_secs(), Ordering::Relaxed);
+    }
+
+    /// Record a command reply received at `at`, pings are only sent while no
+    /// other commands are.
+    pub fn set_last_reply(&self, at: SystemTime) {
+        let unix = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.last_reply.store(unix.as_secs(), Ordering::Relaxed);
+    }
+
+    /// A query connection was established, see [`Self::ready`].
+    pub fn connection_up(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+    /// A query connection was lost or closed.
+    pub fn connection_down(&self) {
+        let _ = self
+            .connections
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                count.checked_sub(1)
+            });
+    }
+    /// Connections needed to be ready, one per served virtual server.
+    pub fn set_expected_connections(&self, count: usize) {
+        self.expected_connections
+            .store(count as u64, Ordering::Relaxed);
+    }
+    /// Time without an event or reply after which the connections count as stale.
+    pub fn set_ready_within(&self, within: Duration) {
+        self.ready_within.store(within.as_secs(), Ordering::Relaxed);
+    }
+
+    /// Whether every connection is up and the last event, ping or reply is recent.
+    pub fn ready(&self, now: SystemTime) -> bool {
+        let connections = self.connections.load(Ordering::Relaxed);
+        if connections < self.expected_connections.load(Ordering::Relaxed) {
+            return false;
+        }
+        let last = self
+            .last_event
+            .load(Ordering::Relaxed)
+            .max(self.last_ping.load(Ordering::Relaxed))
+            .max(self.last_reply.load(Ordering::Relaxed));
+        let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        now.saturating_sub(last) <= self.ready_within.load(Ordering::Relaxed)
+    }
+
+    /// Render all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let counters = [
+            (
+                "channels_created_total",
+                "counter",
+                "Channels spawned for clients",
+                &self.channels_created,
+            ),
+            (
+                "channels_deleted_total",
+                "counter",
+                "Spawned channels deleted after they became empty",
+                &self.channels_deleted,
+            ),
+            (
+                "clients_moved_total",
+                "counter",
+                "Clients moved into their spawned channel",
+                &self.clients_moved,
+            ),
+            (
+                "reconnects_total",
+                "counter",
+                "Reconnects to the ServerQuery",
+                &self.reconnects,
+            ),
+            (
+                "active_temp_channels",
+                "gauge",
+                "Spawned channels currently managed",
+                &self.active_temp_channels,
+            ),
+            (
+                "query_last_ping_timestamp_seconds",
+                "gauge",
+                "Unix time of the last successful ping of the query connection",
+                &self.last_ping,
+            ),
+        ];
+        let ping_seconds = self.ping_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let metrics = counters
+            .into_iter()
+            .map(|(name, kind, help, value)| {
+                (name, kind, help, value.load(Ordering::Relaxed).to_string())
+            })
+            .chain(std::iter::once((
+                "query_ping_seconds",
+                "gauge",
+                "Round trip of the last ping of the query connection",
+                ping_seconds.to_string(),
+            )));
+        let mut out = String::new();
+        for (name, kind, help, value) in metrics {
+            out.push_str(&format!(
+                "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n"
+            ));
+        }
+        out
+    }
+}
+
+/// Time without events or replies `/readyz` tolerates unless configured otherwise.
+pub const DEFAULT_READY_WITHIN: Duration = Duration::from_secs(180);
+
+/// Time a scrape or probe may take, requests are answered one at a time so a
+/// silent client must not hold up the next one.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Serve `metrics` on `addr` from a background thread, returning the bound address.
+/// Besides `/metrics`, `/healthz` answers while the process runs and `/readyz`
+/// while [`Metrics::ready`].
+pub fn serve(addr: &str, metrics: Arc<Metrics>) -> std::io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+    info!(
+        "Serving metrics on http://{}/metrics, health checks on /healthz and /readyz",
+        local_addr
+    );
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|stream| respond(stream, &metrics));
+            if let Err(e) = result {
+                warn!("Got error while serve metrics: {:?}", e);
+            }
+        }
+    });
+    Ok(local_addr)
+}
+
+fn respond(mut stream: TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut request_line = String::new();
+    let mut reader = BufReader::new(stream.try_clone()?);
+    reader.read_line(&mut request_line)?;
+    // Skip the headers, only the path matters
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && header.trim_end() != "" {
+        header.clear();
+    }
+    let mut parts = request_line.split(' ');
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render()),
+        (Some("GET"), Some("/healthz")) => ("200 OK", "ok\n".to_string()),
+        (Some("GET"), Some("/readyz")) if metrics.ready(SystemTime::now()) => {
+            ("200 OK", "ready\n".to_string())
+        }
+        (Some("GET"), Some("/readyz")) => ("503 Service Unavailable", "not ready\n".to_string()),
+        (Some("GET"), _) => ("404 Not Found", String::new()),
+        _ => ("405 Method Not Allowed", String::new()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::{serve, Metrics};
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    /// Fetch `/metrics` from a server started by [`serve`].
+    pub fn scrape(metrics: Arc<Metrics>) -> String {
+        let addr = serve("127.0.0.1:0", metrics).unwrap();
+        get(addr, "/metrics")
+    }
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.inc_channels_created();
+        metrics.inc_channels_created();
+        metrics.inc_reconnects();
+        metrics.update_active_temp_channels(0, 3);
+        metrics.update_active_temp_channels(3, 2);
+        metrics.set_ping(
+            Duration::from_millis(15),
+            UNIX_EPOCH + Duration::from_secs(1651406400),
+        );
+        let text = metrics.render();
+        assert!(text.contains("# TYPE channels_created_total counter\nchannels_created_total 2\n"));
+        assert!(text.contains("channels_deleted_total 0\n"));
+        assert!(text.contains("reconnects_total 1\n"));
+        assert!(text.contains("# TYPE active_temp_channels gauge\nactive_temp_channels 2\n"));
+        assert!(text.contains("query_last_ping_timestamp_seconds 1651406400\n"));
+        assert!(text.contains("query_ping_seconds 0.015\n"));
+    }
+
+    #[test]
+    fn test_serve() {
+        let response = scrape(Arc::new(Metrics::new()));
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("query_ping_seconds 0\n"));
+    }
+
+    #[test]
+    fn test_health() {
+        let metrics = Arc::new(Metrics::new());
+        let addr = serve("127.0.0.1:0", metrics.clone()).unwrap();
+        assert!(get(addr, "/healthz").starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(get(addr, "/readyz").starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(get(addr, "/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        metrics.connection_up();
+        metrics.set_last_event(SystemTime::now());
+        assert!(get(addr, "/readyz").starts_with("HTTP/1.1 200 OK\r\n"));
+        metrics.connection_down();
+        assert!(get(addr, "/readyz").starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(get(addr, "/healthz").starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn test_silent_client() {
+        let addr = serve("127.0.0.1:0", Arc::new(Metrics::new())).unwrap();
+        let _silent = TcpStream::connect(addr).unwrap();
+        assert!(get(addr, "/healthz").starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn test_ready() {
+        let metrics = Metrics::new();
+        let now = UNIX_EPOCH + Duration::from_secs(1651406400);
+        metrics.set_expected_connections(2);
+        metrics.set_ready_within(Duration::from_secs(60));
+        metrics.connection_up();
+        metrics.set_last_event(now);
+        assert!(!metrics.ready(now));
+        metrics.connection_up();
+        assert!(metrics.ready(now + Duration::from_secs(60)));
+        // Stale, neither events nor pings arrive
+        assert!(!metrics.ready(now + Duration::from_secs(61)));
+        metrics.set_ping(Duration::from_millis(5), now + Duration::from_secs(61));
+        assert!(metrics.ready(now + Duration::from_secs(61)));
+    }
+
+    #[test]
+    fn test_ready_without_pings() {
+        // Polling commands keep the connection busy, no keepalive ping is sent
+        let metrics = Metrics::new();
+        let now = UNIX_EPOCH + Duration::from_secs(1651406400);
+        metrics.connection_up();
+        metrics.set_last_reply(now);
+        assert!(metrics.ready(now + super::DEFAULT_READY_WITHIN));
+        assert!(!metrics.ready(now + super::DEFAULT_READY_WITHIN + Duration::from_secs(1)));
+        metrics.set_last_reply(now + Duration::from_secs(150));
+        assert!(metrics.ready(now + super::DEFAULT_READY_WITHIN + Duration::from_secs(1)));
+    }
+}
@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+
+/// Escape a value for use in a ServerQuery command.
+pub fn escape(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '\\' => output.push_str("\\\\"),
+            '/' => output.push_str("\\/"),
+            ' ' => output.push_str("\\s"),
+            '|' => output.push_str("\\p"),
+            '\u{07}' => output.push_str("\\a"),
+            '\u{08}' => output.push_str("\\b"),
+            '\u{0C}' => output.push_str("\\f"),
+            '\n' => output.push_str("\\n"),
+            '\r' => output.push_str("\\r"),
+            '\t' => output.push_str("\\t"),
+            '\u{0B}' => output.push_str("\\v"),
+            _ => output.push(c),
+        }
+    }
+    output
+}
+
+/// Reverse of [`escape`]. Unknown sequences and a trailing backslash are kept as is.
+pub fn unescape(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            output.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => output.push('\\'),
+            Some('/') => output.push('/'),
+            Some('s') => output.push(' '),
+            Some('p') => output.push('|'),
+            Some('a') => output.push('\u{07}'),
+            Some('b') => output.push('\u{08}'),
+            Some('f') => output.push('\u{0C}'),
+            Some('n') => output.push('\n'),
+            Some('r') => output.push('\r'),
+            Some('t') => output.push('\t'),
+            Some('v') => output.push('\u{0B}'),
+            Some(other) => {
+                output.push('\\');
+                output.push(other);
+            }
+            None => output.push('\\'),
+        }
+    }
+    output
+}
+
+/// Ends every command. Replies end with `\n\r`, but the server takes a bare `\n`.
+pub const TERMINATOR: &str = "\n";
+
+/// Build a command line from its name and `key=value` arguments, values are escaped.
+pub fn build_command(command: &str, args: &[(&str, &str)]) -> String {
+    build_command_with(command, &[], args, &[])
+}
+
+/// [`build_command`] with the positional `params` some commands like `login` take
+/// first, escaped too, and `options` like `-uid` last.
+pub fn build_command_with(
+    command: &str,
+    params: &[&str],
+    args: &[(&str, &str)],
+    options: &[&str],
+) -> String {
+    let mut payload = command.to_string();
+    for param in params {
+        payload.push(' ');
+        payload.push_str(&escape(param));
+    }
+    for (key, value) in args {
+        payload.push(' ');
+        payload.push_str(key);
+        payload.push('=');
+        payload.push_str(&escape(value));
+    }
+    for option in options {
+        payload.push(' ');
+        payload.push_str(option);
+    }
+    payload.push_str(TERMINATOR);
+    payload
+}
+
+/// Parse a `key=value key2=value2` reply line, values are unescaped.
+/// Keys without `=` map to an empty value.
+pub fn parse_kv(line: &str) -> HashMap<String, String> {
+    line.split(' ')
+        .filter(|token| !token.is_empty())
+        .map(|token| match token.split_once('=') {
+            Some((key, value)) => (key.to_string(), unescape(value)),
+            None => (token.to_string(), String::new()),
+        })
+        .collect()
+}
+
+/// Parse a `|` separated list reply such as `clientlist` into one map per record.
+pub fn parse_list(line: &str) -> Vec<HashMap<String, String>> {
+    line.split('|')
+        .filter(|record| !record.trim().is_empty())
+        .map(parse_kv)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        build_command, build_command_with, escape, parse_kv, parse_list, unescape, TERMINATOR,
+    };
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("a b|c"), "a\\sb\\pc");
+        assert_eq!(escape("back\\slash/"), "back\\\\slash\\/");
+        assert_eq!(escape("line\nbreak\r\ttab"), "line\\nbreak\\r\\ttab");
+        assert_eq!(escape("\u{07}\u{08}\u{0C}\u{0B}"), "\\a\\b\\f\\v");
+        assert_eq!(escape("plain"), "plain");
+    }
+
+    #[test]
+    fn test_unescape() {
+        assert_eq!(unescape("a\\sb\\pc"), "a b|c");
+        assert_eq!(unescape("\\s\\s\\p"), "  |");
+        assert_eq!(unescape("trailing\\"), "trailing\\");
+        assert_eq!(unescape("unknown\\x"), "unknown\\x");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for input in [
+            "a b|c",
+            "  ||  ",
+            "ends with backslash\\",
+            "\\\\s",
+            "multi\nline\r\ntext\t/",
+            "",
+        ] {
+            assert_eq!(unescape(&escape(input)), input);
+        }
+    }
+
+    #[test]
+    fn test_build_command() {
+        assert_eq!(TERMINATOR, "\n");
+        assert_eq!(build_command("version", &[]), "version\n");
+        assert_eq!(
+            build_command("clientmove", &[("clid", "7"), ("cid", "42")]),
+            "clientmove clid=7 cid=42\n"
+        );
+        assert_eq!(
+            build_command("channelcreate", &[("channel_name", "a b|c")]),
+            "channelcreate channel_name=a\\sb\\pc\n"
+        );
+        assert_eq!(
+            build_command_with("login", &["serveradmin", "pass word"], &[], &[]),
+            "login serveradmin pass\\sword\n"
+        );
+        assert_eq!(
+            build_command_with("clientlist", &[], &[], &["-uid", "-away"]),
+            "clientlist -uid -away\n"
+        );
+        assert_eq!(
+            build_command_with("serverlist", &[], &[("virtualserver_id", "1")], &["-uid"]),
+            "serverlist virtualserver_id=1 -uid\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_kv() {
+        let result = parse_kv("clid=1 cid=2 client_nickname=serveradmin\\sfrom\\s127.0.0.1");
+        assert_eq!(result.len(), 3);
+        assert_eq!(result["clid"], "1");
+        assert_eq!(result["cid"], "2");
+        assert_eq!(result["client_nickname"], "serveradmin from 127.0.0.1");
+    }
+
+    #[test]
+    fn test_parse_kv_missing_values() {
+        let result = parse_kv("channel_topic= client_flag_talking msg=a=b");
+        assert_eq!(result["channel_topic"], "");
+        assert_eq!(result["client_flag_talking"], "");
+        assert_eq!(result["msg"], "a=b");
+        assert!(parse_kv("").is_empty());
+    }
+
+    #[test]
+    fn test_parse_list() {
+        let result = parse_list(
+            "clid=1 cid=1 client_database_id=1 client_nickname=serveradmin client_type=1|\
+             clid=5 cid=2 client_database_id=3 client_nickname=Alice client_type=0|\
+             clid=6 cid=2 client_database_id=4 client_nickname=Bob\\p\\sCarol client_type=0",
+        );
+        assert_eq!(result.len(), 3);
+        assert_eq!(result[0]["client_nickname"], "serveradmin");
+        assert_eq!(result[1]["clid"], "5");
+        assert_eq!(result[1]["client_type"], "0");
+        assert_eq!(result[2]["client_nickname"], "Bob| Carol");
+    }
+
+    #[test]
+    fn test_parse_list_edge_cases() {
+        let result = parse_list("cid=1 channel_name=Lobby");
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0]["channel_name"], "Lobby");
+        assert!(parse_list("").is_empty());
+    }
+}
@@ -0,0 +1,56 @@
+//! Randomness from the operating system, for channel passwords and the reconnect jitter.
+
+use crate::error::Result;
+
+/// Fill `bytes` from the random number generator of the operating system.
+pub(crate) fn fill(bytes: &mut [u8]) -> Result<()> {
+    getrandom::getrandom(bytes).map_err(std::io::Error::from)?;
+    Ok(())
+}
+
+/// A random number in `[0, 1)`.
+pub(crate) fn fraction() -> Result<f64> {
+    let mut bytes = [0u8; 8];
+    fill(&mut bytes)?;
+    // The 53 bits an f64 holds exactly
+    Ok((u64::from_le_bytes(bytes) >> 11) as f64 / (1u64 << 53) as f64)
+}
+
+/// `len` characters picked uniformly from `charset`, which has at most 256 of them.
+pub(crate) fn string(charset: &[u8], len: usize) -> Result<String> {
+    // Bytes past the last whole multiple of the charset would favour its start
+    let limit = 256 - 256 % charset.len();
+    let mut out = String::with_capacity(len);
+    let mut bytes = [0u8; 32];
+    while out.len() < len {
+        fill(&mut bytes)?;
+        out.extend(
+            bytes
+                .iter()
+                .filter(|byte| (**byte as usize) < limit)
+                .map(|byte| charset[*byte as usize % charset.len()] as char)
+                .take(len - out.len()),
+        );
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{fraction, string};
+
+    #[test]
+    fn test_string() {
+        let generated = string(b"ab", 64).unwrap();
+        assert_eq!(generated.len(), 64);
+        assert!(generated.chars().all(|c| c == 'a' || c == 'b'));
+        assert_ne!(string(b"abc", 16).unwrap(), string(b"abc", 16).unwrap());
+    }
+
+    #[test]
+    fn test_fraction() {
+        let fractions: Vec<f64> = (0..16).map(|_| fraction().unwrap()).collect();
+        assert!(fractions.iter().all(|f| (0.0..1.0).contains(f)));
+        assert!(fractions.iter().any(|f| *f != fractions[0]));
+    }
+}
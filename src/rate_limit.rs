@@ -0,0 +1,124 @@
+//! Token bucket pacing the commands we send, the server bans query clients
+//! exceeding its anti-flood limit.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Allows `commands` commands per `interval`, bursts up to `commands` at once.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    capacity: f64,
+    /// Time to refill a single token
+    per_token: Duration,
+    /// Negative while commands wait for their token
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(commands: u32, interval: Duration) -> Self {
+        let commands = commands.max(1);
+        Self {
+            capacity: commands as f64,
+            per_token: interval / commands,
+            tokens: commands as f64,
+            last: Instant::now(),
+        }
+    }
+
+    /// Follow the ServerQuery flood limit of the `instanceinfo` reply, keeping a fifth
+    /// of it spare for other sessions of the same address. `None` if the fields are
+    /// missing, e.g. without permission to view them.
+    pub fn from_flood_settings(fields: &HashMap<String, String>) -> Option<Self> {
+        let field = |key: &str| fields.get(key).and_then(|value| value.parse::<u32>().ok());
+        let commands = field("serverinstance_serverquery_flood_commands")?;
+        let secs = field("serverinstance_serverquery_flood_time")?;
+        if commands == 0 || secs == 0 {
+            return None;
+        }
+        Some(Self::new(
+            (commands - commands / 5).max(1),
+            Duration::from_secs(secs.into()),
+        ))
+    }
+
+    pub fn commands(&self) -> u32 {
+        self.capacity as u32
+    }
+    pub fn interval(&self) -> Duration {
+        self.per_token * self.commands()
+    }
+
+    /// Take a token for a command sent at `now`, returns how long to wait before
+    /// sending it.
+    pub fn acquire(&mut self, now: Instant) -> Duration {
+        if now > self.last {
+            let refill = now.duration_since(self.last).as_secs_f64() / self.per_token.as_secs_f64();
+            self.tokens = (self.tokens + refill).min(self.capacity);
+            self.last = now;
+        }
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            self.per_token.mul_f64(-self.tokens)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::RateLimiter;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_from_flood_settings() {
+        let fields = crate::protocol::parse_kv(
+            "serverinstance_serverquery_flood_commands=10 serverinstance_serverquery_flood_time=3 serverinstance_serverquery_flood_ban_time=600",
+        );
+        let limiter = RateLimiter::from_flood_settings(&fields).unwrap();
+        assert_eq!(limiter.commands(), 8);
+        assert_eq!(limiter.interval(), Duration::from_secs(3));
+
+        let fields = crate::protocol::parse_kv(
+            "serverinstance_serverquery_flood_commands=1 serverinstance_serverquery_flood_time=1",
+        );
+        assert_eq!(
+            RateLimiter::from_flood_settings(&fields)
+                .unwrap()
+                .commands(),
+            1
+        );
+        let fields = crate::protocol::parse_kv(
+            "serverinstance_serverquery_flood_commands=0 serverinstance_serverquery_flood_time=3",
+        );
+        assert!(RateLimiter::from_flood_settings(&fields).is_none());
+        let fields = crate::protocol::parse_kv("virtualserver_name=Test");
+        assert!(RateLimiter::from_flood_settings(&fields).is_none());
+    }
+
+    #[test]
+    fn test_acquire() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(3, Duration::from_secs(3));
+        limiter.last = start;
+        for _ in 0..3 {
+            assert_eq!(limiter.acquire(start), Duration::ZERO);
+        }
+        // The burst is used up, every further command waits one more second
+        assert_eq!(limiter.acquire(start), Duration::from_secs(1));
+        assert_eq!(limiter.acquire(start), Duration::from_secs(2));
+
+        // Once the waiting commands got their tokens the next one waits again
+        assert_eq!(
+            limiter.acquire(start + Duration::from_secs(2)),
+            Duration::from_secs(1)
+        );
+        // A long pause refills the bucket up to its capacity only
+        let later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert_eq!(limiter.acquire(later), Duration::ZERO);
+        }
+        assert_eq!(limiter.acquire(later), Duration::from_secs(1));
+    }
+}
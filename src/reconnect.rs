@@ -0,0 +1,267 @@
+use log::{error, info, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// Jitter of the daemon's reconnect delays, see [`Backoff::set_jitter`].
+pub const DEFAULT_JITTER: f64 = 0.5;
+
+/// Exponential backoff between reconnect attempts, capped at `max`.
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    attempt: u32,
+    /// Part of each delay that is randomly cut off, see [`Self::set_jitter`]
+    jitter: f64,
+    /// Attempts before giving up, 0 for no limit
+    max_attempts: u32,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            attempt: 0,
+            jitter: 0.0,
+            max_attempts: 0,
+        }
+    }
+
+    /// Shorten each delay by a random part of up to `jitter` (0 to 1) of it, so
+    /// daemons losing the same server don't all reconnect at the same moment.
+    pub fn set_jitter(&mut self, jitter: f64) {
+        self.jitter = jitter.clamp(0.0, 1.0);
+    }
+
+    /// Let [`reconnect`] give up after `max_attempts` failed attempts, 0 never does.
+    pub fn set_max_attempts(&mut self, max_attempts: u32) {
+        self.max_attempts = max_attempts;
+    }
+
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Whether every allowed attempt was made.
+    pub fn exhausted(&self) -> bool {
+        self.max_attempts > 0 && self.attempt >= self.max_attempts
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self
+            .initial
+            .saturating_mul(2u32.saturating_pow(self.attempt))
+            .min(self.max);
+        self.attempt += 1;
+        if self.jitter == 0.0 {
+            return delay;
+        }
+        match crate::random::fraction() {
+            Ok(fraction) => delay.mul_f64(1.0 - self.jitter * fraction),
+            Err(e) => {
+                warn!("Got error while pick the reconnect jitter: {:?}", e);
+                delay
+            }
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+/// Longest a [`sleep_while`] sleeps before looking at `running` again.
+const SLEEP_SLICE: Duration = Duration::from_millis(100);
+
+/// Sleep for `delay`, returning early once `running` is cleared.
+pub fn sleep_while(running: &AtomicBool, delay: Duration) {
+    let deadline = Instant::now() + delay;
+    while running.load(Ordering::SeqCst) {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            break;
+        }
+        std::thread::sleep(left.min(SLEEP_SLICE));
+    }
+}
+
+/// Call `connect` until it succeeds, waiting with `sleep` according to `backoff` before
+/// each try. The error of the last attempt is returned once the attempts are used up,
+/// `None` once `running` is cleared.
+pub fn reconnect<T, E, F, S>(
+    mut connect: F,
+    backoff: &mut Backoff,
+    running: &AtomicBool,
+    mut sleep: S,
+) -> Result<Option<T>, E>
+where
+    E: std::fmt::Debug,
+    F: FnMut() -> Result<T, E>,
+    S: FnMut(Duration),
+{
+    loop {
+        let delay = backoff.next_delay();
+        crate::logging::set_attempt(backoff.attempt());
+        warn!(
+            "Reconnecting in {:?} (attempt {})",
+            delay,
+            backoff.attempt()
+        );
+        sleep(delay);
+        if !running.load(Ordering::SeqCst) {
+            info!("Stopped while reconnecting");
+            return Ok(None);
+        }
+        match connect() {
+            Ok(conn) => {
+                backoff.reset();
+                return Ok(Some(conn));
+            }
+            Err(e) if backoff.exhausted() => {
+                error!(
+                    "Reconnect attempt {} failed: {:?}, giving up",
+                    backoff.attempt(),
+                    e
+                );
+                return Err(e);
+            }
+            Err(e) => error!("Reconnect attempt {} failed: {:?}", backoff.attempt(), e),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "sync"))]
+mod test {
+    use super::{reconnect, sleep_while, Backoff};
+    use crate::autochannel::setup_session;
+    use crate::connection::test::BANNER;
+    use crate::connection::{VirtualServer, DEFAULT_TIMEOUT};
+    use crate::error::QueryError;
+    use crate::transport::mock::MockTransport;
+    use crate::TelnetConn;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10));
+        let delays: Vec<_> = (0..6).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 10, 10]);
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_jitter() {
+        let mut backoff = Backoff::new(Duration::from_secs(8), Duration::from_secs(8));
+        backoff.set_jitter(0.5);
+        let delays: Vec<_> = (0..50).map(|_| backoff.next_delay()).collect();
+        assert!(delays
+            .iter()
+            .all(|delay| (Duration::from_secs(4)..=Duration::from_secs(8)).contains(delay)));
+        assert!(delays.iter().any(|delay| *delay != delays[0]));
+    }
+
+    #[test]
+    fn test_max_attempts() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+        backoff.set_max_attempts(3);
+        let mut attempts = 0;
+        let mut sleeps = 0;
+        let result: Result<Option<()>, _> = reconnect(
+            || {
+                attempts += 1;
+                Err(QueryError::Closed)
+            },
+            &mut backoff,
+            &AtomicBool::new(true),
+            |_| sleeps += 1,
+        );
+        assert!(matches!(result, Err(QueryError::Closed)));
+        assert_eq!((attempts, sleeps), (3, 3));
+    }
+
+    #[test]
+    fn test_stop_while_reconnecting() {
+        // Retrying forever, as by default
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+        let running = AtomicBool::new(true);
+        let (mut attempts, mut waits) = (0, 0);
+        let result: Result<Option<()>, _> = reconnect(
+            || {
+                attempts += 1;
+                Err(QueryError::Closed)
+            },
+            &mut backoff,
+            &running,
+            |_| {
+                // Stopped during the third wait
+                waits += 1;
+                if waits == 3 {
+                    running.store(false, Ordering::SeqCst);
+                }
+            },
+        );
+        assert!(matches!(result, Ok(None)));
+        assert_eq!(attempts, 2);
+
+        let start = Instant::now();
+        sleep_while(&running, Duration::from_secs(10));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_reconnect_replays_session() {
+        let mock = MockTransport::default();
+        let mut attempts = 0;
+        let mut sleeps = Vec::new();
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+
+        let _conn: TelnetConn = reconnect(
+            || {
+                attempts += 1;
+                if attempts < 3 {
+                    return Err(QueryError::Closed);
+                }
+                mock.push_read(BANNER);
+                for _ in 0..4 {
+                    mock.push_reply(&["error id=0 msg=ok\n\r"]);
+                }
+                let mut conn = TelnetConn::from_transport(Box::new(mock.clone()), DEFAULT_TIMEOUT)?;
+                setup_session(
+                    &mut conn,
+                    Some(("serveradmin", "password")),
+                    VirtualServer::Id(1),
+                    None,
+                    true,
+                )?;
+                Ok(conn)
+            },
+            &mut backoff,
+            &AtomicBool::new(true),
+            |delay| sleeps.push(delay),
+        )
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(attempts, 3);
+        assert_eq!(
+            sleeps,
+            vec![
+                Duration::from_secs(1),
+                Duration::from_secs(2),
+                Duration::from_secs(4)
+            ]
+        );
+        assert_eq!(backoff.attempt(), 0);
+        assert_eq!(
+            mock.written(),
+            vec![
+                "login serveradmin password\n".to_string(),
+                "use 1\n".to_string(),
+                "servernotifyregister event=channel id=0\n".to_string(),
+                "servernotifyregister event=textprivate\n".to_string(),
+            ]
+        );
+    }
+}
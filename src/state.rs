@@ -0,0 +1,102 @@
+//! Channels managed by the daemon, saved so a restart can clean them up.
+
+use crate::error::{QueryError, Result};
+use serde_derive::{Deserialize, Serialize};
+use std::path::Path;
+
+/// A channel spawned by us.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManagedChannel {
+    cid: i32,
+    /// UID of the client the channel was created for
+    owner: String,
+    /// Watched channel it was spawned for
+    parent: i32,
+}
+
+impl ManagedChannel {
+    pub fn new(cid: i32, owner: String, parent: i32) -> Self {
+        Self { cid, owner, parent }
+    }
+
+    pub fn cid(&self) -> i32 {
+        self.cid
+    }
+    pub fn owner(&self) -> &str {
+        &self.owner
+    }
+    pub fn parent(&self) -> i32 {
+        self.parent
+    }
+}
+
+/// Content of the `--state-file`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct State {
+    channels: Vec<ManagedChannel>,
+}
+
+impl State {
+    pub fn new(channels: Vec<ManagedChannel>) -> Self {
+        Self { channels }
+    }
+
+    /// Read the state file, a missing file is an empty state.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => {
+                return Err(QueryError::State(format!(
+                    "Got error while read {}: {:?}",
+                    path.display(),
+                    e
+                )))
+            }
+        };
+        serde_json::from_str(&content).map_err(|e| {
+            QueryError::State(format!("Got error while parse {}: {:?}", path.display(), e))
+        })
+    }
+
+    /// Write the state file, through a temporary file so a crash never leaves half of it.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let content = serde_json::to_string_pretty(self)
+            .map_err(|e| QueryError::State(format!("Got error while serialize state: {:?}", e)))?;
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, content)
+            .and_then(|_| std::fs::rename(&tmp, path))
+            .map_err(|e| {
+                QueryError::State(format!("Got error while write {}: {:?}", path.display(), e))
+            })
+    }
+
+    pub fn channels(&self) -> &[ManagedChannel] {
+        &self.channels
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ManagedChannel, State};
+
+    #[test]
+    fn test_round_trip() {
+        let path = std::env::temp_dir().join(format!(
+            "teamspeak-autochannel-state-{}.json",
+            std::process::id()
+        ));
+        assert_eq!(State::load(&path).unwrap(), State::default());
+
+        let state = State::new(vec![
+            ManagedChannel::new(42, "abc=".to_string(), 1),
+            ManagedChannel::new(43, "def/+=".to_string(), 2),
+        ]);
+        state.save(&path).unwrap();
+        let loaded = State::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unwrap(), state);
+    }
+}